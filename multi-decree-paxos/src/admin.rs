@@ -0,0 +1,150 @@
+use crate::{Ballot, Command, Replica};
+use std::fmt;
+
+/* Admin Interface:
+ * A node given an admin address takes one command per connection, as a
+ * line of text, and answers it with lines of text before closing the
+ * connection:
+ *
+ *   status       the replica's role, ballots, log positions, peers and
+ *                store size, one "name: value" per line
+ *   snapshot     snapshot the store now and compact the logs behind it
+ *   step-down    stop leading for a while, so another replica takes over
+ *   log [from]   the chosen and accepted slots from `from` on, by default
+ *                from the last snapshot
+ *   kv           every entry of the state machine with its value, in
+ *                name order: every key in the store for a key-value store
+ *
+ * For example: echo status | nc 127.0.0.1 7000
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    Status,
+    Snapshot,
+    StepDown,
+    Log { from: Option<u64> },
+    Kv,
+}
+
+impl AdminCommand {
+    /// Parses one line of the admin protocol.
+    pub fn parse(line: &str) -> Option<AdminCommand> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words[..] {
+            ["status"] => AdminCommand::Status,
+            ["snapshot"] => AdminCommand::Snapshot,
+            ["step-down"] => AdminCommand::StepDown,
+            ["log"] => AdminCommand::Log { from: None },
+            ["log", from] => AdminCommand::Log {
+                from: Some(from.parse().ok()?),
+            },
+            ["kv"] => AdminCommand::Kv,
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// What a replica reports about itself to `status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub id: usize,
+    /// `leader`, `candidate` or `follower`.
+    pub role: &'static str,
+    /// The replica leading the cluster as far as this one knows.
+    pub leader: Option<usize>,
+    /// The proposal number of the proposer's latest phase 1.
+    pub ballot: Ballot,
+    /// The highest proposal number the acceptor has promised.
+    pub promised: Ballot,
+    /// The slots the acceptor keeps accepted values for.
+    pub accepted: (u64, u64),
+    /// The first slot after every slot known to be chosen.
+    pub chosen: u64,
+    /// The first slot not yet applied to the store.
+    pub applied: u64,
+    /// The first slot not covered by the last snapshot.
+    pub snapshot: u64,
+    /// The members at the first slot not yet applied.
+    pub members: Vec<usize>,
+    /// How many entries the state machine holds.
+    pub keys: usize,
+    /// Every other node, and whether a connection to it is up.
+    pub peers: Vec<(usize, bool)>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "id: {}", self.id)?;
+        writeln!(f, "role: {}", self.role)?;
+        match self.leader {
+            Some(leader) => writeln!(f, "leader: {}", leader)?,
+            None => writeln!(f, "leader: unknown")?,
+        }
+        writeln!(f, "ballot: {}", self.ballot)?;
+        writeln!(f, "promised: {}", self.promised)?;
+        writeln!(f, "accepted: {}..{}", self.accepted.0, self.accepted.1)?;
+        writeln!(f, "chosen: ..{}", self.chosen)?;
+        writeln!(f, "applied: ..{}", self.applied)?;
+        writeln!(f, "snapshot: ..{}", self.snapshot)?;
+        writeln!(f, "members: {}", list(&self.members))?;
+        writeln!(f, "keys: {}", self.keys)?;
+        let up: Vec<usize> = self.peers.iter().filter(|p| p.1).map(|p| p.0).collect();
+        let down: Vec<usize> = self.peers.iter().filter(|p| !p.1).map(|p| p.0).collect();
+        writeln!(f, "peers up: {}", list(&up))?;
+        writeln!(f, "peers down: {}", list(&down))
+    }
+}
+
+fn list(ids: &[usize]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    ids.join(" ")
+}
+
+/// One line per slot from `from` on: what the learner applied since its
+/// last snapshot, then what the acceptor accepted past that.
+pub(crate) fn dump_log(replica: &Replica, from: Option<u64>) -> String {
+    let learner = replica.learner();
+    let from = from.unwrap_or(learner.snapshot_slot());
+    let mut out = String::new();
+    if from < learner.snapshot_slot() {
+        out += &format!("..{} snapshot\n", learner.snapshot_slot());
+    }
+    for (slot, command) in learner.applied(from) {
+        out += &format!("{} chosen {}\n", slot, describe(command));
+    }
+    let unapplied = from.max(learner.next_slot());
+    for (slot, proposal_number, command) in replica.acceptor().accepted(unapplied) {
+        out += &format!(
+            "{} accepted {} {}\n",
+            slot,
+            proposal_number,
+            describe(command)
+        );
+    }
+    out
+}
+
+/// Every entry of the state machine with its value, in name order.
+pub(crate) fn dump_kv(replica: &Replica) -> String {
+    let entries = replica.learner().state_machine().entries();
+    entries
+        .into_iter()
+        .map(|(key, value)| format!("{}: {}\n", key, value))
+        .collect()
+}
+
+/// A command as a client would send it, on one line.
+fn describe(command: &Command) -> String {
+    match command {
+        Command::Noop => "noop".to_string(),
+        Command::Batch(commands) => {
+            let commands: Vec<String> = commands.iter().map(describe).collect();
+            format!("batch [{}]", commands.join(", "))
+        }
+        command => command
+            .to_client_request()
+            .unwrap_or_default()
+            .replace('\n', " "),
+    }
+}
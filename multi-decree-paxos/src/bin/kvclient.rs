@@ -0,0 +1,150 @@
+/*
+    Key-Value TCP Client
+    Created by: Owen Wacha
+          Date: March 20, 2023
+    CSC258/458 - Parallel & Distributed Systems
+
+
+    Build with "cargo build --bin kvclient"
+    Run "kvclient port[,port...] action key value", or "kvclient port[,port...]"
+    for an interactive prompt
+*/
+use multi_decree_paxos::{KvClient, Watch, WatchEvent};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::process::exit;
+use std::time::Instant;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        println!("Incorrect usage. Try \"kvclient help\" for valid usage");
+        exit(1);
+    }
+    if args[0].eq_ignore_ascii_case("help") {
+        help();
+        return;
+    }
+    let Some(nodes) = parse_nodes(&args[0]) else {
+        println!("Could not parse node list: {}", args[0]);
+        exit(1);
+    };
+    let client = KvClient::new(nodes);
+    if args.len() > 1 {
+        if !run(&client, &args[1..]).await {
+            exit(1);
+        }
+    } else {
+        repl(&client).await;
+    }
+}
+
+/// Reads requests from stdin, one per line, until it ends or says `quit`.
+async fn repl(client: &KvClient) {
+    let stdin = io::stdin();
+    loop {
+        print!("{}> ", client.leader());
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        match args.first().map(|action| action.to_lowercase()).as_deref() {
+            None => {}
+            Some("quit") | Some("exit") => break,
+            Some("help") => help(),
+            Some(_) => {
+                run(client, &args).await;
+            }
+        }
+    }
+}
+
+/// Sends one request and prints the answer. Returns whether one came.
+async fn run(client: &KvClient, args: &[String]) -> bool {
+    if let Some(watch) = Watch::from_client_request(&args.join("\n")) {
+        return watch_changes(client, watch).await;
+    }
+    let start = Instant::now();
+    match client.request(&args.join("\n")).await {
+        Ok(outcome) => {
+            println!("{}", outcome);
+            println!("Received response in {:?}", start.elapsed());
+            true
+        }
+        Err(e) => {
+            println!("Request failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Prints every change the watch streams until it fails.
+async fn watch_changes(client: &KvClient, watch: Watch) -> bool {
+    let mut watcher = match client.watch(watch).await {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            println!("Watch failed: {}", e);
+            return false;
+        }
+    };
+    println!("Watching from slot {}", watcher.slot().unwrap_or_default());
+    loop {
+        match watcher.next().await {
+            Ok(change) => println!("{}", WatchEvent::Change(change)),
+            Err(e) => {
+                println!("Watch failed: {}", e);
+                return false;
+            }
+        }
+    }
+}
+
+/// Parses a comma separated list of ports on this machine or addresses.
+fn parse_nodes(list: &str) -> Option<Vec<SocketAddr>> {
+    list.split(',')
+        .map(|node| match node.parse::<u16>() {
+            Ok(port) => Some(SocketAddr::from(([127, 0, 0, 1], port))),
+            Err(_) => node.parse().ok(),
+        })
+        .collect()
+}
+
+fn help() {
+    println!("\n** KVCLIENT HELP **:");
+    println!("Correct Usage:\n kvclient nodes action key value");
+    println!(" kvclient nodes          (then type one request per line, \"quit\" to leave)");
+    println!("Example Usage:\n kvclient 7878,7879,7880 put 12 twelve");
+    println!("nodes: comma separated ports on this machine, or host:port addresses.");
+    println!("List them in node id order. Requests go to the node that answered last,");
+    println!("move on to the next one if it fails, and to the leader a node names if");
+    println!("it does not lead.");
+    println!("action: one of");
+    println!("  get key");
+    println!("  put key value");
+    println!("  delete key");
+    println!("  cas key expected new   (replace the value only if it is expected)");
+    println!("  append key value");
+    println!("  incr key [by]          (add to an integer value, 1 by default)");
+    println!("  scan start [end]       (keys from start up to, not including, end)");
+    println!("  prefix prefix          (keys starting with prefix)");
+    println!("  watch key key [slot]   (print every change to key, from slot on if given)");
+    println!("  watch prefix prefix [slot]");
+    println!("  reconfigure id...      (make the given node ids the voting members,");
+    println!("                          from a few dozen slots later on)");
+    println!("On a lock service (paxos-node --service locks):");
+    println!("  acquire name owner ttl_ms  (take a lock, answered with its fencing token)");
+    println!("  renew name owner ttl_ms    (keep a lock held for ttl_ms more)");
+    println!("  release name owner");
+    println!("  get name                   (who holds a lock)");
+    println!("Put \"stale\" before get, scan or prefix to read the node's own copy");
+    println!("without asking the cluster. It is fast, but may miss recent writes.");
+    println!("Requests that change the store are retried under a session, so they");
+    println!("take effect once. Put \"session id seq\" before an action to number it");
+    println!("as request seq of client id yourself; sending it again then returns");
+    println!("the first answer instead of applying it twice.");
+}
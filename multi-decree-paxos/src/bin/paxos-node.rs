@@ -0,0 +1,61 @@
+use multi_decree_paxos::{Config, KvStore, LockTable, Node, StateMachine};
+use std::env;
+use std::process::exit;
+use tracing_subscriber::EnvFilter;
+
+fn usage() -> ! {
+    println!("Incorrect usage. Try \"paxos-node --id ID --config CLUSTER.toml [--service kv|locks]\" for valid usage");
+    exit(1);
+}
+
+fn main() {
+    // RUST_LOG=multi_decree_paxos=debug traces every round
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut id = None;
+    let mut config = None;
+    let mut service = "kv".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--id" => id = args.next().and_then(|id| id.parse::<usize>().ok()),
+            "--config" => config = args.next().cloned(),
+            "--service" => service = args.next().cloned().unwrap_or_else(|| usage()),
+            _ => usage(),
+        }
+    }
+    let (Some(id), Some(config)) = (id, config) else {
+        usage();
+    };
+
+    let config = Config::load(&config).unwrap_or_else(|e| {
+        println!("could not load {}: {}", config, e);
+        exit(1);
+    });
+    let state: Box<dyn StateMachine> = match service.as_str() {
+        "kv" => Box::new(KvStore::default()),
+        "locks" => Box::new(LockTable::default()),
+        _ => usage(),
+    };
+    let mut node = Node::bind_with(&config, id, state).unwrap_or_else(|e| {
+        println!("could not start node {}: {}", id, e);
+        exit(1);
+    });
+    let me = config.node(id).unwrap();
+    println!(
+        "node {} serving peers on {} and {} clients on {}",
+        id, me.peer_addr, service, me.client_addr
+    );
+    if let Some(addr) = me.metrics_addr {
+        println!("node {} serving metrics on http://{}/metrics", id, addr);
+    }
+    if let Some(addr) = me.admin_addr {
+        println!("node {} taking admin commands on {}", id, addr);
+    }
+    if let Err(e) = node.run() {
+        println!("node {} stopped: {}", id, e);
+        exit(1);
+    }
+}
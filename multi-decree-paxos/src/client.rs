@@ -0,0 +1,522 @@
+use crate::rng::Rng;
+use crate::{Change, Command, Outcome, Watch, WatchEvent};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/* Client Library:
+ * KvClient talks to the nodes' client ports over framed connections (see
+ * Client Connections in node.rs) and keeps idle ones open for later
+ * requests. It sends every request to the node that answered last, and
+ * moves on to the next node when that one fails or times out, backing off
+ * exponentially with jitter between attempts. A node that does not lead
+ * answers NOT_LEADER, naming the leader if it knows one; the request goes
+ * straight there, and only counts as a failed attempt if no leader is named.
+ *
+ * Requests that change the store are sent under a session, so a retry of
+ * one that did take effect is answered from the session instead of being
+ * applied twice. Each request in progress holds a session of its own, as a
+ * later request of the same session expires an earlier one. A client opens
+ * at most `sessions` of them, and further requests wait for one to be free,
+ * so the nodes keep a bounded number of sessions for it.
+ *
+ * A Watcher holds a connection of its own, which streams changes rather
+ * than answers. When it fails, the watcher watches again on the next node,
+ * from the slot of the last change it got, and skips the changes of that
+ * slot it already has.
+ */
+/// How long a request may take on one node before it is tried on the next.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times a request is sent again after the first attempt failed.
+pub const REQUEST_RETRIES: u32 = 5;
+/// The first delay before retrying a request.
+pub const RETRY_BACKOFF_MIN: Duration = Duration::from_millis(50);
+/// The longest delay between two attempts of a request.
+pub const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(2);
+/// How many idle connections are kept open to each node.
+pub const POOL_SIZE: usize = 4;
+/// The most sessions a client opens, and so the most requests that change
+/// the store it has in progress at once.
+pub const MAX_SESSIONS: usize = 16;
+
+/// How patient a `KvClient` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff_min: Duration,
+    pub backoff_max: Duration,
+    pub pool_size: usize,
+    /// How many sessions the client opens, from 1 to `MAX_SESSIONS`. Values
+    /// outside that range are clamped to it.
+    pub sessions: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            timeout: REQUEST_TIMEOUT,
+            retries: REQUEST_RETRIES,
+            backoff_min: RETRY_BACKOFF_MIN,
+            backoff_max: RETRY_BACKOFF_MAX,
+            pool_size: POOL_SIZE,
+            sessions: MAX_SESSIONS,
+        }
+    }
+}
+
+/// A client of the whole cluster, which may be shared by concurrent tasks.
+pub struct KvClient {
+    nodes: Vec<SocketAddr>,
+    options: ClientOptions,
+    /// The node requests go to first, in `nodes` order.
+    leader: AtomicUsize,
+    /// Idle connections, by node.
+    pool: Mutex<HashMap<SocketAddr, Vec<TcpStream>>>,
+    /// Sessions no request is using, as the client id and its next sequence
+    /// number.
+    sessions: Mutex<Vec<(u64, u64)>>,
+    /// A permit for each session a request may hold, opened or not.
+    session_permits: Semaphore,
+    rng: Mutex<Rng>,
+}
+
+impl KvClient {
+    /// A client of the nodes whose client ports are `nodes`, listed by node
+    /// id so that leader hints can be followed.
+    pub fn new(nodes: Vec<SocketAddr>) -> KvClient {
+        assert!(!nodes.is_empty(), "a client needs at least one node");
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+            ^ std::process::id() as u64;
+        KvClient {
+            nodes,
+            options: ClientOptions::default(),
+            leader: AtomicUsize::new(0),
+            pool: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(Vec::new()),
+            session_permits: Semaphore::new(MAX_SESSIONS),
+            rng: Mutex::new(Rng::new(seed)),
+        }
+    }
+
+    /// Meant to be called before the first request: the sessions opened so
+    /// far are forgotten.
+    pub fn set_options(&mut self, options: ClientOptions) {
+        let sessions = options.sessions.clamp(1, MAX_SESSIONS);
+        self.options = ClientOptions {
+            sessions,
+            ..options
+        };
+        self.sessions.get_mut().unwrap().clear();
+        self.session_permits = Semaphore::new(sessions);
+    }
+
+    /// The node requests are sent to first.
+    pub fn leader(&self) -> SocketAddr {
+        self.nodes[self.leader.load(Ordering::Relaxed)]
+    }
+
+    pub async fn get(&self, key: &str) -> io::Result<Option<String>> {
+        let command = Command::Get {
+            key: key.to_string(),
+        };
+        match self.execute(command).await? {
+            Outcome::Value(value) => Ok(Some(value)),
+            Outcome::NotFound => Ok(None),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    pub async fn put(&self, key: &str, value: &str) -> io::Result<()> {
+        let command = Command::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        match self.execute(command).await? {
+            Outcome::PutOk => Ok(()),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Returns whether `key` was there to delete.
+    pub async fn delete(&self, key: &str) -> io::Result<bool> {
+        let command = Command::Delete {
+            key: key.to_string(),
+        };
+        match self.execute(command).await? {
+            Outcome::Deleted => Ok(true),
+            Outcome::NotFound => Ok(false),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Replaces the value of `key` with `new` if it is `expected`. Otherwise
+    /// gives back what `key` holds, if anything.
+    pub async fn cas(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+    ) -> io::Result<Result<(), Option<String>>> {
+        let command = Command::Cas {
+            key: key.to_string(),
+            expected: expected.to_string(),
+            new: new.to_string(),
+        };
+        match self.execute(command).await? {
+            Outcome::Updated(_) => Ok(Ok(())),
+            Outcome::CasFailed(current) => Ok(Err(current)),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Takes the lock `name` of a lock service for `owner`, for `ttl`.
+    /// Gives back its fencing token, or the owner holding it instead.
+    pub async fn acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> io::Result<Result<u64, String>> {
+        let command = Command::Acquire {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        match self.execute(command).await? {
+            Outcome::Locked(token) => Ok(Ok(token)),
+            Outcome::LockHeld(owner) => Ok(Err(owner)),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Extends the lock `owner` holds on `name` to `ttl` from now. Returns
+    /// its fencing token, or `None` if `owner` no longer holds it.
+    pub async fn renew(&self, name: &str, owner: &str, ttl: Duration) -> io::Result<Option<u64>> {
+        let command = Command::Renew {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            ttl_ms: ttl.as_millis() as u64,
+        };
+        match self.execute(command).await? {
+            Outcome::Locked(token) => Ok(Some(token)),
+            Outcome::NotLockOwner => Ok(None),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Returns whether `owner` held the lock on `name` it released.
+    pub async fn release(&self, name: &str, owner: &str) -> io::Result<bool> {
+        let command = Command::Release {
+            name: name.to_string(),
+            owner: owner.to_string(),
+        };
+        match self.execute(command).await? {
+            Outcome::Released => Ok(true),
+            Outcome::NotLockOwner => Ok(false),
+            outcome => Err(unexpected(outcome)),
+        }
+    }
+
+    /// Starts streaming the changes `watch` asks for, from the node requests
+    /// go to first.
+    pub async fn watch(&self, watch: Watch) -> io::Result<Watcher> {
+        let mut watcher = Watcher {
+            nodes: self.nodes.clone(),
+            options: self.options,
+            node: self.leader.load(Ordering::Relaxed),
+            slot: watch.from,
+            watch,
+            stream: None,
+            seen: 0,
+            skip: 0,
+        };
+        watcher.connect().await?;
+        Ok(watcher)
+    }
+
+    /// Sends a newline separated request, as `Replica::propose` takes it.
+    /// Requests that change the store are numbered in a session of their
+    /// own unless they already name one.
+    pub async fn request(&self, request: &str) -> io::Result<Outcome> {
+        match Command::from_client_request(request) {
+            Some(command) if !matches!(command, Command::Session { .. }) => {
+                self.execute(command).await
+            }
+            // Stale reads, explicit sessions, and requests the node rejects
+            _ => self.send(request).await,
+        }
+    }
+
+    async fn execute(&self, command: Command) -> io::Result<Outcome> {
+        if command.is_read_only() {
+            return self.send(&to_request(&command)?).await;
+        }
+        // No more sessions than permits are ever opened
+        let _permit = self
+            .session_permits
+            .acquire()
+            .await
+            .map_err(io::Error::other)?;
+        let (client, seq) = self.sessions.lock().unwrap().pop().unwrap_or_else(|| {
+            let client = self.rng.lock().unwrap().next_u64();
+            (client, 0)
+        });
+        let command = Command::Session {
+            client,
+            seq,
+            command: Box::new(command),
+        };
+        let outcome = self.send(&to_request(&command)?).await;
+        // The next request may only follow this one, applied or not
+        self.sessions.lock().unwrap().push((client, seq + 1));
+        outcome
+    }
+
+    /// Sends `request` until a node answers it, trying the next node after
+    /// each failure and the leader a node names instead of itself.
+    async fn send(&self, request: &str) -> io::Result<Outcome> {
+        let mut backoff = self.options.backoff_min;
+        let mut attempt = 0;
+        // Redirects followed since the last failure, so that nodes which
+        // name each other cannot bounce a request forever
+        let mut hops = 0;
+        loop {
+            let node = self.leader.load(Ordering::Relaxed);
+            let result = tokio::time::timeout(self.options.timeout, self.send_to(node, request))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+            let error = match result {
+                Ok(response) => {
+                    if response == "invalid request!" {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, response));
+                    }
+                    match Outcome::from_response(&response) {
+                        Some(Outcome::NotLeader(Some(leader)))
+                            if leader < self.nodes.len()
+                                && leader != node
+                                && hops < self.nodes.len() =>
+                        {
+                            hops += 1;
+                            self.leader.store(leader, Ordering::Relaxed);
+                            continue;
+                        }
+                        Some(Outcome::NotLeader(_)) => {
+                            io::Error::new(io::ErrorKind::NotConnected, response)
+                        }
+                        outcome => {
+                            self.leader.store(node, Ordering::Relaxed);
+                            return outcome.ok_or_else(|| {
+                                io::Error::new(io::ErrorKind::InvalidData, response)
+                            });
+                        }
+                    }
+                }
+                Err(e) => e,
+            };
+            if attempt == self.options.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            hops = 0;
+            // Others may have moved on from this node already
+            let next = (node + 1) % self.nodes.len();
+            let _ = self
+                .leader
+                .compare_exchange(node, next, Ordering::Relaxed, Ordering::Relaxed);
+            let jitter = self.rng.lock().unwrap().fraction();
+            tokio::time::sleep(backoff.mul_f64(0.5 + jitter / 2.0)).await;
+            backoff = (backoff * 2).min(self.options.backoff_max);
+        }
+    }
+
+    /// Sends `request` to one node over a pooled connection, which goes back
+    /// to the pool once it has been answered.
+    async fn send_to(&self, node: usize, request: &str) -> io::Result<String> {
+        let addr = self.nodes[node];
+        let pooled = self
+            .pool
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .and_then(|idle| idle.pop());
+        let mut stream = match pooled {
+            Some(stream) => stream,
+            None => {
+                let mut stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                stream.write_all(&[0]).await?;
+                stream
+            }
+        };
+        let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(request.as_bytes());
+        stream.write_all(&frame).await?;
+        let len = stream.read_u32().await? as usize;
+        let mut response = vec![0; len];
+        stream.read_exact(&mut response).await?;
+
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.entry(addr).or_default();
+        if idle.len() < self.options.pool_size {
+            idle.push(stream);
+        }
+        String::from_utf8(response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The changes to the keys a watch follows, in log order.
+pub struct Watcher {
+    nodes: Vec<SocketAddr>,
+    options: ClientOptions,
+    /// The node watched, in `nodes` order.
+    node: usize,
+    watch: Watch,
+    stream: Option<TcpStream>,
+    /// The slot of the last change received, or where the watch started.
+    slot: Option<u64>,
+    /// How many changes of `slot` were received.
+    seen: usize,
+    /// How many changes of `slot` the node resumed on will send again.
+    skip: usize,
+}
+
+impl Watcher {
+    /// Waits for the next change. Gives up only when the watch cannot be
+    /// resumed on any node, `retries` times in a row.
+    pub async fn next(&mut self) -> io::Result<Change> {
+        loop {
+            if self.stream.is_none() {
+                self.connect().await?;
+            }
+            match self.receive().await {
+                Ok(Some(change)) => return Ok(change),
+                Ok(None) => {}
+                Err(_) => {
+                    self.stream = None;
+                    self.node = (self.node + 1) % self.nodes.len();
+                }
+            }
+        }
+    }
+
+    /// The slot of the last change received, or where the watch started.
+    pub fn slot(&self) -> Option<u64> {
+        self.slot
+    }
+
+    /// Watches on the current node, moving on to the next one after each
+    /// failure.
+    async fn connect(&mut self) -> io::Result<()> {
+        let mut backoff = self.options.backoff_min;
+        let mut attempt = 0;
+        loop {
+            let result = tokio::time::timeout(self.options.timeout, self.resume())
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+            let error = match result {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            if attempt == self.options.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            self.node = (self.node + 1) % self.nodes.len();
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.options.backoff_max);
+        }
+    }
+
+    /// Opens a connection to the current node and watches there from
+    /// `slot`. The changes of `slot` already received come again, and are
+    /// skipped.
+    async fn resume(&mut self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.nodes[self.node]).await?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&[0]).await?;
+        let watch = Watch {
+            from: self.slot,
+            ..self.watch.clone()
+        };
+        write_frame(&mut stream, &watch.to_client_request()).await?;
+        match read_event(&mut stream).await? {
+            WatchEvent::Started(slot) => {
+                if self.slot.is_none() {
+                    self.slot = Some(slot);
+                }
+                self.skip = self.seen;
+                Ok(stream)
+            }
+            event => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                event.to_string(),
+            )),
+        }
+    }
+
+    /// Reads the next event. `None` if it was a change received before.
+    async fn receive(&mut self) -> io::Result<Option<Change>> {
+        let stream = self.stream.as_mut().unwrap();
+        let change = match read_event(stream).await? {
+            WatchEvent::Change(change) => change,
+            event => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    event.to_string(),
+                ))
+            }
+        };
+        if Some(change.slot) == self.slot {
+            if self.skip > 0 {
+                self.skip -= 1;
+                return Ok(None);
+            }
+            self.seen += 1;
+        } else {
+            self.slot = Some(change.slot);
+            self.seen = 1;
+            self.skip = 0;
+        }
+        Ok(Some(change))
+    }
+}
+
+async fn write_frame(stream: &mut TcpStream, request: &str) -> io::Result<()> {
+    let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(request.as_bytes());
+    stream.write_all(&frame).await
+}
+
+async fn read_event(stream: &mut TcpStream) -> io::Result<WatchEvent> {
+    let len = stream.read_u32().await? as usize;
+    let mut response = vec![0; len];
+    stream.read_exact(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    WatchEvent::from_response(&response)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, response.into_owned()))
+}
+
+fn to_request(command: &Command) -> io::Result<String> {
+    command
+        .to_client_request()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a client request"))
+}
+
+fn unexpected(outcome: Outcome) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {}", outcome),
+    )
+}
@@ -0,0 +1,216 @@
+use crate::{
+    ACCEPT_TIMEOUT, BACKOFF_MAX, BACKOFF_MIN, ELECTION_TIMEOUT, HEARTBEAT_INTERVAL, MAX_BATCH,
+    PIPELINE_WINDOW, PREPARE_TIMEOUT,
+};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The address book of a cluster, shared by every node. Nodes outside
+/// `members` start as spares that a reconfiguration can bring in.
+///
+/// ```toml
+/// data_dir = "/var/lib/paxos"
+/// members = [0, 1, 2]
+/// secret = "correct horse battery staple"
+///
+/// [[nodes]]
+/// id = 0
+/// peer_addr = "127.0.0.1:9000"
+/// client_addr = "127.0.0.1:8000"
+/// metrics_addr = "127.0.0.1:9100"
+/// admin_addr = "127.0.0.1:7000"
+///
+/// [timeouts]
+/// prepare_timeout_ms = 200
+///
+/// [pipeline]
+/// window = 8
+/// ```
+///
+/// The default config has no nodes and leaves every option at its default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Each node keeps its logs in `data_dir/node-<id>`. Without it nodes
+    /// keep their state in memory only.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub pipeline: Pipeline,
+    /// The nodes whose votes count before any reconfiguration. Defaults to
+    /// every node.
+    #[serde(default)]
+    pub members: Option<Vec<usize>>,
+    /// A secret every node shares, with which they authenticate the frames
+    /// they send each other (see auth.rs). Without it anything that can
+    /// reach a peer address can speak for any node.
+    #[serde(default)]
+    pub secret: Option<String>,
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub id: usize,
+    /// Where the node accepts connections from other nodes.
+    pub peer_addr: SocketAddr,
+    /// Where the node accepts client requests.
+    pub client_addr: SocketAddr,
+    /// Where the node serves its metrics to Prometheus over HTTP, if
+    /// anywhere.
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where the node takes admin commands, if anywhere.
+    #[serde(default)]
+    pub admin_addr: Option<SocketAddr>,
+}
+
+impl NodeConfig {
+    /// A node that serves neither metrics nor admin commands.
+    pub fn new(id: usize, peer_addr: SocketAddr, client_addr: SocketAddr) -> NodeConfig {
+        NodeConfig {
+            id,
+            peer_addr,
+            client_addr,
+            metrics_addr: None,
+            admin_addr: None,
+        }
+    }
+}
+
+/// How long a proposer waits before acting on silence. Retransmissions and
+/// retries after a rejection back off exponentially from the given timeout
+/// or `backoff_min` up to `backoff_max`, with random jitter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    #[serde(rename = "heartbeat_interval_ms", deserialize_with = "millis")]
+    pub heartbeat_interval: Duration,
+    /// Staggered by `heartbeat_interval` per node id.
+    #[serde(rename = "election_timeout_ms", deserialize_with = "millis")]
+    pub election_timeout: Duration,
+    /// How long a candidate waits for a quorum of PROMISEs before resending
+    /// PREPARE.
+    #[serde(rename = "prepare_timeout_ms", deserialize_with = "millis")]
+    pub prepare_timeout: Duration,
+    /// How long the leader waits for a quorum of ACCEPTEDs before resending
+    /// ACCEPT.
+    #[serde(rename = "accept_timeout_ms", deserialize_with = "millis")]
+    pub accept_timeout: Duration,
+    /// The first delay before running for leader again after a NACK or
+    /// UNACCEPTED.
+    #[serde(rename = "backoff_min_ms", deserialize_with = "millis")]
+    pub backoff_min: Duration,
+    #[serde(rename = "backoff_max_ms", deserialize_with = "millis")]
+    pub backoff_max: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            election_timeout: ELECTION_TIMEOUT,
+            prepare_timeout: PREPARE_TIMEOUT,
+            accept_timeout: ACCEPT_TIMEOUT,
+            backoff_min: BACKOFF_MIN,
+            backoff_max: BACKOFF_MAX,
+        }
+    }
+}
+
+/// How much client work the leader keeps in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pipeline {
+    /// How many slots may await a quorum at once. Client commands that
+    /// arrive while the window is full wait, and go out as one batch.
+    pub window: usize,
+    /// The most client commands sent in a single ACCEPT.
+    pub max_batch: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            window: PIPELINE_WINDOW,
+            max_batch: MAX_BATCH,
+        }
+    }
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+impl Config {
+    /// Reads a `.json` file as JSON and anything else as TOML.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Config> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Config::from_json(&text)
+        } else {
+            Config::from_toml(&text)
+        }
+    }
+
+    pub fn from_toml(text: &str) -> io::Result<Config> {
+        let config: Config = toml::from_str(text).map_err(invalid_data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(text: &str) -> io::Result<Config> {
+        let config: Config = serde_json::from_str(text).map_err(invalid_data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Node ids must be `0..n` so they can index the other roles' votes.
+    fn validate(&self) -> io::Result<()> {
+        let ids: HashSet<usize> = self.nodes.iter().map(|node| node.id).collect();
+        if ids.len() != self.nodes.len() {
+            return Err(invalid_data("duplicate node id"));
+        }
+        if self.nodes.is_empty() || !(0..self.nodes.len()).all(|id| ids.contains(&id)) {
+            return Err(invalid_data("node ids must be 0..n"));
+        }
+        if self.pipeline.window == 0 || self.pipeline.max_batch == 0 {
+            return Err(invalid_data(
+                "pipeline window and max_batch must be positive",
+            ));
+        }
+        if let Some(members) = &self.members {
+            let unique: HashSet<usize> = members.iter().copied().collect();
+            if members.is_empty() || unique.len() != members.len() {
+                return Err(invalid_data("members must be non-empty and unique"));
+            }
+            if !unique.is_subset(&ids) {
+                return Err(invalid_data("members must be node ids"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn node(&self, id: usize) -> Option<&NodeConfig> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// Where node `id` keeps its logs, if anywhere.
+    pub fn node_dir(&self, id: usize) -> Option<PathBuf> {
+        self.data_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("node-{}", id)))
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
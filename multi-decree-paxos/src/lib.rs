@@ -1,441 +1,1650 @@
-use std::collections::HashMap;
-use std::thread::sleep;
-use std::time::Duration;
-
-/* Message Format:
- * PREPARE <proposal_number>
- * PROMISE <proposal_number> <accepted_proposal_number> <key> *<value>
- * ACCEPT <proposal_number> <key> *<value> // If no accepted value, proposal_number is 0
- * ACCEPTED <proposal_number> <key> *<value>
- * UNACCEPTED <proposal_number>
- * RESPONSE <proposal_number> <key> *<value>
- * NACK <proposal_number>
- */
-#[derive(PartialEq)]
-#[repr(u8)]
-pub enum MsgType {
-    PREPARE = 0,
-    PROMISE,
-    ACCEPT,
-    ACCEPTED,
-    UNACCEPTED,
-    RESPONSE,
-    NACK,
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, info, Span};
+
+mod admin;
+mod auth;
+mod client;
+mod config;
+mod linearizability;
+mod locks;
+mod membership;
+mod message;
+mod metrics;
+mod node;
+mod proxy;
+mod replica;
+mod rng;
+mod sim;
+mod state_machine;
+mod storage;
+mod watch;
+
+pub use admin::{AdminCommand, Status};
+pub use auth::{PeerKey, TAG_LEN};
+pub use client::{
+    ClientOptions, KvClient, POOL_SIZE, REQUEST_RETRIES, REQUEST_TIMEOUT, RETRY_BACKOFF_MAX,
+    RETRY_BACKOFF_MIN,
+};
+pub use config::{Config, NodeConfig, Pipeline, Timeouts};
+pub use linearizability::{check_linearizable, NonLinearizable, Operation};
+pub use locks::{LockTable, LOCK_TICK_INTERVAL};
+pub use membership::Membership;
+pub use message::{
+    Ballot, Command, DecodeError, Message, MsgType, Outcome, PValue, MAX_FRAME_LEN,
+    PROTOCOL_VERSION,
+};
+pub use metrics::{Histogram, Metrics, LATENCY_BUCKETS};
+pub use node::{Node, RECONNECT_BACKOFF_MAX, RECONNECT_BACKOFF_MIN};
+pub use proxy::{FaultyNetwork, LinkFaults};
+pub use replica::{Action, Replica};
+pub use sim::{Report, SimConfig, Simulation, Violation};
+pub use state_machine::{KvStore, StateMachine};
+pub use storage::{FileStorage, MemStorage, Record, Storage};
+pub use watch::{Change, Watch, WatchEvent, WatchTarget, WATCH_HISTORY};
+
+use rng::Rng;
+use watch::ChangeLog;
+
+/// How often a leader broadcasts HEARTBEAT.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a follower waits without hearing from the leader before starting
+/// an election. Each node adds `HEARTBEAT_INTERVAL * id` so that elections
+/// are staggered and a single candidate usually wins.
+pub const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a candidate waits for PROMISEs before resending PREPARE.
+pub const PREPARE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long the leader waits for ACCEPTEDs before resending ACCEPT.
+pub const ACCEPT_TIMEOUT: Duration = Duration::from_millis(200);
+/// The first delay before retrying phase 1 after a NACK or UNACCEPTED. It
+/// doubles on every consecutive failure, up to `BACKOFF_MAX`.
+pub const BACKOFF_MIN: Duration = Duration::from_millis(100);
+pub const BACKOFF_MAX: Duration = Duration::from_millis(2000);
+/// How many slots a learner applies between snapshots.
+pub const SNAPSHOT_INTERVAL: u64 = 1000;
+/// How many slots the leader may have proposed and not yet seen chosen.
+pub const PIPELINE_WINDOW: usize = 16;
+/// The most client commands the leader puts in one slot.
+pub const MAX_BATCH: usize = 64;
+/// How many slots after the one it is chosen at a reconfiguration takes
+/// effect. The leader never proposes this far past the slots it has applied.
+pub const RECONFIG_ALPHA: u64 = 32;
+/// How many slots a client's session outlives its last request. Every
+/// multiple of this slot, each learner forgets the sessions that have been
+/// unused for that long, so a client must retry a request within it.
+pub const SESSION_EXPIRY: u64 = 100_000;
+
+/// A client command waiting to be proposed. Its outcome is handed back under
+/// `id`.
+struct Request {
+    id: u64,
+    command: Command,
 }
 
-impl From<u8> for MsgType {
-    fn from(item: u8) -> Self {
-        match item {
-            0 => MsgType::PREPARE,
-            1 => MsgType::PROMISE,
-            2 => MsgType::ACCEPT,
-            3 => MsgType::ACCEPTED,
-            4 => MsgType::UNACCEPTED,
-            5 => MsgType::RESPONSE,
-            6 => MsgType::NACK,
-            _ => panic!("Unknown message type"),
-        }
-    }
+/// A command this proposer has sent ACCEPT for.
+struct Proposal {
+    command: Command,
+    /// The ids of the local client requests `command` carries, in batch
+    /// order. Empty if no local client is waiting on the outcome.
+    requests: Vec<u64>,
+    accepted_votes: HashSet<usize>,
+    /// When ACCEPT is resent if a quorum has not answered by then.
+    deadline: Instant,
+    retry_delay: Duration,
+    /// When ACCEPT was first sent.
+    sent_at: Instant,
+    /// Covers the slot's round from ACCEPT to RESPONSE.
+    span: Span,
+}
+
+/// A read the leader answers without a log entry, once a round of CONFIRM
+/// sent after the read arrived shows it still leads.
+struct Read {
+    id: u64,
+    command: Command,
+    /// Every slot below it may have been chosen before the read arrived.
+    read_index: u64,
+    /// The first CONFIRM round that can vouch for the read.
+    round: u64,
 }
+
+/// Multi-Paxos proposer. Once a PREPARE covering every slot from `first_slot`
+/// onwards is promised by a quorum, the proposer is the leader and sends only
+/// ACCEPT for new commands until a higher proposal number preempts it. Up to
+/// `window` slots are in flight at once; client commands that arrive while
+/// the window is full are batched into the next free slot.
 pub struct Proposer {
-    suggested_proposal_number: u8,
-    suggested_value: (Option<String>, Option<String>),
+    id: usize,
+    membership: Membership,
+    now: Instant,
+    timeouts: Timeouts,
+    /// Jitters timeouts; seeded with the id so replicas drift apart.
+    rng: Rng,
+    /// Phase 1 attempts that failed in a row since the last election won.
+    failures: u32,
+    proposal_number: Ballot,
+    /// The highest proposal number seen from any proposer.
+    highest_proposal_number: Ballot,
+    is_leader: bool,
     wait_for_promise: bool,
-    wait_for_accepted: bool,
-    wait_for_response: bool,
-    promise_vote_count: u8,
-    accepted_vote_count: u8,
-    proposal_number: u8,
-    proposal_value: (Option<String>, Option<String>),
-    nack_count: u8,
-    unaccepted_count: u8,
-    f: u8,
+    promise_votes: HashSet<usize>,
+    /// When PREPARE is resent if a quorum has not promised by then.
+    prepare_deadline: Instant,
+    prepare_retry_delay: Duration,
+    /// The first slot not yet applied by the local learner.
+    first_slot: u64,
+    /// The highest-numbered value reported at each slot by the PROMISEs.
+    recovered: BTreeMap<u64, PValue>,
+    /// Slots below this were compacted away by some acceptor, so they are
+    /// already chosen and must not be proposed again.
+    log_start: u64,
+    /// The next slot the leader will assign.
+    next_slot: u64,
+    in_flight: BTreeMap<u64, Proposal>,
+    window: usize,
+    max_batch: usize,
+    /// Client commands waiting for this proposer to become leader, or for
+    /// room in the window.
+    pending: VecDeque<Request>,
+    /// Reads waiting for a quorum to confirm this leader.
+    reads: VecDeque<Read>,
+    /// The last round of CONFIRM sent, and the last one a quorum answered.
+    confirm_round: u64,
+    confirmed_round: u64,
+    confirm_votes: HashSet<usize>,
+    /// When CONFIRM is resent if a quorum has not answered by then.
+    confirm_deadline: Instant,
+    /// Reads a quorum has vouched for, by id, with their read index.
+    confirmed_reads: Vec<(u64, u64, Command)>,
+    /// Outcomes of local client requests, by id, with their slot.
+    responses: Vec<(u64, u64, Outcome)>,
+    /// When the leader last sent, or a follower last received, a HEARTBEAT.
+    last_heartbeat: Instant,
+    /// The replica whose HEARTBEAT a follower last received.
+    leader: Option<usize>,
+    election_deadline: Instant,
+    /// When the current phase 1 round started.
+    election_started: Instant,
+    /// Covers the current phase 1 round from PREPARE to the last PROMISE.
+    election_span: Span,
+    /// A leader that resigned leaves client commands queued until then,
+    /// rather than running for leader again.
+    resigned_until: Instant,
+    /// How often the leader proposes a TICK, if the state machine keeps
+    /// time.
+    tick_interval: Option<Duration>,
+    /// When the leader last proposed a TICK, or became leader.
+    last_tick: Instant,
+    metrics: Metrics,
+}
+
+/// Promised/accepted state of a single log slot.
+struct Instance {
+    accepted_value: Command,
+    accepted_proposal_number: Ballot,
 }
 
 pub struct Acceptor {
-    promised_proposal_number: u8,
-    accepted_value: (Option<String>, Option<String>),
-    accepted_proposal_number: u8,
+    /// A promise covers every slot, so a single proposal number is kept.
+    promised_proposal_number: Ballot,
+    instances: BTreeMap<u64, Instance>,
+    /// Slots below this have been chosen and compacted away.
+    log_start: u64,
+    storage: Box<dyn Storage>,
+}
+
+/// ACCEPTED votes seen for a slot that has not been chosen yet.
+struct Votes {
+    proposal_number: Ballot,
+    command: Command,
+    voters: HashSet<usize>,
 }
 
 pub struct Learner {
-    kv_store: HashMap<String, String>,
-    proposal_number: u8,
+    /// What the chosen commands are applied to.
+    state: Box<dyn StateMachine>,
+    /// The replicas whose votes count at each slot.
+    membership: Membership,
+    /// Each client's last request number, its outcome and the slot the
+    /// session was last used at.
+    sessions: HashMap<u64, (u64, Outcome, u64)>,
+    session_expiry: u64,
+    votes: BTreeMap<u64, Votes>,
+    /// Chosen commands waiting for the slots before them to be chosen.
+    chosen: BTreeMap<u64, (Ballot, Command)>,
+    /// The next slot to apply to the state machine.
+    next_slot: u64,
+    /// Commands applied since the last snapshot, kept to answer CATCH_UP.
+    log: BTreeMap<u64, (Ballot, Command)>,
+    /// The first slot not covered by the last snapshot.
+    snapshot_slot: u64,
+    snapshot_interval: u64,
+    /// The leader's next slot as of the previous HEARTBEAT.
+    catch_up_slot: Option<u64>,
+    /// The last changes applied, for watches.
+    changes: ChangeLog,
+    storage: Box<dyn Storage>,
 }
 
 pub trait Role {
     fn new() -> Self;
-    fn handle_msg(&mut self, msg_type: &MsgType, msg: &str) -> Option<String>;
+    /// Handles `msg` received from replica `from`.
+    fn handle_msg(&mut self, from: usize, msg: &Message) -> Vec<Message>;
 }
 
 impl Proposer {
+    /// Makes the proposer part of a fixed cluster of replicas `0..=2f`.
     pub fn set_f(&mut self, f: u8) {
-        self.f = f;
+        self.membership = Membership::new((0..=2 * f as usize).collect());
     }
 
-    pub fn send_prepare(&mut self, msg: &str) -> Option<String> {
-        let msg: Vec<&str> = msg.split("\n").collect();
-        let method = msg[0];
-        let key = msg[1];
+    /// Adopts the members the local learner knows of. A leader runs phase 1
+    /// again if its promises are not a quorum of a configuration it may now
+    /// propose in, and steps down once its removal takes effect.
+    pub fn set_membership(&mut self, membership: &Membership) {
+        if self.membership != *membership {
+            self.membership = membership.clone();
+            let promised = self
+                .membership
+                .is_quorum_from(self.first_slot, &self.promise_votes);
+            if self.is_leader && !promised {
+                self.step_down(Duration::ZERO);
+            }
+        }
+        if self.is_leader && !self.can_lead() {
+            self.step_down(Duration::ZERO);
+        }
+    }
+
+    /// Only members of a configuration from `first_slot` on run for leader.
+    /// A replica that lags behind its removal may still lead until it has
+    /// caught up with it, which keeps replicas that disagree on the latest
+    /// members from all standing aside.
+    pub fn can_lead(&self) -> bool {
+        self.membership.is_member_from(self.first_slot, self.id)
+    }
+
+    pub fn set_id(&mut self, id: usize) {
+        self.id = id;
+        self.rng = Rng::new(id as u64);
+        self.election_deadline = self.now + self.election_timeout();
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+        self.election_deadline = self.now + self.election_timeout();
+    }
+
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.window = pipeline.window.max(1);
+        self.max_batch = pipeline.max_batch.max(1);
+    }
+
+    /// Moves the clock to `now` without acting on it, restarting the
+    /// election timer. Lets a simulation run the proposer on virtual time.
+    pub fn set_clock(&mut self, now: Instant) {
+        self.now = now;
+        self.last_heartbeat = now;
+        self.election_deadline = now + self.election_timeout();
+        self.resigned_until = now;
+        self.last_tick = now;
+    }
+
+    /// Makes the leader propose a TICK every `interval`, carrying the time
+    /// since its last one. See `StateMachine::tick_interval`.
+    pub fn set_tick_interval(&mut self, interval: Option<Duration>) {
+        self.tick_interval = interval;
+    }
+
+    /// Tells the proposer which slots the local learner has already applied,
+    /// e.g. after the learner replayed its log on startup.
+    pub fn set_first_slot(&mut self, slot: u64) {
+        self.first_slot = self.first_slot.max(slot);
+        // Slots applied from a snapshot were never answered, so retry them
+        let current = self.in_flight.split_off(&self.first_slot);
+        for (_, proposal) in std::mem::replace(&mut self.in_flight, current) {
+            self.requeue(proposal);
+        }
+        self.redirect_pending();
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+
+    /// Whether the proposer has sent PREPARE and waits for a quorum of
+    /// PROMISEs.
+    pub fn is_candidate(&self) -> bool {
+        self.wait_for_promise
+    }
+
+    /// The proposal number of the latest phase 1 this proposer ran.
+    pub fn ballot(&self) -> Ballot {
+        self.proposal_number
+    }
+
+    /// Stops leading, and stays out of elections long enough for another
+    /// replica to take over. Returns whether the proposer was leading.
+    pub fn resign(&mut self) -> bool {
+        if !self.is_leader {
+            return false;
+        }
+        let delay = self.election_timeout() * 2;
+        self.step_down(delay);
+        self.resigned_until = self.now + delay;
+        true
+    }
+
+    /// The time as of the last `tick`.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub(crate) fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// The replica leading the cluster as far as this one knows: itself, or
+    /// the one it heard a HEARTBEAT from within an election timeout.
+    pub fn leader(&self) -> Option<usize> {
+        if self.is_leader {
+            return Some(self.id);
+        }
+        self.leader
+            .filter(|_| self.now < self.last_heartbeat + self.election_timeout())
+    }
+
+    /// True when no client command is queued or awaiting its outcome.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+            && self.reads.is_empty()
+            && self
+                .in_flight
+                .values()
+                .all(|proposal| proposal.requests.is_empty())
+    }
+
+    fn election_timeout(&self) -> Duration {
+        self.timeouts.election_timeout + self.timeouts.heartbeat_interval * self.id as u32
+    }
+
+    /// A random delay between half and all of `delay`, so that proposers
+    /// retrying at the same time drift apart.
+    fn jitter(&mut self, delay: Duration) -> Duration {
+        let half = delay / 2;
+        half + half.mul_f64(self.rng.fraction())
+    }
+
+    /// How long to wait before running for leader again after being
+    /// rejected. Grows exponentially with consecutive failures.
+    fn backoff(&mut self) -> Duration {
+        let delay = self
+            .timeouts
+            .backoff_min
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.timeouts.backoff_max);
+        self.failures += 1;
+        self.jitter(delay)
+    }
+
+    /// Starts phase 1 for every slot the local learner has not applied yet.
+    pub fn send_prepare(&mut self) -> Message {
+        self.abandon_reads();
+        self.highest_proposal_number = self.highest_proposal_number.next(self.id as u32);
+        self.proposal_number = self.highest_proposal_number;
+        self.is_leader = false;
         self.wait_for_promise = true;
-        self.promise_vote_count = 0;
-        self.accepted_vote_count = 0;
-        self.nack_count = 0;
-        self.unaccepted_count = 0;
-        self.proposal_number += 1;
-        if method == "get" {
-            self.proposal_value = (Some(key.to_string()), None);
-        } else {
-            let value = msg[2];
-            self.proposal_value = (Some(key.to_string()), Some(value.to_string()));
+        self.promise_votes.clear();
+        self.recovered.clear();
+        self.election_deadline = self.now + self.election_timeout();
+        self.prepare_retry_delay = self.timeouts.prepare_timeout;
+        self.prepare_deadline = self.now + self.jitter(self.prepare_retry_delay);
+        self.metrics.elections += 1;
+        self.election_started = self.now;
+        self.election_span = debug_span!(
+            "election",
+            node = self.id,
+            ballot = %self.proposal_number,
+            slot = self.first_slot
+        );
+        debug!(parent: &self.election_span, "PREPARE");
+        Message::Prepare {
+            slot: self.first_slot,
+            proposal_number: self.proposal_number,
+        }
+    }
+
+    /// Handles a newline separated client request, whose outcome
+    /// `take_responses` hands back under `id`. The leader sends ACCEPT as soon
+    /// as the window has room; any other replica queues the command and runs
+    /// for leader. Returns `None` if the request cannot be parsed.
+    pub fn propose(&mut self, id: u64, msg: &str) -> Option<Vec<Message>> {
+        let command = Command::from_client_request(msg)?;
+        self.pending.push_back(Request { id, command });
+        if self.is_leader {
+            return Some(self.flush());
+        }
+        if self.wait_for_promise || !self.can_lead() || self.now < self.resigned_until {
+            return Some(vec![]);
+        }
+        Some(vec![self.send_prepare()])
+    }
+
+    /// Handles a client command that only reads. The leader answers it
+    /// without a log entry: it sends CONFIRM, and once a quorum shows it
+    /// still leads, `take_confirmed_reads` hands the read back to be served
+    /// as soon as the learner has applied every slot below its read index.
+    /// Any other replica proposes the read like a write.
+    pub fn read(&mut self, id: u64, command: Command) -> Vec<Message> {
+        if !self.is_leader {
+            self.pending.push_back(Request { id, command });
+            if self.wait_for_promise || !self.can_lead() || self.now < self.resigned_until {
+                return vec![];
+            }
+            return vec![self.send_prepare()];
+        }
+        // A round already under way may have been sent before the read arrived
+        self.reads.push_back(Read {
+            id,
+            command,
+            read_index: self.next_slot,
+            round: self.confirm_round + 1,
+        });
+        if self.confirmed_round < self.confirm_round {
+            return vec![];
+        }
+        vec![self.send_confirm()]
+    }
+
+    /// Reads a quorum has vouched for, by id, with the slot the learner must
+    /// reach before each is served.
+    pub fn take_confirmed_reads(&mut self) -> Vec<(u64, u64, Command)> {
+        std::mem::take(&mut self.confirmed_reads)
+    }
+
+    /// The outcomes of local client requests applied since the last call, by
+    /// id, with the slot each was applied at.
+    pub fn take_responses(&mut self) -> Vec<(u64, u64, Outcome)> {
+        std::mem::take(&mut self.responses)
+    }
+
+    fn send_confirm(&mut self) -> Message {
+        self.confirm_round += 1;
+        self.confirm_votes.clear();
+        self.confirm_deadline = self.now + self.jitter(self.timeouts.accept_timeout);
+        Message::Confirm {
+            slot: self.confirm_round,
+            proposal_number: self.proposal_number,
+        }
+    }
+
+    /// Reads still waiting for a confirmation a deposed leader cannot get go
+    /// through the log instead.
+    fn abandon_reads(&mut self) {
+        self.confirmed_round = self.confirm_round;
+        for read in std::mem::take(&mut self.reads) {
+            self.pending.push_back(Request {
+                id: read.id,
+                command: read.command,
+            });
+        }
+    }
+
+    /// Answers the queued client commands of a follower with the leader to
+    /// send them to instead.
+    fn redirect_pending(&mut self) {
+        if self.is_leader || self.wait_for_promise {
+            return;
+        }
+        let Some(leader) = self.leader() else {
+            return;
+        };
+        for request in self.pending.drain(..) {
+            self.responses.push((
+                request.id,
+                self.first_slot,
+                Outcome::NotLeader(Some(leader)),
+            ));
+        }
+    }
+
+    /// Puts the client commands of a proposal that lost its slot back in the
+    /// queue, unbatched.
+    fn requeue(&mut self, proposal: Proposal) {
+        let commands = match proposal.command {
+            Command::Batch(commands) => commands,
+            command => vec![command],
         };
-        let msg = format!(
-            "{} {}",
-            char::from(MsgType::PREPARE as u8),
-            self.proposal_number,
+        for (id, command) in proposal.requests.into_iter().zip(commands) {
+            self.pending.push_back(Request { id, command });
+        }
+    }
+
+    /// Sends queued client commands while the window has room, up to
+    /// `max_batch` of them per slot, and the members of the next slot are
+    /// known.
+    fn flush(&mut self) -> Vec<Message> {
+        let mut msgs = vec![];
+        while self.is_leader
+            && !self.pending.is_empty()
+            && self.in_flight.len() < self.window
+            && self.next_slot < self.first_slot + RECONFIG_ALPHA
+        {
+            let count = self.pending.len().min(self.max_batch);
+            let (requests, mut commands): (Vec<u64>, Vec<Command>) = self
+                .pending
+                .drain(..count)
+                .map(|request| (request.id, request.command))
+                .unzip();
+            let command = if commands.len() == 1 {
+                commands.pop().unwrap()
+            } else {
+                Command::Batch(commands)
+            };
+            msgs.push(self.accept(command, requests));
+        }
+        msgs
+    }
+
+    /// Proposes no-ops for slots the learner found missing. Only the leader
+    /// may do so without phase 1, and only for slots it has not assigned.
+    pub fn fill_gaps(&mut self, slots: &[u64]) -> Vec<Message> {
+        if !self.is_leader {
+            return vec![];
+        }
+        let slots: Vec<u64> = slots
+            .iter()
+            .filter(|&&slot| slot >= self.first_slot && !self.in_flight.contains_key(&slot))
+            .copied()
+            .collect();
+        slots
+            .into_iter()
+            .map(|slot| self.accept_at(slot, Command::Noop, vec![]))
+            .collect()
+    }
+
+    /// Advances the proposer's clock. The leader returns a HEARTBEAT or TICK
+    /// when one is due, resends ACCEPTs that timed out and sends queued
+    /// commands the window has room for; a candidate resends PREPARE
+    /// if it timed out, and a follower returns a PREPARE when the leader has
+    /// gone quiet.
+    pub fn tick(&mut self, now: Instant) -> Vec<Message> {
+        self.now = now;
+        let mut msgs = vec![];
+        if self.is_leader {
+            if now >= self.last_heartbeat + self.timeouts.heartbeat_interval {
+                self.last_heartbeat = now;
+                // The learner may have applied slots another leader chose
+                msgs.push(Message::Heartbeat {
+                    slot: self.next_slot.max(self.first_slot),
+                    proposal_number: self.proposal_number,
+                });
+            }
+            msgs.extend(self.retransmit_accepts());
+            msgs.extend(self.send_tick());
+            if self.confirmed_round < self.confirm_round && now >= self.confirm_deadline {
+                self.confirm_deadline = now + self.jitter(self.timeouts.accept_timeout);
+                msgs.push(Message::Confirm {
+                    slot: self.confirm_round,
+                    proposal_number: self.proposal_number,
+                });
+            }
+            msgs.extend(self.flush());
+        } else if self.wait_for_promise {
+            if now >= self.prepare_deadline {
+                self.prepare_retry_delay =
+                    (self.prepare_retry_delay * 2).min(self.timeouts.backoff_max);
+                self.prepare_deadline = now + self.jitter(self.prepare_retry_delay);
+                self.metrics.retries += 1;
+                debug!(parent: &self.election_span, "PREPARE resent");
+                msgs.push(Message::Prepare {
+                    slot: self.first_slot,
+                    proposal_number: self.proposal_number,
+                });
+            }
+        } else if now >= self.election_deadline && self.can_lead() {
+            msgs.push(self.send_prepare());
+        }
+        msgs
+    }
+
+    /// Proposes a TICK if one is due and the window has room. A TICK put off
+    /// carries the time it was put off for in the next one.
+    fn send_tick(&mut self) -> Option<Message> {
+        let interval = self.tick_interval?;
+        if self.now < self.last_tick + interval
+            || self.in_flight.len() >= self.window
+            || self.next_slot >= self.first_slot + RECONFIG_ALPHA
+        {
+            return None;
+        }
+        let millis = (self.now - self.last_tick).as_millis() as u64;
+        self.last_tick = self.now;
+        Some(self.accept(Command::Tick { millis }, vec![]))
+    }
+
+    /// Resends ACCEPT for every slot a quorum has not accepted in time.
+    fn retransmit_accepts(&mut self) -> Vec<Message> {
+        let now = self.now;
+        let overdue: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(&slot, proposal)| {
+                now >= proposal.deadline
+                    && !self.membership.is_quorum(slot, &proposal.accepted_votes)
+            })
+            .map(|(&slot, _)| slot)
+            .collect();
+        let mut msgs = vec![];
+        for slot in overdue {
+            let retry_delay =
+                (self.in_flight[&slot].retry_delay * 2).min(self.timeouts.backoff_max);
+            let deadline = now + self.jitter(retry_delay);
+            let proposal = self.in_flight.get_mut(&slot).unwrap();
+            proposal.retry_delay = retry_delay;
+            proposal.deadline = deadline;
+            self.metrics.retries += 1;
+            debug!(parent: &proposal.span, "ACCEPT resent");
+            msgs.push(Message::Accept {
+                slot,
+                proposal_number: self.proposal_number,
+                command: proposal.command.clone(),
+            });
+        }
+        msgs
+    }
+
+    fn accept(&mut self, command: Command, requests: Vec<u64>) -> Message {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.accept_at(slot, command, requests)
+    }
+
+    fn accept_at(&mut self, slot: u64, command: Command, requests: Vec<u64>) -> Message {
+        let retry_delay = self.timeouts.accept_timeout;
+        let deadline = self.now + self.jitter(retry_delay);
+        let span = debug_span!(
+            "slot",
+            node = self.id,
+            slot,
+            ballot = %self.proposal_number
+        );
+        debug!(parent: &span, requests = requests.len(), "ACCEPT");
+        self.in_flight.insert(
+            slot,
+            Proposal {
+                command: command.clone(),
+                requests,
+                accepted_votes: HashSet::new(),
+                deadline,
+                retry_delay,
+                sent_at: self.now,
+                span,
+            },
         );
-        Some(msg)
+        Message::Accept {
+            slot,
+            proposal_number: self.proposal_number,
+            command,
+        }
+    }
+
+    /// Called once a quorum has promised: re-proposes every recovered value,
+    /// fills the holes between them with no-ops and sends queued commands the
+    /// window has room for.
+    fn become_leader(&mut self) -> Vec<Message> {
+        self.is_leader = true;
+        self.wait_for_promise = false;
+        self.failures = 0;
+        self.last_heartbeat = self.now;
+        // Time the cluster spent without a leader is not counted
+        self.last_tick = self.now;
+        self.metrics.elections_won += 1;
+        self.metrics
+            .election_latency
+            .observe(self.now - self.election_started);
+        info!(parent: &self.election_span, "elected");
+        self.election_span = Span::none();
+        self.first_slot = self.first_slot.max(self.log_start);
+
+        let mut msgs = vec![];
+        let mut previous = std::mem::take(&mut self.in_flight);
+        // Our commands still in flight at slots the learner has applied since
+        // lost them, or were applied from a snapshot; either way, retry them
+        let current = previous.split_off(&self.first_slot);
+        for (_, own) in std::mem::replace(&mut previous, current) {
+            self.requeue(own);
+        }
+        let recovered = std::mem::take(&mut self.recovered);
+        let last_slot = recovered
+            .keys()
+            .chain(previous.keys())
+            .copied()
+            .max()
+            .map_or(self.first_slot, |slot| slot + 1)
+            // Slots recovered before the learner caught up are already decided
+            .max(self.first_slot);
+        for slot in self.first_slot..last_slot {
+            let own = previous.remove(&slot);
+            match (recovered.get(&slot), own) {
+                (Some(pvalue), Some(own)) if own.command == pvalue.command => {
+                    msgs.push(self.accept_at(slot, own.command, own.requests));
+                }
+                (Some(pvalue), own) => {
+                    msgs.push(self.accept_at(slot, pvalue.command.clone(), vec![]));
+                    // Our commands lost the slot, so they go to the back of the queue
+                    if let Some(own) = own {
+                        self.requeue(own);
+                    }
+                }
+                (None, Some(own)) => msgs.push(self.accept_at(slot, own.command, own.requests)),
+                (None, None) => msgs.push(self.accept_at(slot, Command::Noop, vec![])),
+            }
+        }
+        self.next_slot = last_slot;
+        msgs.extend(self.flush());
+        msgs
     }
 
-    pub fn reset(&mut self) {
-        self.suggested_proposal_number = 0;
-        self.suggested_value = (None, None);
+    /// Whether the PROMISEs so far are a quorum of every configuration from
+    /// `first_slot` on, including those the recovered values bring in.
+    fn has_promise_quorum(&self) -> bool {
+        let mut membership = self.membership.clone();
+        for pvalue in self.recovered.values() {
+            if let Some(members) = pvalue.command.new_members() {
+                membership.reconfigure(pvalue.slot, members.to_vec());
+            }
+        }
+        membership.is_quorum_from(self.first_slot, &self.promise_votes)
+    }
+
+    fn step_down(&mut self, election_delay: Duration) {
+        self.abandon_reads();
+        self.is_leader = false;
         self.wait_for_promise = false;
-        self.wait_for_accepted = false;
-        self.wait_for_response = false;
-        self.promise_vote_count = 0;
-        self.accepted_vote_count = 0;
-        // self.proposal_number = 0;
-        self.proposal_value = (None, None);
-        self.nack_count = 0;
-        self.unaccepted_count = 0;
+        self.election_deadline = self.now + election_delay;
     }
 }
 
 impl Acceptor {
-    pub fn flush_accepted_value(&mut self) {
-        self.accepted_value = (None, None);
+    /// Restores the promised and accepted state recorded in `storage`. Every
+    /// PROMISE and ACCEPTED is written to `storage` before it is returned.
+    pub fn with_storage(mut storage: Box<dyn Storage>) -> io::Result<Acceptor> {
+        let records = storage.load()?;
+        let mut acceptor = Acceptor::new();
+        acceptor.storage = storage;
+        for record in records {
+            match record {
+                Record::Promised { proposal_number } => {
+                    acceptor.promised_proposal_number =
+                        acceptor.promised_proposal_number.max(proposal_number);
+                }
+                Record::Accepted {
+                    slot,
+                    proposal_number,
+                    command,
+                } => {
+                    acceptor.promised_proposal_number =
+                        acceptor.promised_proposal_number.max(proposal_number);
+                    acceptor.instances.insert(
+                        slot,
+                        Instance {
+                            accepted_value: command,
+                            accepted_proposal_number: proposal_number,
+                        },
+                    );
+                }
+                Record::Truncated { slot } => {
+                    acceptor.log_start = acceptor.log_start.max(slot);
+                    acceptor.instances = acceptor.instances.split_off(&slot);
+                }
+                Record::Chosen { .. } | Record::Snapshot { .. } => {}
+            }
+        }
+        Ok(acceptor)
+    }
+
+    pub fn promised_proposal_number(&self) -> Ballot {
+        self.promised_proposal_number
+    }
+
+    /// The first slot this acceptor still keeps state for.
+    pub fn log_start(&self) -> u64 {
+        self.log_start
+    }
+
+    /// Forgets every slot below `slot`. Only safe once those slots are chosen
+    /// and covered by a learner snapshot.
+    pub fn compact(&mut self, slot: u64) -> io::Result<()> {
+        if slot <= self.log_start {
+            return Ok(());
+        }
+        let instances = self.instances.split_off(&slot);
+        let mut records = vec![
+            Record::Promised {
+                proposal_number: self.promised_proposal_number,
+            },
+            Record::Truncated { slot },
+        ];
+        records.extend(instances.iter().map(|(&slot, instance)| Record::Accepted {
+            slot,
+            proposal_number: instance.accepted_proposal_number,
+            command: instance.accepted_value.clone(),
+        }));
+        if let Err(e) = self.storage.rewrite(&records) {
+            self.instances.extend(instances);
+            return Err(e);
+        }
+        self.instances = instances;
+        self.log_start = slot;
+        Ok(())
+    }
+
+    /// The values accepted at `from` or later, in slot order, with the
+    /// proposal number each was accepted under.
+    pub fn accepted(&self, from: u64) -> impl Iterator<Item = (u64, Ballot, &Command)> {
+        self.instances.range(from..).map(|(&slot, instance)| {
+            (
+                slot,
+                instance.accepted_proposal_number,
+                &instance.accepted_value,
+            )
+        })
+    }
+
+    /// The first slot this acceptor has not seen any proposal for.
+    pub fn next_slot(&self) -> u64 {
+        self.instances
+            .keys()
+            .next_back()
+            .map_or(self.log_start, |slot| slot + 1)
     }
 }
 
 impl Learner {
-    pub fn get_kv_store(&self) -> &HashMap<String, String> {
-        &self.kv_store
+    /// A learner with a key-value store, rebuilt from `storage`.
+    pub fn with_storage(storage: Box<dyn Storage>) -> io::Result<Learner> {
+        Learner::with_state_machine(Box::new(KvStore::default()), storage)
+    }
+
+    /// Rebuilds `state` by replaying the commands recorded in `storage`.
+    /// Every command is written to `storage` before it is applied.
+    pub fn with_state_machine(
+        state: Box<dyn StateMachine>,
+        mut storage: Box<dyn Storage>,
+    ) -> io::Result<Learner> {
+        let records = storage.load()?;
+        let mut learner = Learner::new();
+        learner.state = state;
+        learner.storage = storage;
+        for record in records {
+            match record {
+                Record::Chosen {
+                    slot,
+                    proposal_number,
+                    command,
+                } if slot == learner.next_slot => {
+                    learner.expire_sessions();
+                    learner.apply(&command);
+                    learner.log.insert(slot, (proposal_number, command));
+                    learner.next_slot += 1;
+                }
+                Record::Snapshot {
+                    slot,
+                    state,
+                    sessions,
+                    configs,
+                } => {
+                    learner
+                        .state
+                        .restore(&state)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    learner.membership = Membership::from_configs(configs);
+                    learner.sessions = sessions
+                        .into_iter()
+                        .map(|(client, seq, outcome, last_slot)| {
+                            (client, (seq, outcome, last_slot))
+                        })
+                        .collect();
+                    learner.log.clear();
+                    learner.changes.reset(slot);
+                    learner.next_slot = slot;
+                    learner.snapshot_slot = slot;
+                }
+                _ => {}
+            }
+        }
+        Ok(learner)
+    }
+
+    /// Makes the learner part of a fixed cluster of replicas `0..=2f`.
+    pub fn set_f(&mut self, f: u8) {
+        self.membership = Membership::new((0..=2 * f as usize).collect());
+    }
+
+    /// Sets the members the cluster started with, before any
+    /// reconfiguration.
+    pub fn set_initial_members(&mut self, members: Vec<usize>) {
+        self.membership.set_initial(members);
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// Sets how many slots are applied between automatic snapshots.
+    pub fn set_snapshot_interval(&mut self, interval: u64) {
+        self.snapshot_interval = interval;
+    }
+
+    /// Sets how many slots a session outlives its last request, in place of
+    /// `SESSION_EXPIRY`. Every replica has to use the same expiry from the
+    /// first slot on, or they forget different sessions.
+    pub fn set_session_expiry(&mut self, slots: u64) {
+        self.session_expiry = slots;
+    }
+
+    /// The first slot not covered by the last snapshot. The local acceptor
+    /// may compact everything below it.
+    pub fn snapshot_slot(&self) -> u64 {
+        self.snapshot_slot
+    }
+
+    /// Replaces the log with a snapshot of the state machine, the sessions
+    /// and the members.
+    pub fn snapshot(&mut self) -> io::Result<()> {
+        self.membership.forget_before(self.next_slot);
+        let record = Record::Snapshot {
+            slot: self.next_slot,
+            state: self.state.snapshot(),
+            sessions: self.sorted_sessions(),
+            configs: self.membership.configs(),
+        };
+        self.storage.rewrite(&[record])?;
+        self.log.clear();
+        self.snapshot_slot = self.next_slot;
+        Ok(())
+    }
+
+    fn sorted_sessions(&self) -> Vec<(u64, u64, Outcome, u64)> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .map(|(&client, (seq, outcome, last_slot))| (client, *seq, outcome.clone(), *last_slot))
+            .collect();
+        sessions.sort_by_key(|&(client, _, _, _)| client);
+        sessions
+    }
+
+    /// Forgets the sessions unused for `session_expiry` slots. Runs before
+    /// applying each slot, but only prunes at multiples of the expiry, so
+    /// every replica forgets the same sessions at the same slot.
+    fn expire_sessions(&mut self) {
+        let slot = self.next_slot;
+        if slot > 0 && slot.is_multiple_of(self.session_expiry) {
+            let expiry = self.session_expiry;
+            self.sessions
+                .retain(|_, (_, _, last_slot)| *last_slot + expiry > slot);
+        }
+    }
+
+    pub fn state_machine(&self) -> &dyn StateMachine {
+        self.state.as_ref()
+    }
+
+    /// What GET `key` would answer now, if it answers with a value.
+    pub fn get_value(&self, key: &str) -> Option<String> {
+        let get = Command::Get {
+            key: key.to_string(),
+        };
+        match self.state.read(&get) {
+            Some(Outcome::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The commands applied at `from` or later, in slot order. Only slots
+    /// since the last snapshot are known.
+    pub fn applied(&self, from: u64) -> impl Iterator<Item = (u64, &Command)> {
+        self.log
+            .range(from..)
+            .map(|(&slot, (_, command))| (slot, command))
+    }
+
+    /// The changes applied at `from` or later, in log order. `Err` with the
+    /// first slot whose changes are known if that is after `from`.
+    pub fn changes(&self, from: u64) -> Result<impl Iterator<Item = &Change>, u64> {
+        self.changes.since(from)
+    }
+
+    /// The first slot that has not been applied to the state machine.
+    pub fn next_slot(&self) -> u64 {
+        self.next_slot
+    }
+
+    /// The first slot after every slot known to be chosen.
+    pub fn next_free_slot(&self) -> u64 {
+        self.chosen
+            .keys()
+            .next_back()
+            .map_or(self.next_slot, |slot| slot + 1)
+    }
+
+    /// Unchosen slots below the highest chosen slot. They block every later
+    /// command from being applied until they are filled.
+    pub fn missing_slots(&self) -> Vec<u64> {
+        (self.next_slot..self.next_free_slot())
+            .filter(|slot| !self.chosen.contains_key(slot))
+            .collect()
     }
-    pub fn get_value(&self, key: &str) -> Option<&String> {
-        self.kv_store.get(key)
+
+    /// Applies every chosen command up to the first gap, returning a RESPONSE
+    /// for each.
+    fn apply_chosen(&mut self) -> Vec<Message> {
+        let mut responses = vec![];
+        loop {
+            // Applying a slot tells the members of one more slot
+            self.promote_votes();
+            let Some((proposal_number, command)) = self.chosen.remove(&self.next_slot) else {
+                break;
+            };
+            let record = Record::Chosen {
+                slot: self.next_slot,
+                proposal_number,
+                command: command.clone(),
+            };
+            if let Err(e) = self.storage.append(&[record]) {
+                // Retried when another slot is chosen, rather than applied unlogged
+                error!("failed to persist chosen command: {}", e);
+                self.chosen
+                    .insert(self.next_slot, (proposal_number, command));
+                break;
+            }
+            self.expire_sessions();
+            let outcome = self.apply(&command);
+            self.log
+                .insert(self.next_slot, (proposal_number, command.clone()));
+            responses.push(Message::Response {
+                slot: self.next_slot,
+                proposal_number,
+                command,
+                outcome,
+            });
+            self.next_slot += 1;
+        }
+        if self.snapshot_interval > 0
+            && self.next_slot - self.snapshot_slot >= self.snapshot_interval
+        {
+            if let Err(e) = self.snapshot() {
+                error!("failed to write snapshot: {}", e);
+            }
+        }
+        responses
+    }
+
+    /// Moves every slot a quorum of its members has accepted to `chosen`.
+    /// The members of a slot are known once the learner is less than
+    /// `RECONFIG_ALPHA` slots behind it.
+    fn promote_votes(&mut self) {
+        let known = self.next_slot + RECONFIG_ALPHA;
+        let quorums: Vec<u64> = self
+            .votes
+            .range(..known)
+            .filter(|(&slot, votes)| self.membership.is_quorum(slot, &votes.voters))
+            .map(|(&slot, _)| slot)
+            .collect();
+        for slot in quorums {
+            let votes = self.votes.remove(&slot).unwrap();
+            self.chosen
+                .insert(slot, (votes.proposal_number, votes.command));
+        }
+    }
+
+    /// Replaces the state machine, the sessions and the members with a
+    /// peer's snapshot taken at `slot`.
+    fn install_snapshot(
+        &mut self,
+        slot: u64,
+        state: &[u8],
+        sessions: &[(u64, u64, Outcome, u64)],
+        configs: &[(u64, Vec<usize>)],
+    ) -> Vec<Message> {
+        if slot <= self.next_slot {
+            return vec![];
+        }
+        let previous = self.state.snapshot();
+        if let Err(e) = self.state.restore(state) {
+            error!("failed to install snapshot: {}", e);
+            return vec![];
+        }
+        let record = Record::Snapshot {
+            slot,
+            state: state.to_vec(),
+            sessions: sessions.to_vec(),
+            configs: configs.to_vec(),
+        };
+        if let Err(e) = self.storage.rewrite(&[record]) {
+            error!("failed to install snapshot: {}", e);
+            let _ = self.state.restore(&previous);
+            return vec![];
+        }
+        self.sessions = sessions
+            .iter()
+            .map(|(client, seq, outcome, last_slot)| (*client, (*seq, outcome.clone(), *last_slot)))
+            .collect();
+        self.membership = Membership::from_configs(configs.to_vec());
+        self.log.clear();
+        self.changes.reset(slot);
+        self.next_slot = slot;
+        self.snapshot_slot = slot;
+        self.votes = self.votes.split_off(&slot);
+        self.chosen = self.chosen.split_off(&slot);
+        self.apply_chosen()
+    }
+
+    fn apply(&mut self, command: &Command) -> Outcome {
+        match command {
+            // A retried request is answered from the session, not applied
+            // again. A session that expired starts over.
+            Command::Session {
+                client,
+                seq,
+                command,
+            } => {
+                let slot = self.next_slot;
+                match self.sessions.get_mut(client) {
+                    Some((last, outcome, last_slot)) if *last == *seq => {
+                        *last_slot = slot;
+                        outcome.clone()
+                    }
+                    Some((last, _, last_slot)) if *last > *seq => {
+                        *last_slot = slot;
+                        Outcome::Expired
+                    }
+                    _ => {
+                        let outcome = self.apply(command);
+                        self.sessions.insert(*client, (*seq, outcome.clone(), slot));
+                        outcome
+                    }
+                }
+            }
+            Command::Batch(commands) => {
+                Outcome::Batch(commands.iter().map(|command| self.apply(command)).collect())
+            }
+            // Applied while `next_slot` is the slot it was chosen at
+            Command::Reconfigure { members } => {
+                Outcome::Reconfigured(self.membership.reconfigure(self.next_slot, members.clone()))
+            }
+            Command::Noop => Outcome::Noop,
+            command => {
+                let keys = self.state.changed_keys(command);
+                let old: Vec<Option<String>> = keys.iter().map(|key| self.get_value(key)).collect();
+                let outcome = self.state.apply(self.next_slot, command);
+                for (key, old) in keys.into_iter().zip(old) {
+                    let new = self.get_value(&key);
+                    if new != old {
+                        self.changes.push(Change {
+                            slot: self.next_slot,
+                            key,
+                            old,
+                            new,
+                        });
+                    }
+                }
+                outcome
+            }
+        }
     }
 
-    pub fn print_kv_store(&self) {
-        for (key, value) in &self.kv_store {
-            println!("{}: {}", key, value);
+    /// Answers a command that only reads from the state machine as it
+    /// stands now. `None` if the command writes.
+    pub fn read(&self, command: &Command) -> Option<Outcome> {
+        match command {
+            Command::Session { command, .. } => self.read(command),
+            Command::Noop | Command::Batch(_) | Command::Reconfigure { .. } => None,
+            command => self.state.read(command),
         }
     }
 }
 
 impl Role for Proposer {
     fn new() -> Self {
+        let now = Instant::now();
         Proposer {
-            suggested_proposal_number: 0,
-            suggested_value: (None, None),
+            id: 0,
+            membership: Membership::new(vec![0]),
+            now,
+            timeouts: Timeouts::default(),
+            rng: Rng::new(0),
+            failures: 0,
+            proposal_number: Ballot::default(),
+            highest_proposal_number: Ballot::default(),
+            is_leader: false,
             wait_for_promise: false,
-            wait_for_accepted: false,
-            wait_for_response: false,
-            proposal_number: 0,
-            promise_vote_count: 0,
-            accepted_vote_count: 0,
-            nack_count: 0,
-            unaccepted_count: 0,
-            f: 0,
-            proposal_value: (None, None),
-        }
-    }
-    fn handle_msg(&mut self, msg_type: &MsgType, msg: &str) -> Option<String> {
-        let split_msg: Vec<&str> = msg.split(" ").filter(|&msg| msg != "").collect();
-        match msg_type {
-            MsgType::PROMISE => {
-                if self.wait_for_promise {
-                    let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                    if proposal_number != self.proposal_number {
-                        return None;
-                    }
-                    self.promise_vote_count += 1;
-                    let accepted_proposal_number = split_msg[1].parse::<u8>().unwrap();
-                    if accepted_proposal_number > self.suggested_proposal_number {
-                        self.suggested_proposal_number = accepted_proposal_number;
-                        let key = split_msg[2].to_string();
-                        if split_msg.len() == 4 {
-                            let value = split_msg[3].to_string();
-                            self.suggested_value = (Some(key), Some(value));
-                        } else {
-                            self.suggested_value = (Some(key), None);
-                        }
-                    }
+            promise_votes: HashSet::new(),
+            prepare_deadline: now,
+            prepare_retry_delay: PREPARE_TIMEOUT,
+            first_slot: 0,
+            recovered: BTreeMap::new(),
+            log_start: 0,
+            next_slot: 0,
+            in_flight: BTreeMap::new(),
+            window: PIPELINE_WINDOW,
+            max_batch: MAX_BATCH,
+            pending: VecDeque::new(),
+            reads: VecDeque::new(),
+            confirm_round: 0,
+            confirmed_round: 0,
+            confirm_votes: HashSet::new(),
+            confirm_deadline: now,
+            confirmed_reads: Vec::new(),
+            responses: Vec::new(),
+            last_heartbeat: now,
+            leader: None,
+            election_deadline: now + ELECTION_TIMEOUT,
+            election_started: now,
+            election_span: Span::none(),
+            resigned_until: now,
+            tick_interval: None,
+            last_tick: now,
+            metrics: Metrics::default(),
+        }
+    }
 
-                    if self.promise_vote_count >= self.f + 1 {
-                        self.wait_for_promise = false;
-                        self.wait_for_accepted = true;
-                        return if self.suggested_proposal_number == 0 {
-                            if self.proposal_value.1.is_some() {
-                                let msg = format!(
-                                    "{} {} {} {}",
-                                    char::from(MsgType::ACCEPT as u8),
-                                    self.proposal_number,
-                                    self.proposal_value.0.as_ref().unwrap(),
-                                    self.proposal_value.1.as_ref().unwrap().clone(),
-                                );
-                                Some(msg)
-                            } else {
-                                let msg = format!(
-                                    "{} {} {}",
-                                    char::from(MsgType::ACCEPT as u8),
-                                    self.proposal_number,
-                                    self.proposal_value.0.as_ref().unwrap(),
-                                );
-                                Some(msg)
-                            }
-                        } else {
-                            if self.suggested_value.1.is_some() {
-                                let msg = format!(
-                                    "{} {} {} {}",
-                                    char::from(MsgType::ACCEPT as u8),
-                                    self.proposal_number,
-                                    self.suggested_value.0.as_ref().unwrap(),
-                                    self.suggested_value.1.as_ref().unwrap().clone(),
-                                );
-                                Some(msg)
-                            } else {
-                                let msg = format!(
-                                    "{} {} {}",
-                                    char::from(MsgType::ACCEPT as u8),
-                                    self.proposal_number,
-                                    self.suggested_value.0.as_ref().unwrap(),
-                                );
-                                Some(msg)
-                            }
-                        };
+    /// Returns the next messages to broadcast. The outcomes of local client
+    /// commands in a RESPONSE are kept for `take_responses`.
+    fn handle_msg(&mut self, from: usize, msg: &Message) -> Vec<Message> {
+        match msg {
+            // A PROMISE from before the learner moved on also covers the slots
+            // after first_slot
+            Message::Promise {
+                slot,
+                proposal_number,
+                log_start,
+                accepted,
+            } if self.wait_for_promise
+                && *proposal_number == self.proposal_number
+                && *slot <= self.first_slot =>
+            {
+                debug!(parent: &self.election_span, from, "PROMISE");
+                self.promise_votes.insert(from);
+                self.log_start = self.log_start.max(*log_start);
+                for pvalue in accepted {
+                    let newer = self
+                        .recovered
+                        .get(&pvalue.slot)
+                        .is_none_or(|known| pvalue.proposal_number > known.proposal_number);
+                    if pvalue.slot >= self.first_slot && newer {
+                        self.recovered.insert(pvalue.slot, pvalue.clone());
                     }
                 }
+                if self.has_promise_quorum() {
+                    return self.become_leader();
+                }
             }
-            MsgType::RESPONSE => {
-                    let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                    if proposal_number != self.proposal_number {
-                        return None;
+            Message::Accepted {
+                slot,
+                proposal_number,
+                command,
+            } if *proposal_number == self.proposal_number => {
+                if let Some(proposal) = self.in_flight.get_mut(slot) {
+                    let chosen = self.membership.is_quorum(*slot, &proposal.accepted_votes);
+                    if proposal.command == *command {
+                        debug!(parent: &proposal.span, from, "ACCEPTED");
+                        proposal.accepted_votes.insert(from);
                     }
-                    self.reset();
-                    let response_type = split_msg[1].parse::<u8>().unwrap();
-                    if response_type == 0 {
-                        return Some("put successful!".to_string());
-                    } else if response_type == 1 {
-                        let value = split_msg[2].to_string();
-                        return Some("get successful! value:".to_string() + &value);
+                    if !chosen && self.membership.is_quorum(*slot, &proposal.accepted_votes) {
+                        self.metrics
+                            .accept_latency
+                            .observe(self.now - proposal.sent_at);
+                    }
+                    // Clients are answered once the learner applies the slot
+                    if self.membership.is_quorum(*slot, &proposal.accepted_votes)
+                        && proposal.requests.is_empty()
+                    {
+                        self.in_flight.remove(slot);
+                        return self.flush();
+                    }
+                }
+            }
+            // The slot has been chosen, possibly with another proposer's command
+            Message::Response {
+                slot,
+                command,
+                outcome,
+                ..
+            } => {
+                self.first_slot = self.first_slot.max(slot + 1);
+                if let Some(proposal) = self.in_flight.remove(slot) {
+                    if proposal.command == *command {
+                        debug!(parent: &proposal.span, "RESPONSE");
+                        let outcomes = match outcome {
+                            Outcome::Batch(outcomes) => outcomes.clone(),
+                            outcome => vec![outcome.clone()],
+                        };
+                        for (id, outcome) in proposal.requests.into_iter().zip(outcomes) {
+                            self.responses.push((id, *slot, outcome));
+                        }
                     } else {
-                        return Some("get failed!".to_string());
+                        debug!(parent: &proposal.span, "lost the slot");
+                        self.requeue(proposal);
                     }
-                // }
+                    return self.flush();
+                }
             }
-            MsgType::ACCEPTED => {
-                if self.wait_for_accepted {
-                    self.wait_for_response = true;
-                    let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                    if proposal_number != self.proposal_number {
-                        return None;
+            Message::Confirmed {
+                slot,
+                proposal_number,
+            } if self.is_leader
+                && *proposal_number == self.proposal_number
+                && *slot == self.confirm_round
+                && self.confirmed_round < self.confirm_round =>
+            {
+                self.confirm_votes.insert(from);
+                if self
+                    .membership
+                    .is_quorum_from(self.first_slot, &self.confirm_votes)
+                {
+                    self.confirmed_round = self.confirm_round;
+                    while let Some(read) = self.reads.front() {
+                        if read.round > self.confirmed_round {
+                            break;
+                        }
+                        let read = self.reads.pop_front().unwrap();
+                        self.confirmed_reads
+                            .push((read.id, read.read_index, read.command));
                     }
-                    self.accepted_vote_count += 1;
-                    if self.accepted_vote_count >= self.f + 1 {
-                        self.wait_for_accepted = false;
-                        // self.proposal_number += 1;
-                        self.proposal_value = (None, None);
-                        self.suggested_proposal_number = 0;
-                        self.suggested_value = (None, None);
-                        self.promise_vote_count = 0;
-                        self.accepted_vote_count = 0;
-                        self.nack_count = 0;
-                        self.unaccepted_count = 0;
-                        return None;
+                    // Reads that arrived during this round need another one
+                    if !self.reads.is_empty() {
+                        return vec![self.send_confirm()];
                     }
                 }
             }
-            MsgType::UNACCEPTED => {
-                let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                if proposal_number != self.proposal_number {
-                    return None;
+            Message::Nack {
+                proposal_number,
+                promised_proposal_number,
+                ..
+            } => {
+                self.highest_proposal_number =
+                    self.highest_proposal_number.max(*promised_proposal_number);
+                if self.wait_for_promise && *proposal_number == self.proposal_number {
+                    self.metrics.nacks += 1;
+                    debug!(
+                        parent: &self.election_span,
+                        from,
+                        promised = %promised_proposal_number,
+                        "NACK"
+                    );
+                    let delay = self.backoff();
+                    self.step_down(delay);
                 }
             }
-            MsgType::NACK => {
-                let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                if proposal_number != self.proposal_number {
-                    return None;
+            Message::Unaccepted {
+                slot,
+                proposal_number,
+                promised_proposal_number,
+            } => {
+                self.highest_proposal_number =
+                    self.highest_proposal_number.max(*promised_proposal_number);
+                // Run again with a ballot above the one that preempted us
+                if self.is_leader && *proposal_number == self.proposal_number {
+                    self.metrics.nacks += 1;
+                    debug!(
+                        node = self.id,
+                        from,
+                        slot,
+                        promised = %promised_proposal_number,
+                        "UNACCEPTED"
+                    );
+                    let delay = self.backoff();
+                    self.step_down(delay);
+                }
+            }
+            Message::Heartbeat {
+                proposal_number, ..
+            } if from != self.id => {
+                self.highest_proposal_number = self.highest_proposal_number.max(*proposal_number);
+                if *proposal_number >= self.proposal_number {
+                    self.last_heartbeat = self.now;
+                    self.leader = Some(from);
+                    // Client commands go to that leader rather than to a
+                    // leader of our own
+                    if self.is_leader || self.wait_for_promise {
+                        self.step_down(self.election_timeout());
+                    } else {
+                        self.election_deadline = self.now + self.election_timeout();
+                    }
+                    self.redirect_pending();
                 }
-                self.proposal_number = split_msg[1].parse::<u8>().unwrap();
-                self.proposal_number += 1;
-                self.wait_for_promise = true;
-                self.promise_vote_count = 0;
-                self.accepted_vote_count = 0;
-                self.nack_count = 0;
-                self.unaccepted_count = 0;
-                let msg = format!(
-                    "{} {}",
-                    char::from(MsgType::PREPARE as u8),
-                    self.proposal_number
-                );
-                sleep(Duration::from_millis(1000));
-                return Some(msg);
             }
             _ => {}
         }
-        None
+        vec![]
     }
 }
 
 impl Role for Acceptor {
     fn new() -> Self {
         Acceptor {
-            accepted_value: (None, None),
-            promised_proposal_number: 0,
-            accepted_proposal_number: 0,
+            promised_proposal_number: Ballot::default(),
+            instances: BTreeMap::new(),
+            log_start: 0,
+            storage: Box::new(MemStorage::default()),
         }
     }
-    fn handle_msg(&mut self, msg_type: &MsgType, msg: &str) -> Option<String> {
-        let split_msg: Vec<&str> = msg.split(" ").filter(|&msg| msg != "").collect();
 
-        match msg_type {
-            MsgType::PREPARE => {
-                let proposal_number = split_msg[0].parse().unwrap();
-                if self.promised_proposal_number < proposal_number {
-                    self.promised_proposal_number = proposal_number;
-                    if self.accepted_value.0.is_some() {
-                        return if self.accepted_value.1.is_some() {
-                            let msg = format!(
-                                "{} {} {} {} {}",
-                                char::from(MsgType::PROMISE as u8),
-                                proposal_number,
-                                self.accepted_proposal_number,
-                                self.accepted_value.0.as_ref().unwrap(),
-                                self.accepted_value.1.as_ref().unwrap(),
-                            );
-                            Some(msg)
-                        } else {
-                            let msg = format!(
-                                "{} {} {} {}",
-                                char::from(MsgType::PROMISE as u8),
-                                proposal_number,
-                                self.accepted_proposal_number,
-                                self.accepted_value.0.as_ref().unwrap(),
-                            );
-                            Some(msg)
-                        };
-                    } else {
-                        let msg = format!(
-                            "{} {} {}",
-                            char::from(MsgType::PROMISE as u8),
-                            proposal_number,
-                            0,
-                        );
-                        return Some(msg);
+    fn handle_msg(&mut self, _from: usize, msg: &Message) -> Vec<Message> {
+        match msg {
+            Message::Prepare {
+                slot,
+                proposal_number,
+            } => {
+                let (slot, proposal_number) = (*slot, *proposal_number);
+                // An equal ballot is a retransmitted PREPARE
+                if self.promised_proposal_number <= proposal_number {
+                    if self.promised_proposal_number < proposal_number {
+                        let record = Record::Promised { proposal_number };
+                        if let Err(e) = self.storage.append(&[record]) {
+                            error!("failed to persist promise: {}", e);
+                            return vec![];
+                        }
                     }
+                    self.promised_proposal_number = proposal_number;
+                    let accepted = self
+                        .instances
+                        .range(slot..)
+                        .map(|(&slot, instance)| PValue {
+                            slot,
+                            proposal_number: instance.accepted_proposal_number,
+                            command: instance.accepted_value.clone(),
+                        })
+                        .collect();
+                    vec![Message::Promise {
+                        slot,
+                        proposal_number,
+                        log_start: self.log_start,
+                        accepted,
+                    }]
                 } else {
-                    let msg = format!(
-                        "{} {} {}",
-                        char::from(MsgType::NACK as u8),
+                    vec![Message::Nack {
+                        slot,
                         proposal_number,
-                        self.promised_proposal_number,
-                    );
-                    return Some(msg);
+                        promised_proposal_number: self.promised_proposal_number,
+                    }]
                 }
             }
-            MsgType::ACCEPT => {
-                let proposal_number = split_msg[0].parse().unwrap();
+            Message::Accept {
+                slot,
+                proposal_number,
+                command,
+            } => {
+                let (slot, proposal_number) = (*slot, *proposal_number);
+                if slot < self.log_start {
+                    // Already chosen; the value is only kept in snapshots now
+                    return vec![];
+                }
                 if self.promised_proposal_number <= proposal_number {
-                    self.accepted_proposal_number = proposal_number;
-                    let key = split_msg[1].to_string();
-                    return if split_msg.len() == 3 {
-                        let value = split_msg[2].to_string();
-                        self.accepted_value = (Some(key), Some(value));
-                        let msg = format!(
-                            "{} {} {} {}",
-                            char::from(MsgType::ACCEPTED as u8),
-                            proposal_number,
-                            self.accepted_value.0.as_ref().unwrap(),
-                            self.accepted_value.1.as_ref().unwrap(),
-                        );
-                        Some(msg)
-                    } else {
-                        self.accepted_value = (Some(key), None);
-                        let msg = format!(
-                            "{} {} {}",
-                            char::from(MsgType::ACCEPTED as u8),
-                            proposal_number,
-                            self.accepted_value.0.as_ref().unwrap(),
-                        );
-                        Some(msg)
+                    let record = Record::Accepted {
+                        slot,
+                        proposal_number,
+                        command: command.clone(),
                     };
+                    if let Err(e) = self.storage.append(&[record]) {
+                        error!("failed to persist accepted value: {}", e);
+                        return vec![];
+                    }
+                    self.promised_proposal_number = proposal_number;
+                    self.instances.insert(
+                        slot,
+                        Instance {
+                            accepted_value: command.clone(),
+                            accepted_proposal_number: proposal_number,
+                        },
+                    );
+                    vec![Message::Accepted {
+                        slot,
+                        proposal_number,
+                        command: command.clone(),
+                    }]
                 } else {
-                    let msg = format!(
-                        "{} {} {}",
-                        char::from(MsgType::UNACCEPTED as u8),
+                    vec![Message::Unaccepted {
+                        slot,
                         proposal_number,
-                        self.promised_proposal_number,
-                    );
-                    return Some(msg);
+                        promised_proposal_number: self.promised_proposal_number,
+                    }]
                 }
             }
-            _ => {}
+            // Unlike PREPARE, CONFIRM promises nothing
+            Message::Confirm {
+                slot,
+                proposal_number,
+            } if self.promised_proposal_number <= *proposal_number => {
+                vec![Message::Confirmed {
+                    slot: *slot,
+                    proposal_number: *proposal_number,
+                }]
+            }
+            _ => vec![],
         }
-        None
     }
 }
 
 impl Role for Learner {
     fn new() -> Self {
         Learner {
-            proposal_number: 0,
-            kv_store: HashMap::new(),
-        }
-    }
-    fn handle_msg(&mut self, msg_type: &MsgType, msg: &str) -> Option<String> {
-        let split_msg: Vec<&str> = msg.split(" ").filter(|&msg| msg != "").collect();
-        match msg_type {
-            MsgType::ACCEPTED => {
-                let proposal_number = split_msg[0].parse::<u8>().unwrap();
-                if proposal_number > self.proposal_number {
-                    self.proposal_number = proposal_number;
-                    let key = split_msg[1].to_string();
-                    return if split_msg.len() == 3 {
-                        let value = split_msg[2].to_string();
-                        self.kv_store.insert(key, value);
-                        let msg = format!(
-                            "{} {} {}",
-                            char::from(MsgType::RESPONSE as u8),
-                            proposal_number,
-                            0,
-                        );
-                        Some(msg)
-                    } else {
-                        let value = self.kv_store.get(&key);
-                        return if value.is_some() {
-                            let msg = format!(
-                                "{} {} {} {}",
-                                char::from(MsgType::RESPONSE as u8),
-                                proposal_number,
-                                1,
-                                value.unwrap(),
-                            );
-                            Some(msg)
-                        } else {
-                            let msg = format!(
-                                "{} {} {}",
-                                char::from(MsgType::RESPONSE as u8),
-                                proposal_number,
-                                2,
-                            );
-                            Some(msg)
-                        };
-                    };
+            state: Box::new(KvStore::default()),
+            membership: Membership::new(vec![0]),
+            sessions: HashMap::new(),
+            votes: BTreeMap::new(),
+            chosen: BTreeMap::new(),
+            next_slot: 0,
+            log: BTreeMap::new(),
+            snapshot_slot: 0,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            session_expiry: SESSION_EXPIRY,
+            catch_up_slot: None,
+            changes: ChangeLog::default(),
+            storage: Box::new(MemStorage::default()),
+        }
+    }
+
+    /// Counts ACCEPTED votes per slot, from the members of that slot only.
+    /// Once a slot is chosen, every chosen command up to the first gap is
+    /// applied in slot order and a RESPONSE is returned for each. A learner
+    /// that falls behind the leader's HEARTBEATs asks its peers for the
+    /// chosen commands, or a snapshot, it is missing.
+    fn handle_msg(&mut self, from: usize, msg: &Message) -> Vec<Message> {
+        match msg {
+            Message::Accepted {
+                slot,
+                proposal_number,
+                command,
+            } => {
+                let (slot, proposal_number) = (*slot, *proposal_number);
+                if slot < self.next_slot || self.chosen.contains_key(&slot) {
+                    return vec![];
                 }
+
+                let votes = self.votes.entry(slot).or_insert_with(|| Votes {
+                    proposal_number,
+                    command: command.clone(),
+                    voters: HashSet::new(),
+                });
+                if proposal_number > votes.proposal_number {
+                    votes.proposal_number = proposal_number;
+                    votes.command = command.clone();
+                    votes.voters.clear();
+                }
+                if proposal_number == votes.proposal_number {
+                    votes.voters.insert(from);
+                }
+                self.apply_chosen()
             }
-            _ => {}
+            Message::Chosen {
+                slot,
+                proposal_number,
+                command,
+            } => {
+                if *slot < self.next_slot {
+                    return vec![];
+                }
+                self.votes.remove(slot);
+                self.chosen
+                    .insert(*slot, (*proposal_number, command.clone()));
+                self.apply_chosen()
+            }
+            Message::Snapshot {
+                slot,
+                state,
+                sessions,
+                configs,
+            } => self.install_snapshot(*slot, state, sessions, configs),
+            Message::CatchUp { slot } => {
+                let mut msgs = vec![];
+                let mut slot = *slot;
+                if slot >= self.next_slot {
+                    return msgs;
+                }
+                if slot < self.snapshot_slot {
+                    msgs.push(Message::Snapshot {
+                        slot: self.next_slot,
+                        state: self.state.snapshot(),
+                        sessions: self.sorted_sessions(),
+                        configs: self.membership.configs(),
+                    });
+                    slot = self.next_slot;
+                }
+                msgs.extend(
+                    self.log
+                        .range(slot..)
+                        .map(|(&slot, (proposal_number, command))| Message::Chosen {
+                            slot,
+                            proposal_number: *proposal_number,
+                            command: command.clone(),
+                        }),
+                );
+                msgs
+            }
+            // Still behind the slot the leader announced one HEARTBEAT ago
+            Message::Heartbeat { slot, .. } => {
+                let behind = self
+                    .catch_up_slot
+                    .is_some_and(|leader_slot| self.next_slot < leader_slot);
+                if behind {
+                    self.catch_up_slot = None;
+                    return vec![Message::CatchUp {
+                        slot: self.next_slot,
+                    }];
+                }
+                self.catch_up_slot = Some(*slot);
+                vec![]
+            }
+            _ => vec![],
         }
-        None
     }
 }
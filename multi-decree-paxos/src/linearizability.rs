@@ -0,0 +1,331 @@
+use crate::{Command, Outcome};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+/* Linearizability:
+ * A history is linearizable if every operation can be given a point between
+ * its invocation and its response such that the operations, run one at a
+ * time in that order, give the responses the clients saw. Linearizability is
+ * local, so each key is checked on its own as a register.
+ *
+ * The search follows Wing & Gong: repeatedly pick an operation that no
+ * pending response forces to go later, apply it to the register and recurse,
+ * backtracking when a response does not match. As in Knossos and Porcupine,
+ * every (linearized set, register value) pair is searched at most once.
+ *
+ * An operation the client gave up on may have taken effect at any point after
+ * its invocation, or not at all, and so may one answered as expired: the
+ * retry that got that answer could have come after the first try was
+ * applied. Both count as unanswered. A get without a response changes
+ * nothing, so it is left out. Scans read many keys at once and are not checked, and
+ * neither are lock commands, whose names are not registers.
+ */
+
+/// A client request as the client saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operation {
+    /// The client that sent it. A client that gives up on a request carries
+    /// on as a new process, so each process has at most one open request.
+    pub process: usize,
+    pub command: Command,
+    pub invoked: Duration,
+    /// When the client got a response, and what it was. `None` if the client
+    /// gave up, in which case the command may or may not have taken effect.
+    pub completed: Option<(Duration, Outcome)>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let request = self.command.to_client_request().unwrap_or_default();
+        write!(f, "process {} {}", self.process, request.replace('\n', " "))?;
+        match &self.completed {
+            Some((at, outcome)) => write!(f, " [{:?}, {:?}] {}", self.invoked, at, outcome),
+            None => write!(f, " [{:?}, ...] no response", self.invoked),
+        }
+    }
+}
+
+/// A key whose history is not linearizable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonLinearizable {
+    pub key: String,
+    /// A small sub-history of the key that is not linearizable, as it stood
+    /// when the last response in it arrived. The rest is linearizable on its
+    /// own, and the last response could follow one of them, just not in any
+    /// order that respects when the operations ran.
+    pub history: Vec<Operation>,
+}
+
+impl fmt::Display for NonLinearizable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "history of key {} is not linearizable:", self.key)?;
+        for op in &self.history {
+            write!(f, "\n  {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NonLinearizable {}
+
+/// Checks `history` against a key-value map where every key is a register
+/// that starts out empty.
+pub fn check_linearizable(history: &[Operation]) -> Result<(), NonLinearizable> {
+    let mut keys: BTreeMap<&str, Vec<Operation>> = BTreeMap::new();
+    for op in history {
+        if let Some(key) = register_key(&op.command) {
+            keys.entry(key).or_default().push(unanswered_if_expired(op));
+        }
+    }
+    for (key, ops) in keys {
+        if !linearizable(&ops) {
+            return Err(NonLinearizable {
+                key: key.to_string(),
+                history: minimize(&ops),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The key of a single key-value command, the only commands checked.
+fn register_key(command: &Command) -> Option<&str> {
+    match command {
+        Command::Get { key }
+        | Command::Put { key, .. }
+        | Command::Delete { key }
+        | Command::Cas { key, .. }
+        | Command::Append { key, .. }
+        | Command::Incr { key, .. } => Some(key),
+        Command::Session { command, .. } => register_key(command),
+        _ => None,
+    }
+}
+
+/// `op`, without its response if that only says the request expired.
+fn unanswered_if_expired(op: &Operation) -> Operation {
+    Operation {
+        completed: op
+            .completed
+            .clone()
+            .filter(|(_, outcome)| *outcome != Outcome::Expired),
+        ..op.clone()
+    }
+}
+
+fn linearizable(ops: &[Operation]) -> bool {
+    // A get without a response changes nothing
+    let ops: Vec<&Operation> = ops
+        .iter()
+        .filter(|op| op.completed.is_some() || !matches!(op.command, Command::Get { .. }))
+        .collect();
+    Search::new(&ops).run()
+}
+
+/// Whether `last` gives its response right after one of `ops`, or on the
+/// empty register.
+fn could_follow(ops: &[Operation], last: &Operation) -> bool {
+    let Some((_, outcome)) = &last.completed else {
+        return true;
+    };
+    std::iter::once(Some(None))
+        .chain(ops.iter().map(left_behind))
+        .flatten()
+        .any(|value| model(&last.command, value).1 == *outcome)
+}
+
+/// The value `op` is known to have left in the register, if any.
+fn left_behind(op: &Operation) -> Option<Option<String>> {
+    let command = match &op.command {
+        Command::Session { command, .. } => command,
+        command => command,
+    };
+    let outcome = op.completed.as_ref().map(|(_, outcome)| outcome);
+    match (command, outcome) {
+        (Command::Put { value, .. }, _) => Some(Some(value.clone())),
+        (_, Some(Outcome::Value(value) | Outcome::Updated(value))) => Some(Some(value.clone())),
+        (_, Some(Outcome::CasFailed(value))) => Some(value.clone()),
+        (_, Some(Outcome::NotFound | Outcome::Deleted)) => Some(None),
+        _ => None,
+    }
+}
+
+/// The history as it stood at `at`: later operations had not been invoked
+/// and later responses had not arrived.
+fn as_of(ops: &[Operation], at: Duration) -> Vec<Operation> {
+    ops.iter()
+        .filter(|op| op.invoked <= at)
+        .map(|op| Operation {
+            completed: op.completed.clone().filter(|(done, _)| *done <= at),
+            ..op.clone()
+        })
+        .collect()
+}
+
+/// Finds the first response that cannot be explained, then leaves out
+/// operations one at a time for as long as the rest still explains
+/// everything but that response, and that response could still follow one
+/// of the operations left.
+fn minimize(ops: &[Operation]) -> Vec<Operation> {
+    let mut responses: Vec<Duration> = ops
+        .iter()
+        .filter_map(|op| op.completed.as_ref().map(|(at, _)| *at))
+        .collect();
+    responses.sort();
+    let Some((mut rest, last)) = responses.into_iter().find_map(|at| {
+        let ops = as_of(ops, at);
+        if linearizable(&ops) {
+            return None;
+        }
+        // The operation answered at `at` is the one that cannot be explained
+        (0..ops.len()).find_map(|i| {
+            let answered = ops[i]
+                .completed
+                .as_ref()
+                .is_some_and(|(done, _)| *done == at);
+            let mut rest = ops.clone();
+            let last = rest.remove(i);
+            (answered && linearizable(&rest)).then_some((rest, last))
+        })
+    }) else {
+        return ops.to_vec();
+    };
+    loop {
+        let len = rest.len();
+        let mut i = 0;
+        while i < rest.len() {
+            let mut without = rest.clone();
+            without.remove(i);
+            let mut with_last = without.clone();
+            with_last.push(last.clone());
+            if linearizable(&without) && !linearizable(&with_last) && could_follow(&without, &last)
+            {
+                rest = without;
+            } else {
+                i += 1;
+            }
+        }
+        if rest.len() == len {
+            break;
+        }
+    }
+    rest.push(last);
+    rest.sort_by_key(|op| op.invoked);
+    rest
+}
+
+struct Search<'a> {
+    ops: &'a [&'a Operation],
+    linearized: Vec<bool>,
+    /// How many operations with a response are not linearized yet.
+    remaining: usize,
+    seen: HashSet<(Vec<bool>, Option<String>)>,
+}
+
+impl<'a> Search<'a> {
+    fn new(ops: &'a [&'a Operation]) -> Search<'a> {
+        Search {
+            ops,
+            linearized: vec![false; ops.len()],
+            remaining: ops.iter().filter(|op| op.completed.is_some()).count(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        self.search(None)
+    }
+
+    fn search(&mut self, value: Option<String>) -> bool {
+        if self.remaining == 0 {
+            return true;
+        }
+        if !self.seen.insert((self.linearized.clone(), value.clone())) {
+            return false;
+        }
+        // Nothing invoked after the earliest outstanding response can go next
+        let deadline = (0..self.ops.len())
+            .filter(|&i| !self.linearized[i])
+            .filter_map(|i| self.ops[i].completed.as_ref().map(|(at, _)| *at))
+            .min()
+            .unwrap_or(Duration::MAX);
+        for i in 0..self.ops.len() {
+            let op = self.ops[i];
+            if self.linearized[i] || op.invoked > deadline {
+                continue;
+            }
+            let Some(next) = step(op, value.clone()) else {
+                continue;
+            };
+            self.linearized[i] = true;
+            let completed = op.completed.is_some() as usize;
+            self.remaining -= completed;
+            if self.search(next) {
+                return true;
+            }
+            self.remaining += completed;
+            self.linearized[i] = false;
+        }
+        false
+    }
+}
+
+/// Applies `op` to a register holding `value`. Returns the new value, or
+/// `None` if the register would have answered differently.
+fn step(op: &Operation, value: Option<String>) -> Option<Option<String>> {
+    let (next, outcome) = model(&op.command, value);
+    match &op.completed {
+        Some((_, seen)) if *seen != outcome => None,
+        _ => Some(next),
+    }
+}
+
+/// What `command` does to a register holding `value`: the value it leaves
+/// behind and the outcome it returns.
+fn model(command: &Command, value: Option<String>) -> (Option<String>, Outcome) {
+    match command {
+        Command::Get { .. } => {
+            let outcome = value.clone().map_or(Outcome::NotFound, Outcome::Value);
+            (value, outcome)
+        }
+        Command::Put { value, .. } => (Some(value.clone()), Outcome::PutOk),
+        Command::Delete { .. } => {
+            let outcome = value.map_or(Outcome::NotFound, |_| Outcome::Deleted);
+            (None, outcome)
+        }
+        Command::Cas { expected, new, .. } => {
+            if value.as_ref() == Some(expected) {
+                (Some(new.clone()), Outcome::Updated(new.clone()))
+            } else {
+                (value.clone(), Outcome::CasFailed(value))
+            }
+        }
+        Command::Append { value: suffix, .. } => {
+            let appended = value.unwrap_or_default() + suffix;
+            (Some(appended.clone()), Outcome::Updated(appended))
+        }
+        Command::Incr { by, .. } => {
+            let current = match &value {
+                Some(value) => value.parse::<i64>().ok(),
+                None => Some(0),
+            };
+            match current.and_then(|current| current.checked_add(*by)) {
+                Some(sum) => (Some(sum.to_string()), Outcome::Updated(sum.to_string())),
+                None => (value, Outcome::NotAnInteger),
+            }
+        }
+        Command::Session { command, .. } => model(command, value),
+        Command::Noop
+        | Command::Scan { .. }
+        | Command::ScanPrefix { .. }
+        | Command::Batch(_)
+        | Command::Reconfigure { .. }
+        | Command::Acquire { .. }
+        | Command::Release { .. }
+        | Command::Renew { .. }
+        | Command::Tick { .. } => {
+            unreachable!("only single key-value commands are checked")
+        }
+    }
+}
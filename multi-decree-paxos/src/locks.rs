@@ -0,0 +1,221 @@
+use crate::message::{Decoder, Encoder};
+use crate::{Command, DecodeError, Outcome, StateMachine};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/* Lock Service:
+ * A lock table replicates named locks, each held by one owner until it is
+ * released or its time to live runs out. Owners are whatever names clients
+ * give themselves; a client that keeps a lock renews it before it expires.
+ *
+ * Time is replicated too. No replica may read its own clock while applying
+ * a command, or replicas would expire locks at different slots. Instead the
+ * leader proposes a TICK every `LOCK_TICK_INTERVAL` carrying the time since
+ * its last one, and the table's clock is the sum of the TICKs chosen so far.
+ * Locks expire at the TICK that takes the clock to their deadline. Time
+ * that passes between one leader's last TICK and the next leader's first is
+ * lost, so locks outlive their TTL while the cluster is without a leader,
+ * and never expire early. A holder should still stop relying on a lock once
+ * its own clock says the TTL is up.
+ *
+ * Every grant carries a fencing token: the slot the ACQUIRE was chosen at,
+ * or one more than the last token handed out if that is higher, as happens
+ * when a batch grants several locks at one slot. Tokens only grow, so a
+ * resource that remembers the highest token it has seen can turn away a
+ * holder whose lock expired and was granted to someone else since. Renewing
+ * a lock, or acquiring it again as its owner, keeps its token.
+ *
+ * GET <name> reads the owner of a lock, and SCAN and PREFIX list owners by
+ * lock name. Snapshots are
+ *   <now: u64> <next_token: u64> <count: u32> *(<name> <owner> <token: u64> <expires: u64>)
+ * in name order.
+ */
+/// How often the leader of a lock service proposes a TICK.
+pub const LOCK_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lock {
+    owner: String,
+    token: u64,
+    /// The replicated time, in milliseconds, at which the lock expires.
+    expires: u64,
+}
+
+/// Named locks with fencing tokens that expire on replicated time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockTable {
+    /// The replicated time in milliseconds: the sum of every TICK applied.
+    now: u64,
+    /// Every token handed out so far is below this.
+    next_token: u64,
+    locks: BTreeMap<String, Lock>,
+}
+
+impl LockTable {
+    /// The replicated time in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// The owner of the lock `name` and its fencing token, if it is held.
+    pub fn holder(&self, name: &str) -> Option<(&str, u64)> {
+        self.locks
+            .get(name)
+            .map(|lock| (lock.owner.as_str(), lock.token))
+    }
+
+    fn list(&self, wanted: impl Fn(&str) -> bool) -> Outcome {
+        Outcome::Entries(
+            self.locks
+                .iter()
+                .filter(|(name, _)| wanted(name))
+                .map(|(name, lock)| (name.clone(), lock.owner.clone()))
+                .collect(),
+        )
+    }
+}
+
+impl StateMachine for LockTable {
+    fn apply(&mut self, slot: u64, command: &Command) -> Outcome {
+        match command {
+            Command::Tick { millis } => {
+                self.now = self.now.saturating_add(*millis);
+                let now = self.now;
+                self.locks.retain(|_, lock| lock.expires > now);
+                Outcome::Noop
+            }
+            Command::Acquire {
+                name,
+                owner,
+                ttl_ms,
+            } => {
+                let expires = self.now.saturating_add(*ttl_ms);
+                match self.locks.get_mut(name) {
+                    Some(lock) if lock.owner == *owner => {
+                        lock.expires = expires;
+                        Outcome::Locked(lock.token)
+                    }
+                    Some(lock) => Outcome::LockHeld(lock.owner.clone()),
+                    None => {
+                        let token = slot.max(self.next_token);
+                        self.next_token = token + 1;
+                        self.locks.insert(
+                            name.clone(),
+                            Lock {
+                                owner: owner.clone(),
+                                token,
+                                expires,
+                            },
+                        );
+                        Outcome::Locked(token)
+                    }
+                }
+            }
+            Command::Renew {
+                name,
+                owner,
+                ttl_ms,
+            } => match self.locks.get_mut(name) {
+                Some(lock) if lock.owner == *owner => {
+                    lock.expires = self.now.saturating_add(*ttl_ms);
+                    Outcome::Locked(lock.token)
+                }
+                _ => Outcome::NotLockOwner,
+            },
+            Command::Release { name, owner } => match self.locks.get(name) {
+                Some(lock) if lock.owner == *owner => {
+                    self.locks.remove(name);
+                    Outcome::Released
+                }
+                _ => Outcome::NotLockOwner,
+            },
+            command => self.read(command).unwrap_or(Outcome::Noop),
+        }
+    }
+
+    fn read(&self, command: &Command) -> Option<Outcome> {
+        let outcome = match command {
+            Command::Get { key } => match self.locks.get(key) {
+                Some(lock) => Outcome::Value(lock.owner.clone()),
+                None => Outcome::NotFound,
+            },
+            Command::Scan { start, end } => {
+                self.list(|name| name >= start.as_str() && (end.is_empty() || name < end.as_str()))
+            }
+            Command::ScanPrefix { prefix } => self.list(|name| name.starts_with(prefix.as_str())),
+            _ => return None,
+        };
+        Some(outcome)
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.locks
+            .iter()
+            .map(|(name, lock)| {
+                let ttl = lock.expires.saturating_sub(self.now);
+                let holder = format!("{} token:{} ttl:{}ms", lock.owner, lock.token, ttl);
+                (name.clone(), holder)
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.put_u64(self.now);
+        encoder.put_u64(self.next_token);
+        encoder.put_u32(self.locks.len() as u32);
+        for (name, lock) in &self.locks {
+            encoder.put_str(name);
+            encoder.put_str(&lock.owner);
+            encoder.put_u64(lock.token);
+            encoder.put_u64(lock.expires);
+        }
+        encoder.into_inner()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DecodeError> {
+        let mut decoder = Decoder::new(snapshot);
+        let now = decoder.get_u64()?;
+        let next_token = decoder.get_u64()?;
+        let count = decoder.get_u32()?;
+        let mut locks = BTreeMap::new();
+        for _ in 0..count {
+            let name = decoder.get_str()?;
+            let lock = Lock {
+                owner: decoder.get_str()?,
+                token: decoder.get_u64()?,
+                expires: decoder.get_u64()?,
+            };
+            locks.insert(name, lock);
+        }
+        decoder.finish()?;
+        *self = LockTable {
+            now,
+            next_token,
+            locks,
+        };
+        Ok(())
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(LOCK_TICK_INTERVAL)
+    }
+
+    /// A TICK releases the locks it expires.
+    fn changed_keys(&self, command: &Command) -> Vec<String> {
+        match command {
+            Command::Tick { millis } => {
+                let now = self.now.saturating_add(*millis);
+                self.locks
+                    .iter()
+                    .filter(|(_, lock)| lock.expires <= now)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            }
+            Command::Acquire { name, .. }
+            | Command::Release { name, .. }
+            | Command::Renew { name, .. } => vec![name.clone()],
+            _ => vec![],
+        }
+    }
+}
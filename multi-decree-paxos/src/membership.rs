@@ -0,0 +1,111 @@
+use crate::RECONFIG_ALPHA;
+use std::collections::{BTreeMap, HashSet};
+
+/* Reconfiguration:
+ * The replicas whose votes count can change at runtime. A RECONFIGURE command
+ * chosen at slot s replaces the members from slot s + RECONFIG_ALPHA onwards,
+ * so every replica switches at the same slot. A learner that has applied every
+ * slot below n knows the members of every slot below n + RECONFIG_ALPHA, and a
+ * leader never proposes a slot that far ahead of its learner.
+ *
+ * Phase 1 covers every slot from the leader's first slot on, so its PROMISEs
+ * must come from a quorum of every configuration in effect at those slots,
+ * including any a recovered RECONFIGURE will bring in. A leader that learns of
+ * a configuration it holds no quorum of runs phase 1 again.
+ */
+
+/// The replicas whose votes count at each slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Membership {
+    /// The members from each slot on, until the next entry.
+    configs: BTreeMap<u64, Vec<usize>>,
+}
+
+impl Membership {
+    /// The members from slot 0 on.
+    pub fn new(members: Vec<usize>) -> Membership {
+        Membership {
+            configs: BTreeMap::from([(0, members)]),
+        }
+    }
+
+    /// Sets the members from slot 0 on, keeping any later configurations.
+    pub fn set_initial(&mut self, members: Vec<usize>) {
+        self.configs.insert(0, members);
+    }
+
+    /// Restores the configurations listed by `configs`.
+    pub fn from_configs(configs: Vec<(u64, Vec<usize>)>) -> Membership {
+        Membership {
+            configs: configs.into_iter().collect(),
+        }
+    }
+
+    /// The members of every configuration, keyed by the slot it starts at.
+    pub fn configs(&self) -> Vec<(u64, Vec<usize>)> {
+        self.configs
+            .iter()
+            .map(|(&slot, members)| (slot, members.clone()))
+            .collect()
+    }
+
+    /// The replicas whose votes count at `slot`.
+    pub fn members(&self, slot: u64) -> &[usize] {
+        self.configs
+            .range(..=slot)
+            .next_back()
+            .or_else(|| self.configs.iter().next())
+            .map_or(&[], |(_, members)| members)
+    }
+
+    /// The members of the last known configuration.
+    pub fn latest(&self) -> &[usize] {
+        self.members(u64::MAX)
+    }
+
+    /// Whether `id` is a member at `slot` or later.
+    pub fn is_member_from(&self, slot: u64, id: usize) -> bool {
+        self.members(slot).contains(&id)
+            || self
+                .configs
+                .range(slot + 1..)
+                .any(|(_, members)| members.contains(&id))
+    }
+
+    /// How many members at `slot` may fail without losing the quorum.
+    pub fn f(&self, slot: u64) -> u8 {
+        (self.members(slot).len() / 2) as u8
+    }
+
+    /// Whether `voters` include a majority of the members at `slot`.
+    pub fn is_quorum(&self, slot: u64, voters: &HashSet<usize>) -> bool {
+        let members = self.members(slot);
+        let votes = members.iter().filter(|id| voters.contains(id)).count();
+        votes > members.len() / 2
+    }
+
+    /// Whether `voters` include a majority of every configuration in effect
+    /// at `slot` or later.
+    pub fn is_quorum_from(&self, slot: u64, voters: &HashSet<usize>) -> bool {
+        self.is_quorum(slot, voters)
+            && self
+                .configs
+                .range(slot + 1..)
+                .all(|(&start, _)| self.is_quorum(start, voters))
+    }
+
+    /// Records that `members` was chosen at `slot`, returning the slot they
+    /// take over from.
+    pub fn reconfigure(&mut self, slot: u64, members: Vec<usize>) -> u64 {
+        let start = slot + RECONFIG_ALPHA;
+        self.configs.insert(start, members);
+        start
+    }
+
+    /// Drops the configurations that no slot from `slot` on uses any more.
+    pub fn forget_before(&mut self, slot: u64) {
+        if let Some((&current, _)) = self.configs.range(..=slot).next_back() {
+            self.configs = self.configs.split_off(&current);
+        }
+    }
+}
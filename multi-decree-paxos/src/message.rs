@@ -0,0 +1,1337 @@
+use std::collections::HashSet;
+use std::fmt;
+
+/* Wire Format:
+ * Every message is sent as a frame
+ *   <version: u8> <msg_type: u8> <body_len: u32> <body>
+ * All integers are big-endian. Strings are encoded as <len: u32> <utf-8 bytes>
+ * and lists as <count: u32> followed by the items, so keys and values may
+ * contain any character, including spaces and newlines. A SNAPSHOT's <state>
+ * is <len: u32> followed by bytes only the state machine reads.
+ *
+ * Proposal numbers are ballots, encoded as <round: u64> <node_id: u32>.
+ * Every body starts with the log slot (u64) the message belongs to; each slot
+ * is an independent Paxos instance. PREPARE and PROMISE cover every slot from
+ * <slot> onwards, so a leader runs phase 1 once for all future slots. Slots
+ * below a PROMISE's <log_start> were compacted away by that acceptor.
+ *
+ * Bodies:
+ * PREPARE    <slot> <proposal_number>
+ * PROMISE    <slot> <proposal_number> <log_start: u64> <count: u32> *(<slot> <proposal_number> <command>)
+ * ACCEPT     <slot> <proposal_number> <command>
+ * ACCEPTED   <slot> <proposal_number> <command>
+ * UNACCEPTED <slot> <proposal_number> <promised_proposal_number>
+ * RESPONSE   <slot> <proposal_number> <command> <outcome>
+ * NACK       <slot> <proposal_number> <promised_proposal_number>
+ * HEARTBEAT  <slot> <proposal_number>
+ * CATCH_UP   <slot>
+ * CHOSEN     <slot> <proposal_number> <command>
+ * SNAPSHOT   <slot> <state: bytes> <count: u32> *(<client: u64> <seq: u64> <outcome> <last_slot: u64>)
+ *            <count: u32> *(<first_slot: u64> <members>)
+ * CONFIRM    <slot> <proposal_number>
+ * CONFIRMED  <slot> <proposal_number>
+ *
+ * CONFIRM and CONFIRMED do not belong to a slot: a leader that wants to serve
+ * reads numbers its rounds of CONFIRM in <slot>, and acceptors echo it.
+ *
+ * A <command> is a tag (u8) followed by its fields:
+ *   0 GET <key>          3 DELETE <key>              6 INCR <key> <by: i64>
+ *   1 PUT <key> <value>  4 CAS <key> <expected> <new> 7 SCAN <start> <end>
+ *   2 NOOP               5 APPEND <key> <value>      8 SCAN_PREFIX <prefix>
+ *   9 SESSION <client: u64> <seq: u64> <command>
+ *  10 BATCH <count: u32> *(<command>)
+ *  11 RECONFIGURE <members>
+ *  12 ACQUIRE <name> <owner> <ttl_ms: u64>   14 RENEW <name> <owner> <ttl_ms: u64>
+ *  13 RELEASE <name> <owner>                 15 TICK <millis: u64>
+ * where <members> is <count: u32> *(<node_id: u32>). A SESSION holds neither
+ * a SESSION nor a BATCH, and a BATCH holds no BATCH.
+ * An <outcome> is likewise a tag followed by its fields:
+ *   0 PUT_OK             4 DELETED                   8 NOT_AN_INTEGER
+ *   1 VALUE <value>      5 UPDATED <value>           9 ENTRIES <count: u32> *(<key> <value>)
+ *   2 NOT_FOUND          6 CAS_FAILED_NOT_FOUND
+ *   3 NOOP               7 CAS_FAILED <value>       10 EXPIRED
+ *  11 BATCH <count: u32> *(<outcome>), of no BATCH   12 RECONFIGURED <first_slot: u64>
+ *  13 NOT_LEADER <leader: u32>, u32::MAX when no leader is known
+ *  14 LOCKED <token: u64>   15 LOCK_HELD <owner>   16 RELEASED   17 NOT_LOCK_OWNER
+ */
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 6;
+/// The longest frame, header included, a node takes from a peer, and the
+/// longest request it takes from a client. A snapshot travels in a single
+/// frame, so this also bounds the state a replica can send a lagging peer.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(non_camel_case_types)]
+pub enum MsgType {
+    PREPARE = 0,
+    PROMISE,
+    ACCEPT,
+    ACCEPTED,
+    UNACCEPTED,
+    RESPONSE,
+    NACK,
+    HEARTBEAT,
+    CATCH_UP,
+    CHOSEN,
+    SNAPSHOT,
+    CONFIRM,
+    CONFIRMED,
+}
+
+impl TryFrom<u8> for MsgType {
+    type Error = DecodeError;
+
+    fn try_from(item: u8) -> Result<Self, Self::Error> {
+        match item {
+            0 => Ok(MsgType::PREPARE),
+            1 => Ok(MsgType::PROMISE),
+            2 => Ok(MsgType::ACCEPT),
+            3 => Ok(MsgType::ACCEPTED),
+            4 => Ok(MsgType::UNACCEPTED),
+            5 => Ok(MsgType::RESPONSE),
+            6 => Ok(MsgType::NACK),
+            7 => Ok(MsgType::HEARTBEAT),
+            8 => Ok(MsgType::CATCH_UP),
+            9 => Ok(MsgType::CHOSEN),
+            10 => Ok(MsgType::SNAPSHOT),
+            11 => Ok(MsgType::CONFIRM),
+            12 => Ok(MsgType::CONFIRMED),
+            _ => Err(DecodeError::UnknownType(item)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer does not yet hold a whole frame.
+    Incomplete,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    InvalidUtf8,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete => write!(f, "incomplete frame"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            DecodeError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            DecodeError::InvalidUtf8 => write!(f, "string field is not valid utf-8"),
+            DecodeError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A client operation carried through a Paxos round.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+    },
+    /// Fills a log slot that would otherwise be left as a gap.
+    Noop,
+    Delete {
+        key: String,
+    },
+    /// Sets `key` to `new` only if it currently holds `expected`.
+    Cas {
+        key: String,
+        expected: String,
+        new: String,
+    },
+    /// Appends `value` to the current value of `key`, or sets it if absent.
+    Append {
+        key: String,
+        value: String,
+    },
+    /// Adds `by` to the integer held by `key`, which counts as 0 if absent.
+    Incr {
+        key: String,
+        by: i64,
+    },
+    /// Every key from `start` up to but excluding `end`. An empty `end` has
+    /// no upper bound.
+    Scan {
+        start: String,
+        end: String,
+    },
+    /// Every key that starts with `prefix`.
+    ScanPrefix {
+        prefix: String,
+    },
+    /// `command` as request `seq` of `client`. Each client numbers its
+    /// requests in order and sends the next one only once the last one has
+    /// been answered, so a retried request is answered again rather than
+    /// applied twice, as long as it comes within `SESSION_EXPIRY` slots.
+    Session {
+        client: u64,
+        seq: u64,
+        command: Box<Command>,
+    },
+    /// Client commands the leader sent together, applied in order at one
+    /// slot.
+    Batch(Vec<Command>),
+    /// Makes `members` the replicas whose votes count, starting
+    /// `RECONFIG_ALPHA` slots after the one this is chosen at.
+    Reconfigure {
+        members: Vec<usize>,
+    },
+    /// Takes the lock `name` for `owner` for `ttl_ms` of replicated time,
+    /// unless another owner holds it.
+    Acquire {
+        name: String,
+        owner: String,
+        ttl_ms: u64,
+    },
+    Release {
+        name: String,
+        owner: String,
+    },
+    /// Extends the lock `owner` holds on `name` to `ttl_ms` from now.
+    Renew {
+        name: String,
+        owner: String,
+        ttl_ms: u64,
+    },
+    /// Advances the replicated clock by `millis`. Only the leader proposes
+    /// it; clients cannot send it.
+    Tick {
+        millis: u64,
+    },
+}
+
+impl Command {
+    /// Parses a newline separated client request such as `put\nkey\nvalue`.
+    ///
+    /// ```text
+    /// get <key>              delete <key>
+    /// put <key> <value>      append <key> <value>
+    /// cas <key> <expected> <new>
+    /// incr <key> [<by>]      (by defaults to 1)
+    /// scan <start> [<end>]   prefix <prefix>
+    /// session <client> <seq> <request>
+    /// reconfigure <id>...    (the new members, at least one, no repeats)
+    /// acquire <name> <owner> <ttl_ms>
+    /// renew <name> <owner> <ttl_ms>
+    /// release <name> <owner>
+    /// ```
+    pub fn from_client_request(msg: &str) -> Option<Command> {
+        let msg: Vec<&str> = msg.split('\n').collect();
+        let action = msg.first()?.to_lowercase();
+        let arg = |i: usize| msg.get(i).map(|s| s.to_string());
+        let command = match action.as_str() {
+            "session" => {
+                let command = Command::from_client_request(&msg.get(3..)?.join("\n"))?;
+                if let Command::Session { .. } = command {
+                    return None;
+                }
+                Command::Session {
+                    client: msg[1].parse().ok()?,
+                    seq: msg[2].parse().ok()?,
+                    command: Box::new(command),
+                }
+            }
+            "get" => Command::Get { key: arg(1)? },
+            "put" => Command::Put {
+                key: arg(1)?,
+                value: arg(2)?,
+            },
+            "delete" => Command::Delete { key: arg(1)? },
+            "cas" => Command::Cas {
+                key: arg(1)?,
+                expected: arg(2)?,
+                new: arg(3)?,
+            },
+            "append" => Command::Append {
+                key: arg(1)?,
+                value: arg(2)?,
+            },
+            "incr" => Command::Incr {
+                key: arg(1)?,
+                by: match msg.get(2) {
+                    Some(by) => by.parse().ok()?,
+                    None => 1,
+                },
+            },
+            "scan" => Command::Scan {
+                start: arg(1)?,
+                end: arg(2).unwrap_or_default(),
+            },
+            "prefix" => Command::ScanPrefix { prefix: arg(1)? },
+            "reconfigure" => {
+                let members: Vec<usize> = msg[1..]
+                    .iter()
+                    .map(|id| id.parse().ok())
+                    .collect::<Option<_>>()?;
+                let unique: HashSet<&usize> = members.iter().collect();
+                if members.is_empty() || unique.len() != members.len() {
+                    return None;
+                }
+                Command::Reconfigure { members }
+            }
+            "acquire" => Command::Acquire {
+                name: arg(1)?,
+                owner: arg(2)?,
+                ttl_ms: msg.get(3)?.parse().ok()?,
+            },
+            "release" => Command::Release {
+                name: arg(1)?,
+                owner: arg(2)?,
+            },
+            "renew" => Command::Renew {
+                name: arg(1)?,
+                owner: arg(2)?,
+                ttl_ms: msg.get(3)?.parse().ok()?,
+            },
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    /// The inverse of `from_client_request`. `None` for a no-op or a tick,
+    /// which clients cannot send.
+    pub fn to_client_request(&self) -> Option<String> {
+        let args = match self {
+            Command::Get { key } => vec!["get", key],
+            Command::Put { key, value } => vec!["put", key, value],
+            Command::Noop | Command::Batch(_) | Command::Tick { .. } => return None,
+            Command::Delete { key } => vec!["delete", key],
+            Command::Cas { key, expected, new } => vec!["cas", key, expected, new],
+            Command::Append { key, value } => vec!["append", key, value],
+            Command::Incr { key, by } => return Some(format!("incr\n{}\n{}", key, by)),
+            Command::Scan { start, end } => vec!["scan", start, end],
+            Command::ScanPrefix { prefix } => vec!["prefix", prefix],
+            Command::Session {
+                client,
+                seq,
+                command,
+            } => {
+                let request = command.to_client_request()?;
+                return Some(format!("session\n{}\n{}\n{}", client, seq, request));
+            }
+            Command::Reconfigure { members } => {
+                let ids: Vec<String> = members.iter().map(|id| id.to_string()).collect();
+                return Some(format!("reconfigure\n{}", ids.join("\n")));
+            }
+            Command::Acquire {
+                name,
+                owner,
+                ttl_ms,
+            } => return Some(format!("acquire\n{}\n{}\n{}", name, owner, ttl_ms)),
+            Command::Release { name, owner } => vec!["release", name, owner],
+            Command::Renew {
+                name,
+                owner,
+                ttl_ms,
+            } => return Some(format!("renew\n{}\n{}\n{}", name, owner, ttl_ms)),
+        };
+        Some(args.join("\n"))
+    }
+
+    /// The single key, or lock name, the command reads or writes. Scans
+    /// cover many keys.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Get { key }
+            | Command::Put { key, .. }
+            | Command::Delete { key }
+            | Command::Cas { key, .. }
+            | Command::Append { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Acquire { name: key, .. }
+            | Command::Release { name: key, .. }
+            | Command::Renew { name: key, .. } => Some(key),
+            Command::Noop | Command::Scan { .. } | Command::ScanPrefix { .. } => None,
+            Command::Session { command, .. } => command.key(),
+            Command::Batch(_) | Command::Reconfigure { .. } | Command::Tick { .. } => None,
+        }
+    }
+
+    /// The members the command reconfigures the cluster to, if it does.
+    pub fn new_members(&self) -> Option<&[usize]> {
+        match self {
+            Command::Reconfigure { members } => Some(members),
+            Command::Session { command, .. } => command.new_members(),
+            Command::Batch(commands) => commands.iter().rev().find_map(Command::new_members),
+            _ => None,
+        }
+    }
+
+    /// Whether the command leaves the state machine as it is, so a leader
+    /// may answer it without a log entry.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Command::Get { .. } | Command::Scan { .. } | Command::ScanPrefix { .. } => true,
+            Command::Session { command, .. } => command.is_read_only(),
+            _ => false,
+        }
+    }
+}
+
+/// The result of applying a command, sent back in a RESPONSE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    PutOk,
+    Value(String),
+    NotFound,
+    Noop,
+    Deleted,
+    /// The value a CAS, APPEND or INCR left behind.
+    Updated(String),
+    /// A CAS found `key` holding something other than what was expected;
+    /// `None` if it was absent.
+    CasFailed(Option<String>),
+    /// INCR found a value that is not an integer, or the sum overflowed.
+    NotAnInteger,
+    /// The keys and values a scan found, in key order.
+    Entries(Vec<(String, String)>),
+    /// The client has sent a later request since, so this one was dropped.
+    Expired,
+    /// The outcome of each command of a batch, in order.
+    Batch(Vec<Outcome>),
+    /// The new members take over from the given slot.
+    Reconfigured(u64),
+    /// The replica does not lead the cluster, so the request was not
+    /// proposed. Names the leader it follows, if any.
+    NotLeader(Option<usize>),
+    /// The owner holds the lock, under the given fencing token.
+    Locked(u64),
+    /// Another owner, named, holds the lock.
+    LockHeld(String),
+    Released,
+    /// A RELEASE or RENEW from someone other than the lock's owner, or of a
+    /// lock that expired.
+    NotLockOwner,
+}
+
+impl Outcome {
+    /// Parses a response as a client receives it, e.g. `put successful!`.
+    pub fn from_response(response: &str) -> Option<Outcome> {
+        let outcome = match response {
+            "put successful!" => Outcome::PutOk,
+            "get failed!" => Outcome::NotFound,
+            "no-op" => Outcome::Noop,
+            "delete successful!" => Outcome::Deleted,
+            "cas failed! not found" => Outcome::CasFailed(None),
+            "incr failed! not an integer" => Outcome::NotAnInteger,
+            "request expired!" => Outcome::Expired,
+            "not leader!" => Outcome::NotLeader(None),
+            "release successful!" => Outcome::Released,
+            "lock failed! not owner" => Outcome::NotLockOwner,
+            _ => {
+                if let Some(value) = response.strip_prefix("get successful! value:") {
+                    Outcome::Value(value.to_string())
+                } else if let Some(value) = response.strip_prefix("update successful! value:") {
+                    Outcome::Updated(value.to_string())
+                } else if let Some(value) = response.strip_prefix("cas failed! value:") {
+                    Outcome::CasFailed(Some(value.to_string()))
+                } else if let Some(slot) =
+                    response.strip_prefix("reconfigure successful! from slot:")
+                {
+                    Outcome::Reconfigured(slot.parse().ok()?)
+                } else if let Some(leader) = response.strip_prefix("not leader! leader:") {
+                    Outcome::NotLeader(Some(leader.parse().ok()?))
+                } else if let Some(token) = response.strip_prefix("lock successful! token:") {
+                    Outcome::Locked(token.parse().ok()?)
+                } else if let Some(owner) = response.strip_prefix("lock failed! owner:") {
+                    Outcome::LockHeld(owner.to_string())
+                } else {
+                    let mut lines = response.split('\n');
+                    let count: usize = lines
+                        .next()?
+                        .strip_prefix("scan successful! count:")?
+                        .parse()
+                        .ok()?;
+                    let mut entries = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        entries.push((lines.next()?.to_string(), lines.next()?.to_string()));
+                    }
+                    if lines.next().is_some() {
+                        return None;
+                    }
+                    Outcome::Entries(entries)
+                }
+            }
+        };
+        Some(outcome)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::PutOk => write!(f, "put successful!"),
+            Outcome::Value(value) => write!(f, "get successful! value:{}", value),
+            Outcome::NotFound => write!(f, "get failed!"),
+            Outcome::Noop => write!(f, "no-op"),
+            Outcome::Deleted => write!(f, "delete successful!"),
+            Outcome::Updated(value) => write!(f, "update successful! value:{}", value),
+            Outcome::CasFailed(Some(value)) => write!(f, "cas failed! value:{}", value),
+            Outcome::CasFailed(None) => write!(f, "cas failed! not found"),
+            Outcome::NotAnInteger => write!(f, "incr failed! not an integer"),
+            Outcome::Expired => write!(f, "request expired!"),
+            Outcome::Reconfigured(slot) => write!(f, "reconfigure successful! from slot:{}", slot),
+            Outcome::NotLeader(Some(leader)) => write!(f, "not leader! leader:{}", leader),
+            Outcome::NotLeader(None) => write!(f, "not leader!"),
+            Outcome::Locked(token) => write!(f, "lock successful! token:{}", token),
+            Outcome::LockHeld(owner) => write!(f, "lock failed! owner:{}", owner),
+            Outcome::Released => write!(f, "release successful!"),
+            Outcome::NotLockOwner => write!(f, "lock failed! not owner"),
+            Outcome::Batch(outcomes) => {
+                write!(f, "batch count:{}", outcomes.len())?;
+                for outcome in outcomes {
+                    write!(f, "\n{}", outcome)?;
+                }
+                Ok(())
+            }
+            // One line per key and per value, like a request
+            Outcome::Entries(entries) => {
+                write!(f, "scan successful! count:{}", entries.len())?;
+                for (key, value) in entries {
+                    write!(f, "\n{}\n{}", key, value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A globally unique proposal number. Ballots are ordered by round and then by
+/// the id of the node that proposed them, so two proposers never pick the same
+/// ballot and a round counter never runs out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ballot {
+    pub round: u64,
+    pub node_id: u32,
+}
+
+impl Ballot {
+    pub fn new(round: u64, node_id: u32) -> Self {
+        Ballot { round, node_id }
+    }
+
+    /// The smallest ballot owned by `node_id` that is higher than `self`.
+    pub fn next(&self, node_id: u32) -> Self {
+        Ballot {
+            round: self.round + 1,
+            node_id,
+        }
+    }
+}
+
+impl fmt::Display for Ballot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.round, self.node_id)
+    }
+}
+
+/// A command accepted at `slot` under `proposal_number`, as reported in a
+/// PROMISE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PValue {
+    pub slot: u64,
+    pub proposal_number: Ballot,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Prepare {
+        slot: u64,
+        proposal_number: Ballot,
+    },
+    /// Promises every slot from `slot` onwards, listing the values already
+    /// accepted there.
+    Promise {
+        slot: u64,
+        proposal_number: Ballot,
+        log_start: u64,
+        accepted: Vec<PValue>,
+    },
+    Accept {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+    },
+    Accepted {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+    },
+    Unaccepted {
+        slot: u64,
+        proposal_number: Ballot,
+        promised_proposal_number: Ballot,
+    },
+    /// Produced by a learner once `command` has been applied at `slot`.
+    Response {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+        outcome: Outcome,
+    },
+    Nack {
+        slot: u64,
+        proposal_number: Ballot,
+        promised_proposal_number: Ballot,
+    },
+    /// Sent periodically by the leader; `slot` is the next slot it will assign.
+    Heartbeat {
+        slot: u64,
+        proposal_number: Ballot,
+    },
+    /// Asks peers for every chosen command from `slot` onwards.
+    CatchUp {
+        slot: u64,
+    },
+    /// A command already known to be chosen at `slot`.
+    Chosen {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+    },
+    /// A snapshot of the state machine after applying every slot below
+    /// `slot`, every client's last request number with its outcome and the
+    /// slot its session was last used at, and the members from each slot on.
+    Snapshot {
+        slot: u64,
+        state: Vec<u8>,
+        sessions: Vec<(u64, u64, Outcome, u64)>,
+        configs: Vec<(u64, Vec<usize>)>,
+    },
+    /// Asks acceptors whether `proposal_number` still leads; `slot` numbers
+    /// the leader's rounds.
+    Confirm {
+        slot: u64,
+        proposal_number: Ballot,
+    },
+    /// An acceptor has not promised anything above `proposal_number`.
+    Confirmed {
+        slot: u64,
+        proposal_number: Ballot,
+    },
+}
+
+impl Message {
+    pub fn msg_type(&self) -> MsgType {
+        match self {
+            Message::Prepare { .. } => MsgType::PREPARE,
+            Message::Promise { .. } => MsgType::PROMISE,
+            Message::Accept { .. } => MsgType::ACCEPT,
+            Message::Accepted { .. } => MsgType::ACCEPTED,
+            Message::Unaccepted { .. } => MsgType::UNACCEPTED,
+            Message::Response { .. } => MsgType::RESPONSE,
+            Message::Nack { .. } => MsgType::NACK,
+            Message::Heartbeat { .. } => MsgType::HEARTBEAT,
+            Message::CatchUp { .. } => MsgType::CATCH_UP,
+            Message::Chosen { .. } => MsgType::CHOSEN,
+            Message::Snapshot { .. } => MsgType::SNAPSHOT,
+            Message::Confirm { .. } => MsgType::CONFIRM,
+            Message::Confirmed { .. } => MsgType::CONFIRMED,
+        }
+    }
+
+    pub fn slot(&self) -> u64 {
+        match self {
+            Message::Prepare { slot, .. }
+            | Message::Promise { slot, .. }
+            | Message::Accept { slot, .. }
+            | Message::Accepted { slot, .. }
+            | Message::Unaccepted { slot, .. }
+            | Message::Response { slot, .. }
+            | Message::Nack { slot, .. }
+            | Message::Heartbeat { slot, .. }
+            | Message::CatchUp { slot }
+            | Message::Chosen { slot, .. }
+            | Message::Snapshot { slot, .. }
+            | Message::Confirm { slot, .. }
+            | Message::Confirmed { slot, .. } => *slot,
+        }
+    }
+
+    /// The ballot the message was sent under, if it belongs to a Paxos round.
+    pub fn proposal_number(&self) -> Option<Ballot> {
+        match self {
+            Message::Prepare {
+                proposal_number, ..
+            }
+            | Message::Promise {
+                proposal_number, ..
+            }
+            | Message::Accept {
+                proposal_number, ..
+            }
+            | Message::Accepted {
+                proposal_number, ..
+            }
+            | Message::Unaccepted {
+                proposal_number, ..
+            }
+            | Message::Response {
+                proposal_number, ..
+            }
+            | Message::Nack {
+                proposal_number, ..
+            }
+            | Message::Heartbeat {
+                proposal_number, ..
+            }
+            | Message::Chosen {
+                proposal_number, ..
+            }
+            | Message::Confirm {
+                proposal_number, ..
+            }
+            | Message::Confirmed {
+                proposal_number, ..
+            } => Some(*proposal_number),
+            Message::CatchUp { .. } | Message::Snapshot { .. } => None,
+        }
+    }
+
+    /// Encodes the message as a single length-prefixed frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Encoder::default();
+        body.put_u64(self.slot());
+        if let Some(proposal_number) = self.proposal_number() {
+            body.put_ballot(proposal_number);
+        }
+        match self {
+            Message::Prepare { .. }
+            | Message::Heartbeat { .. }
+            | Message::CatchUp { .. }
+            | Message::Confirm { .. }
+            | Message::Confirmed { .. } => {}
+            Message::Promise {
+                log_start,
+                accepted,
+                ..
+            } => {
+                body.put_u64(*log_start);
+                body.put_u32(accepted.len() as u32);
+                for pvalue in accepted {
+                    body.put_u64(pvalue.slot);
+                    body.put_ballot(pvalue.proposal_number);
+                    body.put_command(&pvalue.command);
+                }
+            }
+            Message::Accept { command, .. }
+            | Message::Accepted { command, .. }
+            | Message::Chosen { command, .. } => {
+                body.put_command(command);
+            }
+            Message::Unaccepted {
+                promised_proposal_number,
+                ..
+            }
+            | Message::Nack {
+                promised_proposal_number,
+                ..
+            } => {
+                body.put_ballot(*promised_proposal_number);
+            }
+            Message::Response {
+                command, outcome, ..
+            } => {
+                body.put_command(command);
+                body.put_outcome(outcome);
+            }
+            Message::Snapshot {
+                state,
+                sessions,
+                configs,
+                ..
+            } => {
+                body.put_bytes(state);
+                body.put_sessions(sessions);
+                body.put_configs(configs);
+            }
+        }
+
+        let body = body.into_inner();
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.push(PROTOCOL_VERSION);
+        frame.push(self.msg_type() as u8);
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    /// The length of the first frame in `buf` as its header gives it, or
+    /// None if the header has not all arrived.
+    pub fn frame_len(buf: &[u8]) -> Option<usize> {
+        let body_len = u32::from_be_bytes(buf.get(2..HEADER_LEN)?.try_into().unwrap());
+        Some(HEADER_LEN + body_len as usize)
+    }
+
+    /// Decodes the first frame in `buf`, returning the message and the number
+    /// of bytes it occupied. Returns `DecodeError::Incomplete` if `buf` does
+    /// not yet contain a whole frame.
+    pub fn decode(buf: &[u8]) -> Result<(Message, usize), DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::Incomplete);
+        }
+        if buf[0] != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(buf[0]));
+        }
+        let msg_type = MsgType::try_from(buf[1])?;
+        let body_len = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]) as usize;
+        let frame_len = HEADER_LEN + body_len;
+        if frame_len > MAX_FRAME_LEN {
+            return Err(DecodeError::Malformed("frame too long"));
+        }
+        if buf.len() < frame_len {
+            return Err(DecodeError::Incomplete);
+        }
+
+        let mut body = Decoder::new(&buf[HEADER_LEN..frame_len]);
+        let slot = body.get_u64()?;
+        let msg = match msg_type {
+            MsgType::PREPARE => Message::Prepare {
+                slot,
+                proposal_number: body.get_ballot()?,
+            },
+            MsgType::PROMISE => {
+                let proposal_number = body.get_ballot()?;
+                let log_start = body.get_u64()?;
+                let count = body.get_u32()?;
+                let mut accepted = Vec::new();
+                for _ in 0..count {
+                    accepted.push(PValue {
+                        slot: body.get_u64()?,
+                        proposal_number: body.get_ballot()?,
+                        command: body.get_command()?,
+                    });
+                }
+                Message::Promise {
+                    slot,
+                    proposal_number,
+                    log_start,
+                    accepted,
+                }
+            }
+            MsgType::ACCEPT => Message::Accept {
+                slot,
+                proposal_number: body.get_ballot()?,
+                command: body.get_command()?,
+            },
+            MsgType::ACCEPTED => Message::Accepted {
+                slot,
+                proposal_number: body.get_ballot()?,
+                command: body.get_command()?,
+            },
+            MsgType::UNACCEPTED => Message::Unaccepted {
+                slot,
+                proposal_number: body.get_ballot()?,
+                promised_proposal_number: body.get_ballot()?,
+            },
+            MsgType::RESPONSE => Message::Response {
+                slot,
+                proposal_number: body.get_ballot()?,
+                command: body.get_command()?,
+                outcome: body.get_outcome()?,
+            },
+            MsgType::NACK => Message::Nack {
+                slot,
+                proposal_number: body.get_ballot()?,
+                promised_proposal_number: body.get_ballot()?,
+            },
+            MsgType::HEARTBEAT => Message::Heartbeat {
+                slot,
+                proposal_number: body.get_ballot()?,
+            },
+            MsgType::CATCH_UP => Message::CatchUp { slot },
+            MsgType::CHOSEN => Message::Chosen {
+                slot,
+                proposal_number: body.get_ballot()?,
+                command: body.get_command()?,
+            },
+            MsgType::SNAPSHOT => Message::Snapshot {
+                slot,
+                state: body.get_bytes()?,
+                sessions: body.get_sessions()?,
+                configs: body.get_configs()?,
+            },
+            MsgType::CONFIRM => Message::Confirm {
+                slot,
+                proposal_number: body.get_ballot()?,
+            },
+            MsgType::CONFIRMED => Message::Confirmed {
+                slot,
+                proposal_number: body.get_ballot()?,
+            },
+        };
+        body.finish()?;
+        Ok((msg, frame_len))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(crate) fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub(crate) fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub(crate) fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub(crate) fn put_ballot(&mut self, ballot: Ballot) {
+        self.put_u64(ballot.round);
+        self.put_u32(ballot.node_id);
+    }
+
+    pub(crate) fn put_str(&mut self, s: &str) {
+        self.put_u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    pub(crate) fn put_command(&mut self, command: &Command) {
+        match command {
+            Command::Get { key } => {
+                self.put_u8(0);
+                self.put_str(key);
+            }
+            Command::Put { key, value } => {
+                self.put_u8(1);
+                self.put_str(key);
+                self.put_str(value);
+            }
+            Command::Noop => self.put_u8(2),
+            Command::Delete { key } => {
+                self.put_u8(3);
+                self.put_str(key);
+            }
+            Command::Cas { key, expected, new } => {
+                self.put_u8(4);
+                self.put_str(key);
+                self.put_str(expected);
+                self.put_str(new);
+            }
+            Command::Append { key, value } => {
+                self.put_u8(5);
+                self.put_str(key);
+                self.put_str(value);
+            }
+            Command::Incr { key, by } => {
+                self.put_u8(6);
+                self.put_str(key);
+                self.put_u64(*by as u64);
+            }
+            Command::Scan { start, end } => {
+                self.put_u8(7);
+                self.put_str(start);
+                self.put_str(end);
+            }
+            Command::ScanPrefix { prefix } => {
+                self.put_u8(8);
+                self.put_str(prefix);
+            }
+            Command::Session {
+                client,
+                seq,
+                command,
+            } => {
+                self.put_u8(9);
+                self.put_u64(*client);
+                self.put_u64(*seq);
+                self.put_command(command);
+            }
+            Command::Batch(commands) => {
+                self.put_u8(10);
+                self.put_u32(commands.len() as u32);
+                for command in commands {
+                    self.put_command(command);
+                }
+            }
+            Command::Reconfigure { members } => {
+                self.put_u8(11);
+                self.put_members(members);
+            }
+            Command::Acquire {
+                name,
+                owner,
+                ttl_ms,
+            } => {
+                self.put_u8(12);
+                self.put_str(name);
+                self.put_str(owner);
+                self.put_u64(*ttl_ms);
+            }
+            Command::Release { name, owner } => {
+                self.put_u8(13);
+                self.put_str(name);
+                self.put_str(owner);
+            }
+            Command::Renew {
+                name,
+                owner,
+                ttl_ms,
+            } => {
+                self.put_u8(14);
+                self.put_str(name);
+                self.put_str(owner);
+                self.put_u64(*ttl_ms);
+            }
+            Command::Tick { millis } => {
+                self.put_u8(15);
+                self.put_u64(*millis);
+            }
+        }
+    }
+
+    pub(crate) fn put_outcome(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::PutOk => self.put_u8(0),
+            Outcome::Value(value) => {
+                self.put_u8(1);
+                self.put_str(value);
+            }
+            Outcome::NotFound => self.put_u8(2),
+            Outcome::Noop => self.put_u8(3),
+            Outcome::Deleted => self.put_u8(4),
+            Outcome::Updated(value) => {
+                self.put_u8(5);
+                self.put_str(value);
+            }
+            Outcome::CasFailed(None) => self.put_u8(6),
+            Outcome::CasFailed(Some(value)) => {
+                self.put_u8(7);
+                self.put_str(value);
+            }
+            Outcome::NotAnInteger => self.put_u8(8),
+            Outcome::Entries(entries) => {
+                self.put_u8(9);
+                self.put_u32(entries.len() as u32);
+                for (key, value) in entries {
+                    self.put_str(key);
+                    self.put_str(value);
+                }
+            }
+            Outcome::Expired => self.put_u8(10),
+            Outcome::Batch(outcomes) => {
+                self.put_u8(11);
+                self.put_u32(outcomes.len() as u32);
+                for outcome in outcomes {
+                    self.put_outcome(outcome);
+                }
+            }
+            Outcome::Reconfigured(slot) => {
+                self.put_u8(12);
+                self.put_u64(*slot);
+            }
+            Outcome::NotLeader(leader) => {
+                self.put_u8(13);
+                self.put_u32(leader.map_or(u32::MAX, |leader| leader as u32));
+            }
+            Outcome::Locked(token) => {
+                self.put_u8(14);
+                self.put_u64(*token);
+            }
+            Outcome::LockHeld(owner) => {
+                self.put_u8(15);
+                self.put_str(owner);
+            }
+            Outcome::Released => self.put_u8(16),
+            Outcome::NotLockOwner => self.put_u8(17),
+        }
+    }
+
+    pub(crate) fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn put_kv_store(&mut self, kv_store: &[(String, String)]) {
+        self.put_u32(kv_store.len() as u32);
+        for (key, value) in kv_store {
+            self.put_str(key);
+            self.put_str(value);
+        }
+    }
+
+    pub(crate) fn put_sessions(&mut self, sessions: &[(u64, u64, Outcome, u64)]) {
+        self.put_u32(sessions.len() as u32);
+        for (client, seq, outcome, last_slot) in sessions {
+            self.put_u64(*client);
+            self.put_u64(*seq);
+            self.put_outcome(outcome);
+            self.put_u64(*last_slot);
+        }
+    }
+
+    pub(crate) fn put_members(&mut self, members: &[usize]) {
+        self.put_u32(members.len() as u32);
+        for id in members {
+            self.put_u32(*id as u32);
+        }
+    }
+
+    pub(crate) fn put_configs(&mut self, configs: &[(u64, Vec<usize>)]) {
+        self.put_u32(configs.len() as u32);
+        for (slot, members) in configs {
+            self.put_u64(*slot);
+            self.put_members(members);
+        }
+    }
+}
+
+pub(crate) struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() - self.pos < n {
+            return Err(DecodeError::Malformed("body too short"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub(crate) fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek_u8(&self) -> Result<u8, DecodeError> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(DecodeError::Malformed("body too short"))
+    }
+
+    pub(crate) fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.take(8)?;
+        let mut v = [0; 8];
+        v.copy_from_slice(bytes);
+        Ok(u64::from_be_bytes(v))
+    }
+
+    pub(crate) fn get_ballot(&mut self) -> Result<Ballot, DecodeError> {
+        Ok(Ballot {
+            round: self.get_u64()?,
+            node_id: self.get_u32()?,
+        })
+    }
+
+    pub(crate) fn get_str(&mut self) -> Result<String, DecodeError> {
+        let len = self.get_u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub(crate) fn get_command(&mut self) -> Result<Command, DecodeError> {
+        match self.get_u8()? {
+            0 => Ok(Command::Get {
+                key: self.get_str()?,
+            }),
+            1 => Ok(Command::Put {
+                key: self.get_str()?,
+                value: self.get_str()?,
+            }),
+            2 => Ok(Command::Noop),
+            3 => Ok(Command::Delete {
+                key: self.get_str()?,
+            }),
+            4 => Ok(Command::Cas {
+                key: self.get_str()?,
+                expected: self.get_str()?,
+                new: self.get_str()?,
+            }),
+            5 => Ok(Command::Append {
+                key: self.get_str()?,
+                value: self.get_str()?,
+            }),
+            6 => Ok(Command::Incr {
+                key: self.get_str()?,
+                by: self.get_u64()? as i64,
+            }),
+            7 => Ok(Command::Scan {
+                start: self.get_str()?,
+                end: self.get_str()?,
+            }),
+            8 => Ok(Command::ScanPrefix {
+                prefix: self.get_str()?,
+            }),
+            9 => {
+                let client = self.get_u64()?;
+                let seq = self.get_u64()?;
+                // Checked before decoding it, so nesting cannot exhaust the stack
+                match self.peek_u8()? {
+                    9 => Err(DecodeError::Malformed("nested session")),
+                    10 => Err(DecodeError::Malformed("batch in a session")),
+                    _ => Ok(Command::Session {
+                        client,
+                        seq,
+                        command: Box::new(self.get_command()?),
+                    }),
+                }
+            }
+            10 => {
+                let count = self.get_u32()?;
+                let mut commands = vec![];
+                for _ in 0..count {
+                    if self.peek_u8()? == 10 {
+                        return Err(DecodeError::Malformed("nested batch"));
+                    }
+                    commands.push(self.get_command()?);
+                }
+                Ok(Command::Batch(commands))
+            }
+            11 => Ok(Command::Reconfigure {
+                members: self.get_members()?,
+            }),
+            12 => Ok(Command::Acquire {
+                name: self.get_str()?,
+                owner: self.get_str()?,
+                ttl_ms: self.get_u64()?,
+            }),
+            13 => Ok(Command::Release {
+                name: self.get_str()?,
+                owner: self.get_str()?,
+            }),
+            14 => Ok(Command::Renew {
+                name: self.get_str()?,
+                owner: self.get_str()?,
+                ttl_ms: self.get_u64()?,
+            }),
+            15 => Ok(Command::Tick {
+                millis: self.get_u64()?,
+            }),
+            _ => Err(DecodeError::Malformed("unknown command")),
+        }
+    }
+
+    pub(crate) fn get_outcome(&mut self) -> Result<Outcome, DecodeError> {
+        match self.get_u8()? {
+            0 => Ok(Outcome::PutOk),
+            1 => Ok(Outcome::Value(self.get_str()?)),
+            2 => Ok(Outcome::NotFound),
+            3 => Ok(Outcome::Noop),
+            4 => Ok(Outcome::Deleted),
+            5 => Ok(Outcome::Updated(self.get_str()?)),
+            6 => Ok(Outcome::CasFailed(None)),
+            7 => Ok(Outcome::CasFailed(Some(self.get_str()?))),
+            8 => Ok(Outcome::NotAnInteger),
+            9 => {
+                let count = self.get_u32()?;
+                let mut entries = vec![];
+                for _ in 0..count {
+                    entries.push((self.get_str()?, self.get_str()?));
+                }
+                Ok(Outcome::Entries(entries))
+            }
+            10 => Ok(Outcome::Expired),
+            11 => {
+                let count = self.get_u32()?;
+                let mut outcomes = vec![];
+                for _ in 0..count {
+                    if self.peek_u8()? == 11 {
+                        return Err(DecodeError::Malformed("nested batch"));
+                    }
+                    outcomes.push(self.get_outcome()?);
+                }
+                Ok(Outcome::Batch(outcomes))
+            }
+            12 => Ok(Outcome::Reconfigured(self.get_u64()?)),
+            13 => {
+                let leader = self.get_u32()?;
+                Ok(Outcome::NotLeader(
+                    (leader != u32::MAX).then_some(leader as usize),
+                ))
+            }
+            14 => Ok(Outcome::Locked(self.get_u64()?)),
+            15 => Ok(Outcome::LockHeld(self.get_str()?)),
+            16 => Ok(Outcome::Released),
+            17 => Ok(Outcome::NotLockOwner),
+            _ => Err(DecodeError::Malformed("unknown response type")),
+        }
+    }
+
+    pub(crate) fn get_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.get_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub(crate) fn get_kv_store(&mut self) -> Result<Vec<(String, String)>, DecodeError> {
+        let count = self.get_u32()?;
+        let mut kv_store = Vec::new();
+        for _ in 0..count {
+            kv_store.push((self.get_str()?, self.get_str()?));
+        }
+        Ok(kv_store)
+    }
+
+    pub(crate) fn get_sessions(&mut self) -> Result<Vec<(u64, u64, Outcome, u64)>, DecodeError> {
+        let count = self.get_u32()?;
+        let mut sessions = Vec::new();
+        for _ in 0..count {
+            sessions.push((
+                self.get_u64()?,
+                self.get_u64()?,
+                self.get_outcome()?,
+                self.get_u64()?,
+            ));
+        }
+        Ok(sessions)
+    }
+
+    pub(crate) fn get_members(&mut self) -> Result<Vec<usize>, DecodeError> {
+        let count = self.get_u32()?;
+        let mut members = Vec::new();
+        for _ in 0..count {
+            members.push(self.get_u32()? as usize);
+        }
+        Ok(members)
+    }
+
+    pub(crate) fn get_configs(&mut self) -> Result<Vec<(u64, Vec<usize>)>, DecodeError> {
+        let count = self.get_u32()?;
+        let mut configs = Vec::new();
+        for _ in 0..count {
+            configs.push((self.get_u64()?, self.get_members()?));
+        }
+        Ok(configs)
+    }
+
+    /// Fails if any bytes of the body were left unread.
+    pub(crate) fn finish(&self) -> Result<(), DecodeError> {
+        if self.pos == self.buf.len() {
+            Ok(())
+        } else {
+            Err(DecodeError::Malformed("trailing bytes"))
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/* Metrics:
+ * A replica counts what its proposer goes through and times every stage of
+ * a round, by the replica's clock: virtual time in the simulation, wall
+ * clock time on a node. `render` writes them in the Prometheus text
+ * exposition format, which a node serves over HTTP on its metrics address:
+ *
+ *   # HELP paxos_nacks_total NACKs and UNACCEPTEDs for this replica's ballot.
+ *   # TYPE paxos_nacks_total counter
+ *   paxos_nacks_total 3
+ *
+ * Latencies are histograms in seconds, with cumulative `_bucket` counts for
+ * each bound in LATENCY_BUCKETS and a `+Inf` one, then `_sum` and `_count`.
+ */
+/// The upper bounds, in seconds, of the latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// How many observations fell in each of the `LATENCY_BUCKETS`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations per bucket, the last for those above every bound.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+/// What a replica has been through since it started.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Phase 1 rounds started.
+    pub elections: u64,
+    /// Phase 1 rounds that made this replica the leader.
+    pub elections_won: u64,
+    /// NACKs and UNACCEPTEDs for this replica's ballot.
+    pub nacks: u64,
+    /// PREPAREs and ACCEPTs sent again after a quorum did not answer in time.
+    pub retries: u64,
+    /// Client requests answered, whatever the outcome.
+    pub requests: u64,
+    /// Client requests answered with the leader to send them to instead.
+    pub redirects: u64,
+    /// From PREPARE to a quorum of PROMISEs.
+    pub election_latency: Histogram,
+    /// From ACCEPT to a quorum of ACCEPTEDs.
+    pub accept_latency: Histogram,
+    /// From a client request arriving to its answer.
+    pub request_latency: Histogram,
+}
+
+impl Metrics {
+    /// Writes every metric to `out` in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        counter(
+            out,
+            "paxos_elections_total",
+            "Phase 1 rounds started.",
+            self.elections,
+        );
+        counter(
+            out,
+            "paxos_elections_won_total",
+            "Phase 1 rounds that made this replica the leader.",
+            self.elections_won,
+        );
+        counter(
+            out,
+            "paxos_nacks_total",
+            "NACKs and UNACCEPTEDs for this replica's ballot.",
+            self.nacks,
+        );
+        counter(
+            out,
+            "paxos_retries_total",
+            "PREPAREs and ACCEPTs sent again after a timeout.",
+            self.retries,
+        );
+        counter(
+            out,
+            "paxos_requests_total",
+            "Client requests answered.",
+            self.requests,
+        );
+        counter(
+            out,
+            "paxos_redirects_total",
+            "Client requests answered with a redirect to the leader.",
+            self.redirects,
+        );
+        self.election_latency.render(
+            out,
+            "paxos_election_seconds",
+            "From PREPARE to a quorum of PROMISEs.",
+        );
+        self.accept_latency.render(
+            out,
+            "paxos_accept_seconds",
+            "From ACCEPT to a quorum of ACCEPTEDs.",
+        );
+        self.request_latency.render(
+            out,
+            "paxos_request_seconds",
+            "From a client request arriving to its answer.",
+        );
+    }
+}
+
+/// Writes a metric that only goes up to `out`.
+pub(crate) fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Writes a metric that can go up and down to `out`.
+pub(crate) fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
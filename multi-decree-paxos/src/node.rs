@@ -0,0 +1,910 @@
+use crate::admin::{dump_kv, dump_log};
+use crate::metrics::counter;
+use crate::{Action, AdminCommand, Config, KvStore, Message, PeerKey, Replica, StateMachine};
+use crate::{Watch, WatchEvent};
+use crate::{MAX_FRAME_LEN, TAG_LEN};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/* Peer Connections:
+ * Every node dials every other node and only ever writes to the connections
+ * it dialed. Right after connecting, the dialer sends its <node_id: u32>, so
+ * the accepting side knows which replica the frames that follow come from.
+ * With a secret in the config, each frame is followed by a tag that proves
+ * it came from that replica (see auth.rs).
+ * Messages for a peer that is down are dropped; the dialer retries with
+ * exponential backoff. A peer that reads too slowly, with more than
+ * `MAX_PEER_BACKLOG` bytes queued for it, is treated as down, and what it
+ * missed is resent by the usual retries and catch-up once it is back.
+ *
+ * All sockets are non-blocking and driven by a single mio event loop. Bytes
+ * read from a connection are buffered until they form whole frames, so a
+ * frame may arrive split across reads or several frames in one read. No
+ * more than one longest frame is buffered at a time: a connection whose next
+ * frame would be longer than `MAX_FRAME_LEN` is dropped as soon as its header
+ * arrives, and one whose frame fails its tag before any later frame is read.
+ */
+
+/* Client Connections:
+ * A client either writes one request and shuts down its side of the
+ * connection, or opens with a zero byte and then sends <len u32><request>
+ * frames, one at a time. A one-shot request is answered with the plain
+ * response before the node closes the connection. A framed request is
+ * answered with a <len u32><response> frame, and the connection stays open
+ * for the next. Responses are queued and written as the socket takes them,
+ * like frames to peers. Requests longer than `MAX_FRAME_LEN`, or framed
+ * requests sent before the last was answered that add up to more, drop the
+ * connection. A framed WATCH turns the connection into a stream of frames
+ * instead (see watch.rs).
+ */
+
+/* Metrics Endpoint:
+ * A node given a metrics address answers HTTP requests there for /metrics
+ * with its replica's metrics in the Prometheus text format, and any other
+ * path with 404. Either way it closes the connection after answering.
+ * An admin address takes a line of the protocol in admin.rs instead.
+ */
+/// The first delay before redialing a peer.
+pub const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+/// The longest delay between two attempts to dial a peer.
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// The longest the event loop sleeps before advancing the replica's clock.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(10);
+const HANDSHAKE_LEN: usize = 4;
+
+const PEER_LISTENER: Token = Token(0);
+const CLIENT_LISTENER: Token = Token(1);
+const METRICS_LISTENER: Token = Token(2);
+const ADMIN_LISTENER: Token = Token(3);
+/// Outgoing peer connections use the tokens from here on, in `peers` order.
+const FIRST_PEER: usize = 4;
+/// The longest request a metrics or admin connection may send.
+const MAX_QUERY_LEN: usize = 8192;
+/// How many bytes of changes a node queues for a watching client that is
+/// not reading them before it waits for the client to catch up.
+const MAX_WATCH_BACKLOG: usize = 1 << 20;
+/// How many bytes of frames a node queues for a peer that is not reading
+/// them before it drops the connection. Room for a snapshot and then some.
+const MAX_PEER_BACKLOG: usize = 2 * MAX_FRAME_LEN;
+
+/// An outgoing connection to another node.
+struct Peer {
+    id: usize,
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    /// Whether the non-blocking connect has completed.
+    connected: bool,
+    /// Encoded frames not yet written to `stream`.
+    out: Vec<u8>,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+/// A connection dialed by another node.
+struct Inbound {
+    stream: TcpStream,
+    /// The peer's id, once its handshake has arrived.
+    from: Option<usize>,
+    buf: Vec<u8>,
+}
+
+/// A client connection. Its requests are proposed under the connection's
+/// token.
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// Whether the client sends framed requests, once its first byte came.
+    framed: Option<bool>,
+    /// Whether the client has shut down its side of the connection.
+    closed: bool,
+    /// Whether a request is waiting for its answer.
+    proposed: bool,
+    /// What the client watches, and the first slot whose changes it has not
+    /// been sent yet.
+    watch: Option<(Watch, u64)>,
+    /// Responses not yet written to `stream`.
+    out: Vec<u8>,
+    /// Whether to close the connection once `out` is written.
+    finished: bool,
+}
+
+impl Client {
+    /// Takes the next complete request off the buffer.
+    fn next_request(&mut self) -> Option<String> {
+        let request: Vec<u8> = match self.framed {
+            Some(true) => {
+                let len = u32::from_be_bytes(self.buf.get(..4)?.try_into().unwrap()) as usize;
+                if self.buf.len() < 4 + len {
+                    return None;
+                }
+                self.buf.drain(..4 + len).skip(4).collect()
+            }
+            _ if self.closed => std::mem::take(&mut self.buf),
+            _ => return None,
+        };
+        Some(String::from_utf8_lossy(&request).into_owned())
+    }
+
+    /// Whether a request the client sent, or is still sending, is longer
+    /// than `MAX_FRAME_LEN`.
+    fn too_long(&self) -> bool {
+        if self.framed != Some(true) {
+            return self.buf.len() > MAX_FRAME_LEN;
+        }
+        let mut rest = &self.buf[..];
+        while let Some(len) = rest.get(..4) {
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            if len > MAX_FRAME_LEN {
+                return true;
+            }
+            let Some(next) = rest.get(4 + len..) else {
+                break;
+            };
+            rest = next;
+        }
+        false
+    }
+}
+
+/// A connection to the metrics or admin address.
+struct Query {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// Whether the answer is in `out`; the connection closes once it is
+    /// written.
+    answered: bool,
+    out: Vec<u8>,
+}
+
+/// A replica running as its own process, talking to its peers over TCP.
+pub struct Node {
+    replica: Replica,
+    n: usize,
+    poll: Poll,
+    peers: Vec<Peer>,
+    peer_listener: TcpListener,
+    client_listener: TcpListener,
+    metrics_listener: Option<TcpListener>,
+    admin_listener: Option<TcpListener>,
+    inbound: HashMap<Token, Inbound>,
+    clients: HashMap<Token, Client>,
+    scrapes: HashMap<Token, Query>,
+    admins: HashMap<Token, Query>,
+    next_token: usize,
+    /// Messages this node sent to itself.
+    local: VecDeque<Message>,
+    key: Option<PeerKey>,
+    /// Peer frames dropped because their tag did not match.
+    unauthenticated: u64,
+}
+
+impl Node {
+    /// Binds node `id`'s listeners and recovers its key-value store from the
+    /// data directory named by `config`.
+    pub fn bind(config: &Config, id: usize) -> io::Result<Node> {
+        Node::bind_with(config, id, Box::new(KvStore::default()))
+    }
+
+    /// Like `bind`, but replicating `state` rather than a key-value store.
+    pub fn bind_with(config: &Config, id: usize, state: Box<dyn StateMachine>) -> io::Result<Node> {
+        let me = config.node(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("node {} is not in the config", id),
+            )
+        })?;
+        let n = config.nodes.len();
+        let mut replica = match config.node_dir(id) {
+            Some(dir) => Replica::open_with(id, n, &dir, state)?,
+            None => Replica::with_state_machine(id, n, state),
+        };
+        if let Some(members) = &config.members {
+            replica.set_initial_members(members.clone());
+        }
+        replica.set_timeouts(config.timeouts);
+        replica.set_pipeline(config.pipeline);
+        let poll = Poll::new()?;
+        let mut peer_listener = TcpListener::bind(me.peer_addr)?;
+        poll.registry()
+            .register(&mut peer_listener, PEER_LISTENER, Interest::READABLE)?;
+        let mut client_listener = TcpListener::bind(me.client_addr)?;
+        poll.registry()
+            .register(&mut client_listener, CLIENT_LISTENER, Interest::READABLE)?;
+        let metrics_listener = match me.metrics_addr {
+            Some(addr) => Some(listen(&poll, addr, METRICS_LISTENER)?),
+            None => None,
+        };
+        let admin_listener = match me.admin_addr {
+            Some(addr) => Some(listen(&poll, addr, ADMIN_LISTENER)?),
+            None => None,
+        };
+
+        let now = Instant::now();
+        let peers: Vec<Peer> = config
+            .nodes
+            .iter()
+            .filter(|node| node.id != id)
+            .map(|node| Peer {
+                id: node.id,
+                addr: node.peer_addr,
+                stream: None,
+                connected: false,
+                out: Vec::new(),
+                backoff: RECONNECT_BACKOFF_MIN,
+                next_attempt: now,
+            })
+            .collect();
+        let next_token = FIRST_PEER + peers.len();
+        Ok(Node {
+            replica,
+            n,
+            poll,
+            peers,
+            peer_listener,
+            client_listener,
+            metrics_listener,
+            admin_listener,
+            inbound: HashMap::new(),
+            clients: HashMap::new(),
+            scrapes: HashMap::new(),
+            admins: HashMap::new(),
+            next_token,
+            local: VecDeque::new(),
+            key: config
+                .secret
+                .as_ref()
+                .map(|secret| PeerKey::new(secret.as_bytes())),
+            unauthenticated: 0,
+        })
+    }
+
+    pub fn replica(&self) -> &Replica {
+        &self.replica
+    }
+
+    /// Serves peers and clients forever.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            self.poll(&mut events, TICK_INTERVAL)?;
+        }
+    }
+
+    /// Waits up to `timeout` for socket events, handles them and advances
+    /// the replica's clock.
+    pub fn poll(&mut self, events: &mut Events, timeout: Duration) -> io::Result<()> {
+        self.connect_peers(Instant::now());
+        match self.poll.poll(events, Some(timeout)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut msgs = vec![];
+        for event in events.iter() {
+            match event.token() {
+                PEER_LISTENER => self.accept_peers()?,
+                CLIENT_LISTENER => self.accept_clients()?,
+                METRICS_LISTENER => self.accept_queries(METRICS_LISTENER)?,
+                ADMIN_LISTENER => self.accept_queries(ADMIN_LISTENER)?,
+                token if token.0 < FIRST_PEER + self.peers.len() => {
+                    self.peer_ready(token.0 - FIRST_PEER)
+                }
+                token if self.inbound.contains_key(&token) => self.read_inbound(token, &mut msgs),
+                token if self.scrapes.contains_key(&token) => self.read_scrape(token),
+                token if self.admins.contains_key(&token) => self.read_admin(token),
+                token => self.read_client(token),
+            }
+        }
+
+        let mut actions = self.replica.tick(Instant::now());
+        for (from, msg) in msgs {
+            actions.extend(self.replica.handle_msg(from, &msg));
+        }
+        self.dispatch(actions);
+        while let Some(msg) = self.local.pop_front() {
+            let actions = self.replica.handle_msg(self.replica.id(), &msg);
+            self.dispatch(actions);
+        }
+        self.serve_clients();
+        self.serve_watchers();
+        self.flush_peers();
+        self.flush_clients();
+        self.flush_queries();
+        Ok(())
+    }
+
+    fn connect_peers(&mut self, now: Instant) {
+        let id = self.replica.id() as u32;
+        for (i, peer) in self.peers.iter_mut().enumerate() {
+            if peer.stream.is_some() || now < peer.next_attempt {
+                continue;
+            }
+            let stream = TcpStream::connect(peer.addr).and_then(|mut stream| {
+                self.poll.registry().register(
+                    &mut stream,
+                    Token(FIRST_PEER + i),
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                Ok(stream)
+            });
+            match stream {
+                Ok(stream) => {
+                    peer.stream = Some(stream);
+                    peer.out = id.to_be_bytes().to_vec();
+                }
+                Err(_) => peer.retry_later(now),
+            }
+        }
+    }
+
+    /// Completes a pending connect, or notices the peer hung up.
+    fn peer_ready(&mut self, i: usize) {
+        let peer = &mut self.peers[i];
+        let Some(stream) = &mut peer.stream else {
+            return;
+        };
+        if !peer.connected {
+            if !matches!(stream.take_error(), Ok(None)) {
+                peer.disconnect(&self.poll, Instant::now());
+                return;
+            }
+            match stream.peer_addr() {
+                Ok(_) => {
+                    info!(peer = peer.id, addr = %peer.addr, "connected to node");
+                    let _ = stream.set_nodelay(true);
+                    peer.connected = true;
+                    peer.backoff = RECONNECT_BACKOFF_MIN;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return,
+                Err(_) => {
+                    peer.disconnect(&self.poll, Instant::now());
+                    return;
+                }
+            }
+        }
+        // Peers never write on connections we dialed, so this means EOF
+        let mut discard = vec![];
+        if !read_available(stream, &mut discard, usize::MAX) {
+            warn!(peer = peer.id, addr = %peer.addr, "lost connection to node");
+            peer.disconnect(&self.poll, Instant::now());
+        }
+    }
+
+    fn accept_peers(&mut self) -> io::Result<()> {
+        loop {
+            match self.peer_listener.accept() {
+                Ok((mut stream, _)) => {
+                    let token = self.register(&mut stream, Interest::READABLE)?;
+                    self.inbound.insert(
+                        token,
+                        Inbound {
+                            stream,
+                            from: None,
+                            buf: Vec::new(),
+                        },
+                    );
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn accept_clients(&mut self) -> io::Result<()> {
+        loop {
+            match self.client_listener.accept() {
+                Ok((mut stream, _)) => {
+                    let token =
+                        self.register(&mut stream, Interest::READABLE | Interest::WRITABLE)?;
+                    self.clients.insert(
+                        token,
+                        Client {
+                            stream,
+                            buf: Vec::new(),
+                            framed: None,
+                            closed: false,
+                            proposed: false,
+                            watch: None,
+                            out: Vec::new(),
+                            finished: false,
+                        },
+                    );
+                    // Data may have arrived before the stream was registered
+                    self.read_client(token);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Accepts connections to the metrics or the admin address.
+    fn accept_queries(&mut self, listener: Token) -> io::Result<()> {
+        loop {
+            let accepted = match listener {
+                METRICS_LISTENER => self.metrics_listener.as_ref(),
+                _ => self.admin_listener.as_ref(),
+            };
+            let Some(accepted) = accepted else {
+                return Ok(());
+            };
+            match accepted.accept() {
+                Ok((mut stream, _)) => {
+                    let token =
+                        self.register(&mut stream, Interest::READABLE | Interest::WRITABLE)?;
+                    let query = Query {
+                        stream,
+                        buf: Vec::new(),
+                        answered: false,
+                        out: Vec::new(),
+                    };
+                    if listener == METRICS_LISTENER {
+                        self.scrapes.insert(token, query);
+                        self.read_scrape(token);
+                    } else {
+                        self.admins.insert(token, query);
+                        self.read_admin(token);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answers a metrics connection once its request head has arrived.
+    fn read_scrape(&mut self, token: Token) {
+        let Some(scrape) = self
+            .scrapes
+            .get_mut(&token)
+            .filter(|scrape| !scrape.answered)
+        else {
+            return;
+        };
+        let open = read_available(&mut scrape.stream, &mut scrape.buf, MAX_QUERY_LEN);
+        let complete = scrape.buf.windows(4).any(|end| end == b"\r\n\r\n");
+        if open && !complete && scrape.buf.len() < MAX_QUERY_LEN {
+            return;
+        }
+        let head = String::from_utf8_lossy(&scrape.buf);
+        let path = head.split_whitespace().nth(1);
+        let (status, body) = if path == Some("/metrics") {
+            let mut body = self.replica.render_metrics();
+            counter(
+                &mut body,
+                "paxos_unauthenticated_frames_total",
+                "Peer frames dropped because their tag did not match.",
+                self.unauthenticated,
+            );
+            ("200 OK", body)
+        } else {
+            ("404 Not Found", String::new())
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let scrape = self.scrapes.get_mut(&token).unwrap();
+        scrape.out = response.into_bytes();
+        scrape.answered = true;
+    }
+
+    /// Runs an admin command once its line has arrived, and answers it.
+    fn read_admin(&mut self, token: Token) {
+        let Some(query) = self.admins.get_mut(&token).filter(|query| !query.answered) else {
+            return;
+        };
+        let open = read_available(&mut query.stream, &mut query.buf, MAX_QUERY_LEN);
+        if open && !query.buf.contains(&b'\n') && query.buf.len() < MAX_QUERY_LEN {
+            return;
+        }
+        let request = String::from_utf8_lossy(&query.buf).into_owned();
+        let line = request.lines().next().unwrap_or_default();
+        let response = match AdminCommand::parse(line) {
+            Some(command) => self.admin(command),
+            None => {
+                "unknown command! try status, snapshot, step-down, log [from] or kv\n".to_string()
+            }
+        };
+        let query = self.admins.get_mut(&token).unwrap();
+        query.out = response.into_bytes();
+        query.answered = true;
+    }
+
+    /// Runs an admin command and returns its answer.
+    pub fn admin(&mut self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Status => {
+                let mut status = self.replica.status();
+                status.peers = self
+                    .peers
+                    .iter()
+                    .map(|peer| (peer.id, peer.connected))
+                    .collect();
+                status.to_string()
+            }
+            AdminCommand::Snapshot => match self.replica.snapshot() {
+                Ok(slot) => format!("snapshot taken! up to slot:{}\n", slot),
+                Err(e) => format!("snapshot failed! {}\n", e),
+            },
+            AdminCommand::StepDown => {
+                if self.replica.step_down() {
+                    "stepped down!\n".to_string()
+                } else {
+                    "not leader!\n".to_string()
+                }
+            }
+            AdminCommand::Log { from } => dump_log(&self.replica, from),
+            AdminCommand::Kv => dump_kv(&self.replica),
+        }
+    }
+
+    fn register(&mut self, stream: &mut TcpStream, interest: Interest) -> io::Result<Token> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.registry().register(stream, token, interest)?;
+        Ok(token)
+    }
+
+    /// Buffers what a peer sent and decodes every complete frame, dropping
+    /// connections that were closed or sent something undecodable or
+    /// unauthenticated.
+    fn read_inbound(&mut self, token: Token, msgs: &mut Vec<(usize, Message)>) {
+        let Some(inbound) = self.inbound.get_mut(&token) else {
+            return;
+        };
+        let tag_len = if self.key.is_some() { TAG_LEN } else { 0 };
+        // Never more than the handshake and one longest frame at a time
+        let limit = HANDSHAKE_LEN + MAX_FRAME_LEN + tag_len;
+        let mut open = true;
+        let mut eof = false;
+        while open && !eof {
+            eof = !read_available(&mut inbound.stream, &mut inbound.buf, limit);
+            let full = inbound.buf.len() >= limit;
+            if inbound.from.is_none() && inbound.buf.len() >= HANDSHAKE_LEN {
+                let id = u32::from_be_bytes(inbound.buf[..HANDSHAKE_LEN].try_into().unwrap());
+                inbound.buf.drain(..HANDSHAKE_LEN);
+                if id as usize >= self.n {
+                    warn!(peer = id, "rejecting connection from unknown node");
+                    open = false;
+                } else {
+                    inbound.from = Some(id as usize);
+                }
+            }
+            // Frames that arrived before EOF are still delivered
+            if let Some(from) = inbound.from {
+                let mut pos = 0;
+                while open {
+                    let rest = &inbound.buf[pos..];
+                    let len = match Message::frame_len(rest) {
+                        Some(len) if len > MAX_FRAME_LEN => {
+                            warn!(peer = from, "dropping connection: frame too long");
+                            open = false;
+                            break;
+                        }
+                        Some(len) if rest.len() >= len + tag_len => len,
+                        _ => break,
+                    };
+                    let (frame, tag) = (&rest[..len], &rest[len..len + tag_len]);
+                    if let Some(key) = &self.key {
+                        if !key.verify(from, self.replica.id(), frame, tag) {
+                            warn!(peer = from, "dropping connection: bad tag");
+                            self.unauthenticated += 1;
+                            open = false;
+                            break;
+                        }
+                    }
+                    match Message::decode(frame) {
+                        Ok((msg, _)) => msgs.push((from, msg)),
+                        Err(e) => {
+                            warn!(peer = from, "dropping connection: {}", e);
+                            open = false;
+                        }
+                    }
+                    pos += len + tag_len;
+                }
+                inbound.buf.drain(..pos);
+            }
+            // Stopped at the limit rather than at WouldBlock, so there may be more
+            if !full {
+                break;
+            }
+        }
+        if !open || eof {
+            let mut inbound = self.inbound.remove(&token).unwrap();
+            let _ = self.poll.registry().deregister(&mut inbound.stream);
+        }
+    }
+
+    fn read_client(&mut self, token: Token) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+        // The zero byte and a length prefix may come before the longest request
+        let limit = 5 + MAX_FRAME_LEN;
+        if !client.closed {
+            client.closed = !read_available(&mut client.stream, &mut client.buf, limit);
+        }
+        if client.framed.is_none() && !client.buf.is_empty() {
+            let framed = client.buf[0] == 0;
+            if framed {
+                client.buf.remove(0);
+            }
+            client.framed = Some(framed);
+        }
+        if client.buf.len() >= limit || client.too_long() {
+            warn!("dropping client connection: request too long");
+            let mut client = self.clients.remove(&token).unwrap();
+            let _ = self.poll.registry().deregister(&mut client.stream);
+        }
+    }
+
+    fn dispatch(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(to, msg) => {
+                    if to == self.replica.id() {
+                        self.local.push_back(msg);
+                    } else if let Some(peer) = self.peers.iter_mut().find(|peer| peer.id == to) {
+                        peer.send(&msg, self.replica.id(), self.key.as_ref());
+                    }
+                }
+                Action::Broadcast(msg) => {
+                    for peer in &mut self.peers {
+                        peer.send(&msg, self.replica.id(), self.key.as_ref());
+                    }
+                    self.local.push_back(msg);
+                }
+                Action::Respond(id, _, outcome) => {
+                    self.reply(Token(id as usize), outcome.to_string().as_bytes());
+                }
+            }
+        }
+    }
+
+    /// Proposes every complete client request, in arrival order, and drops
+    /// framed connections the client closed between requests. The
+    /// replica's proposer batches and pipelines them.
+    fn serve_clients(&mut self) {
+        let mut idle: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| !client.proposed && !client.finished && client.watch.is_none())
+            .map(|(&token, _)| token)
+            .collect();
+        idle.sort();
+        for token in idle {
+            let client = self.clients.get_mut(&token).unwrap();
+            let Some(request) = client.next_request() else {
+                client.finished |= client.closed;
+                continue;
+            };
+            client.proposed = true;
+            if let Some(watch) = Watch::from_client_request(&request) {
+                self.start_watch(token, watch);
+                continue;
+            }
+            match self.replica.propose(token.0 as u64, &request) {
+                Some(actions) => self.dispatch(actions),
+                None => self.reply(token, b"invalid request!"),
+            }
+        }
+    }
+
+    /// Makes a framed connection stream the changes `watch` asks for, unless
+    /// this node no longer has them all.
+    fn start_watch(&mut self, token: Token, watch: Watch) {
+        let learner = self.replica.learner();
+        let from = watch.from.unwrap_or(learner.next_slot());
+        let event = match learner.changes(from) {
+            Ok(_) => WatchEvent::Started(from),
+            Err(start) => WatchEvent::Compacted(start),
+        };
+        let client = self.clients.get_mut(&token).unwrap();
+        if client.framed != Some(true) {
+            self.reply(token, b"watch failed! framed connections only");
+            return;
+        }
+        if let WatchEvent::Started(_) = event {
+            client.watch = Some((watch, from));
+        }
+        self.reply(token, event.to_string().as_bytes());
+    }
+
+    /// Queues for every watching client the changes it watches that were
+    /// applied since it was last sent any, up to `MAX_WATCH_BACKLOG` bytes
+    /// unwritten, and finishes those that closed their side or fell behind
+    /// the changes this node keeps.
+    fn serve_watchers(&mut self) {
+        let learner = self.replica.learner();
+        let applied = learner.next_slot();
+        for client in self.clients.values_mut() {
+            let Some((watch, from)) = &mut client.watch else {
+                continue;
+            };
+            if client.closed {
+                client.watch = None;
+                client.finished = true;
+                continue;
+            }
+            // A watch may start past what this node has applied
+            if *from >= applied || client.out.len() >= MAX_WATCH_BACKLOG {
+                continue;
+            }
+            let changes = match learner.changes(*from) {
+                Ok(changes) => changes,
+                Err(start) => {
+                    let event = WatchEvent::Compacted(start).to_string();
+                    client.out.extend((event.len() as u32).to_be_bytes());
+                    client.out.extend(event.as_bytes());
+                    client.watch = None;
+                    client.finished = true;
+                    continue;
+                }
+            };
+            let mut next = applied;
+            let mut last = None;
+            for change in changes {
+                // Only whole slots, so a client that watches again from the
+                // slot of the last change it saw misses nothing
+                let new_slot = last.is_some_and(|slot| change.slot > slot);
+                if new_slot && client.out.len() >= MAX_WATCH_BACKLOG {
+                    next = change.slot;
+                    break;
+                }
+                last = Some(change.slot);
+                if !watch.matches(&change.key) {
+                    continue;
+                }
+                let event = WatchEvent::Change(change.clone()).to_string();
+                client.out.extend((event.len() as u32).to_be_bytes());
+                client.out.extend(event.as_bytes());
+            }
+            *from = next;
+        }
+    }
+
+    fn reply(&mut self, token: Token, response: &[u8]) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+        if client.framed == Some(true) {
+            client.out.extend((response.len() as u32).to_be_bytes());
+            client.proposed = false;
+        } else {
+            client.finished = true;
+        }
+        client.out.extend_from_slice(response);
+    }
+
+    fn flush_peers(&mut self) {
+        let now = Instant::now();
+        for peer in &mut self.peers {
+            let Some(stream) = &mut peer.stream else {
+                continue;
+            };
+            if !peer.connected {
+                continue;
+            }
+            if !write_available(stream, &mut peer.out) {
+                warn!(peer = peer.id, addr = %peer.addr, "lost connection to node");
+                peer.disconnect(&self.poll, now);
+            } else if peer.out.len() > MAX_PEER_BACKLOG {
+                // What is lost is resent once the peer asks to catch up
+                warn!(peer = peer.id, addr = %peer.addr, "dropping connection: too far behind");
+                peer.disconnect(&self.poll, now);
+            }
+        }
+    }
+
+    /// Writes what the socket takes of every client's responses, and closes
+    /// the connections that are finished and fully written or broken.
+    fn flush_clients(&mut self) {
+        let poll = &self.poll;
+        self.clients.retain(|_, client| {
+            let open = write_available(&mut client.stream, &mut client.out);
+            if open && !(client.finished && client.out.is_empty()) {
+                return true;
+            }
+            let _ = client.stream.shutdown(Shutdown::Both);
+            let _ = poll.registry().deregister(&mut client.stream);
+            false
+        });
+    }
+
+    /// Writes what the socket takes of every metrics and admin answer, and
+    /// closes the connections whose answer is fully written.
+    fn flush_queries(&mut self) {
+        let poll = &self.poll;
+        for queries in [&mut self.scrapes, &mut self.admins] {
+            queries.retain(|_, query| {
+                if !query.answered {
+                    return true;
+                }
+                if write_available(&mut query.stream, &mut query.out) && !query.out.is_empty() {
+                    return true;
+                }
+                let _ = query.stream.shutdown(Shutdown::Both);
+                let _ = poll.registry().deregister(&mut query.stream);
+                false
+            });
+        }
+    }
+}
+
+impl Peer {
+    /// Queues `msg` from node `from`, tagged if there is a key.
+    fn send(&mut self, msg: &Message, from: usize, key: Option<&PeerKey>) {
+        if self.connected {
+            let mut frame = msg.encode();
+            if let Some(key) = key {
+                key.seal(from, self.id, &mut frame);
+            }
+            self.out.extend(frame);
+        }
+    }
+
+    fn disconnect(&mut self, poll: &Poll, now: Instant) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = poll.registry().deregister(&mut stream);
+        }
+        self.connected = false;
+        self.out.clear();
+        self.retry_later(now);
+    }
+
+    fn retry_later(&mut self, now: Instant) {
+        self.next_attempt = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+fn listen(poll: &Poll, addr: SocketAddr, token: Token) -> io::Result<TcpListener> {
+    let mut listener = TcpListener::bind(addr)?;
+    poll.registry()
+        .register(&mut listener, token, Interest::READABLE)?;
+    Ok(listener)
+}
+
+/// Appends everything readable without blocking to `buf`, but stops once it
+/// holds `limit` bytes. Returns false once the other side has closed the
+/// connection.
+fn read_available(stream: &mut impl Read, buf: &mut Vec<u8>, limit: usize) -> bool {
+    let mut chunk = [0; 4096];
+    while buf.len() < limit {
+        let n = chunk.len().min(limit - buf.len());
+        match stream.read(&mut chunk[..n]) {
+            Ok(0) => return false,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// Writes as much of `out` as the socket takes without blocking and drops
+/// it from `out`. Returns false once the connection is broken.
+fn write_available(stream: &mut impl Write, out: &mut Vec<u8>) -> bool {
+    while !out.is_empty() {
+        match stream.write(out) {
+            Ok(0) => return false,
+            Ok(n) => {
+                out.drain(..n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,229 @@
+use crate::rng::Rng;
+use crate::{Config, Message, TAG_LEN};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+/* Fault Injection:
+ * A FaultyNetwork puts a TCP proxy on every link between the nodes of a
+ * cluster. Nodes only write on the connections they dial (see Peer
+ * Connections in node.rs), so the link from node i to node j is the one
+ * connection i dials to j, and the proxy for it sees all of i's traffic to
+ * j and nothing else. Each node is bound with a config of its own, in which
+ * the other nodes' peer addresses are those of its outgoing proxies.
+ *
+ * A proxy passes the dialer's handshake on as is, then splits the stream
+ * into frames, with their tags if the cluster has a secret, and drops,
+ * delays or duplicates each one as the link's faults say at the time.
+ * Delayed frames keep their order. A partitioned link drops every frame but
+ * keeps the connection up, as a network that loses packets would, so the
+ * nodes only notice through their timeouts. A proxy closes both sides when
+ * either node hangs up, and its threads live as long as the process does.
+ */
+
+/// How a link treats the frames sent over it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkFaults {
+    /// The chance that a frame is lost.
+    pub drop: f64,
+    /// The chance that a frame is delivered twice.
+    pub duplicate: f64,
+    /// How long every frame is held before it is passed on.
+    pub delay: Duration,
+    /// Whether every frame is lost.
+    pub partitioned: bool,
+}
+
+/// The proxy for one link.
+struct Link {
+    /// Where the proxy listens for the dialer.
+    addr: SocketAddr,
+    faults: Arc<Mutex<LinkFaults>>,
+}
+
+/// Proxies between every pair of nodes in a cluster, whose faults can be
+/// changed while it runs.
+pub struct FaultyNetwork {
+    config: Config,
+    /// By (from, to).
+    links: HashMap<(usize, usize), Link>,
+}
+
+impl FaultyNetwork {
+    /// Starts a proxy for every link between the nodes of `config`. The
+    /// proxies drop and duplicate frames at random from `seed`.
+    pub fn start(config: &Config, seed: u64) -> io::Result<FaultyNetwork> {
+        let mut links = HashMap::new();
+        let tag_len = if config.secret.is_some() { TAG_LEN } else { 0 };
+        for from in &config.nodes {
+            for to in &config.nodes {
+                if from.id == to.id {
+                    continue;
+                }
+                let listener = TcpListener::bind(("127.0.0.1", 0))?;
+                let addr = listener.local_addr()?;
+                let faults = Arc::new(Mutex::new(LinkFaults::default()));
+                let link_seed = seed ^ ((from.id as u64) << 32 | to.id as u64);
+                let (upstream, shared) = (to.peer_addr, faults.clone());
+                spawn(move || serve(listener, upstream, shared, link_seed, tag_len));
+                links.insert((from.id, to.id), Link { addr, faults });
+            }
+        }
+        Ok(FaultyNetwork {
+            config: config.clone(),
+            links,
+        })
+    }
+
+    /// The config node `id` should be bound with, so that it reaches the
+    /// other nodes through the proxies.
+    pub fn config(&self, id: usize) -> Config {
+        let mut config = self.config.clone();
+        for node in &mut config.nodes {
+            if let Some(link) = self.links.get(&(id, node.id)) {
+                node.peer_addr = link.addr;
+            }
+        }
+        config
+    }
+
+    /// How the link from node `from` to node `to` treats frames now.
+    pub fn faults(&self, from: usize, to: usize) -> LinkFaults {
+        *self.links[&(from, to)].faults.lock().unwrap()
+    }
+
+    /// Changes how the link from node `from` to node `to` treats the frames
+    /// sent from now on.
+    pub fn set_faults(&self, from: usize, to: usize, faults: LinkFaults) {
+        *self.links[&(from, to)].faults.lock().unwrap() = faults;
+    }
+
+    /// Gives every link the same faults.
+    pub fn set_all(&self, faults: LinkFaults) {
+        for link in self.links.values() {
+            *link.faults.lock().unwrap() = faults;
+        }
+    }
+
+    /// Partitions every link between nodes in different `groups`. Nodes in
+    /// no group are cut off from all others.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let group = |id: usize| groups.iter().position(|group| group.contains(&id));
+        for (&(from, to), link) in &self.links {
+            let apart = group(from).is_none() || group(from) != group(to);
+            link.faults.lock().unwrap().partitioned = apart;
+        }
+    }
+
+    /// Cuts node `id` off from every other node.
+    pub fn isolate(&self, id: usize) {
+        for (&(from, to), link) in &self.links {
+            if from == id || to == id {
+                link.faults.lock().unwrap().partitioned = true;
+            }
+        }
+    }
+
+    /// Ends every partition, leaving the other faults as they are.
+    pub fn heal(&self) {
+        for link in self.links.values() {
+            link.faults.lock().unwrap().partitioned = false;
+        }
+    }
+}
+
+/// Proxies every connection made to `listener` to `upstream`.
+fn serve(
+    listener: TcpListener,
+    upstream: SocketAddr,
+    faults: Arc<Mutex<LinkFaults>>,
+    seed: u64,
+    tag_len: usize,
+) {
+    let mut rng = Rng::new(seed);
+    for downstream in listener.incoming() {
+        let Ok(downstream) = downstream else {
+            continue;
+        };
+        // The dialer retries if the node it wants is not up
+        let Ok(upstream) = TcpStream::connect(upstream) else {
+            continue;
+        };
+        let _ = upstream.set_nodelay(true);
+        let (faults, seed) = (faults.clone(), rng.next_u64());
+        spawn(move || forward(downstream, upstream, faults, seed, tag_len));
+    }
+}
+
+/// Reads frames from `downstream` and passes them to `upstream` as the
+/// link's faults say, until either side hangs up.
+fn forward(
+    mut downstream: TcpStream,
+    mut upstream: TcpStream,
+    faults: Arc<Mutex<LinkFaults>>,
+    seed: u64,
+    tag_len: usize,
+) {
+    let (Ok(dialer), Ok(mut reader)) = (downstream.try_clone(), upstream.try_clone()) else {
+        return;
+    };
+    let (sender, receiver) = mpsc::channel::<(Instant, Vec<u8>)>();
+    spawn(move || {
+        for (due, bytes) in receiver {
+            sleep(due.saturating_duration_since(Instant::now()));
+            if upstream.write_all(&bytes).is_err() {
+                break;
+            }
+        }
+        let _ = upstream.shutdown(Shutdown::Both);
+        let _ = dialer.shutdown(Shutdown::Both);
+    });
+    // Nodes never write on connections they accepted, so this means EOF
+    let closer = downstream.try_clone();
+    spawn(move || {
+        let _ = reader.read(&mut [0; 1]);
+        if let Ok(closer) = closer {
+            let _ = closer.shutdown(Shutdown::Both);
+        }
+    });
+
+    let mut rng = Rng::new(seed);
+    let mut handshake = [0; 4];
+    if downstream.read_exact(&mut handshake).is_err() {
+        return;
+    }
+    let _ = sender.send((Instant::now(), handshake.to_vec()));
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    loop {
+        match downstream.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+        let mut pos = 0;
+        loop {
+            let len = match Message::frame_len(&buf[pos..]) {
+                Some(len) if buf.len() - pos >= len + tag_len => len + tag_len,
+                _ => break,
+            };
+            let frame = buf[pos..pos + len].to_vec();
+            pos += len;
+            let faults = *faults.lock().unwrap();
+            if faults.partitioned || rng.chance(faults.drop) {
+                continue;
+            }
+            let due = Instant::now() + faults.delay;
+            if rng.chance(faults.duplicate) {
+                let _ = sender.send((due, frame.clone()));
+            }
+            if sender.send((due, frame)).is_err() {
+                return;
+            }
+        }
+        buf.drain(..pos);
+    }
+}
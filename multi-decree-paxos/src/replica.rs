@@ -0,0 +1,393 @@
+use crate::metrics::gauge;
+use crate::Status;
+use crate::{Acceptor, Command, FileStorage, Learner, Message, Outcome, Proposer, Role, Storage};
+use crate::{KvStore, MemStorage, StateMachine};
+use crate::{Pipeline, Timeouts};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+use tracing::error;
+
+/// What a replica asks its transport to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Send the message to replica `to` only.
+    Send(usize, Message),
+    /// Send the message to every replica, this one included.
+    Broadcast(Message),
+    /// Answer the local client request with the given id, whose command was
+    /// just applied at the given slot. A read answered without a log entry
+    /// gives the first slot its answer does not reflect.
+    Respond(u64, u64, Outcome),
+}
+
+/// A proposer, acceptor and learner sharing one replica id. Routes every
+/// incoming message to the roles that handle it.
+pub struct Replica {
+    id: usize,
+    /// How many replicas the address book has, members or not.
+    n: usize,
+    proposer: Proposer,
+    acceptor: Acceptor,
+    learner: Learner,
+    /// Confirmed reads waiting for the learner to reach their read index,
+    /// by request id.
+    reads: Vec<(u64, u64, Command)>,
+    /// When each client request waiting for its outcome arrived, by id.
+    arrived: HashMap<u64, Instant>,
+}
+
+impl Replica {
+    /// A replica of an `n` node cluster that keeps its state in memory. All
+    /// `n` replicas are members until reconfigured.
+    pub fn new(id: usize, n: usize) -> Replica {
+        Replica::with_roles(id, n, Acceptor::new(), Learner::new())
+    }
+
+    /// A replica of `state` that keeps its state in memory.
+    pub fn with_state_machine(id: usize, n: usize, state: Box<dyn StateMachine>) -> Replica {
+        let storage = || Box::new(MemStorage::default());
+        // Memory storage starts empty, so there is nothing to fail to load
+        Replica::with_storage_and_state(id, n, storage(), storage(), state).unwrap()
+    }
+
+    /// A replica that keeps its write-ahead logs in `dir`, recovering
+    /// whatever state is already there.
+    pub fn open(id: usize, n: usize, dir: &Path) -> io::Result<Replica> {
+        Replica::open_with(id, n, dir, Box::new(KvStore::default()))
+    }
+
+    /// Like `open`, but replicating `state` rather than a key-value store.
+    pub fn open_with(
+        id: usize,
+        n: usize,
+        dir: &Path,
+        state: Box<dyn StateMachine>,
+    ) -> io::Result<Replica> {
+        std::fs::create_dir_all(dir)?;
+        Replica::with_storage_and_state(
+            id,
+            n,
+            Box::new(FileStorage::open(dir.join("acceptor.wal"))?),
+            Box::new(FileStorage::open(dir.join("learner.wal"))?),
+            state,
+        )
+    }
+
+    /// A replica that recovers from, and logs to, the given storage.
+    pub fn with_storage(
+        id: usize,
+        n: usize,
+        acceptor_storage: Box<dyn Storage>,
+        learner_storage: Box<dyn Storage>,
+    ) -> io::Result<Replica> {
+        let state = Box::new(KvStore::default());
+        Replica::with_storage_and_state(id, n, acceptor_storage, learner_storage, state)
+    }
+
+    /// A replica of `state` that recovers from, and logs to, the given
+    /// storage.
+    pub fn with_storage_and_state(
+        id: usize,
+        n: usize,
+        acceptor_storage: Box<dyn Storage>,
+        learner_storage: Box<dyn Storage>,
+        state: Box<dyn StateMachine>,
+    ) -> io::Result<Replica> {
+        let acceptor = Acceptor::with_storage(acceptor_storage)?;
+        let learner = Learner::with_state_machine(state, learner_storage)?;
+        Ok(Replica::with_roles(id, n, acceptor, learner))
+    }
+
+    fn with_roles(id: usize, n: usize, acceptor: Acceptor, learner: Learner) -> Replica {
+        let mut proposer = Proposer::new();
+        proposer.set_id(id);
+        proposer.set_first_slot(learner.next_slot());
+        proposer.set_tick_interval(learner.state_machine().tick_interval());
+        let mut replica = Replica {
+            id,
+            n,
+            proposer,
+            acceptor,
+            learner,
+            reads: Vec::new(),
+            arrived: HashMap::new(),
+        };
+        replica.set_initial_members((0..n).collect());
+        replica
+    }
+
+    /// Sets the members the cluster started with, which any reconfiguration
+    /// in the log replaces.
+    pub fn set_initial_members(&mut self, members: Vec<usize>) {
+        self.learner.set_initial_members(members);
+        self.proposer.set_membership(self.learner.membership());
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.proposer.set_timeouts(timeouts);
+    }
+
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.proposer.set_pipeline(pipeline);
+    }
+
+    /// See `Proposer::set_clock`.
+    pub fn set_clock(&mut self, now: Instant) {
+        self.proposer.set_clock(now);
+    }
+
+    pub fn set_snapshot_interval(&mut self, interval: u64) {
+        self.learner.set_snapshot_interval(interval);
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn proposer(&self) -> &Proposer {
+        &self.proposer
+    }
+
+    pub fn acceptor(&self) -> &Acceptor {
+        &self.acceptor
+    }
+
+    pub fn learner(&self) -> &Learner {
+        &self.learner
+    }
+
+    /// The replica's counters and latencies, followed by whether it leads
+    /// and how many slots it has applied, in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        self.proposer.metrics().render(&mut out);
+        gauge(
+            &mut out,
+            "paxos_leader",
+            "Whether this replica leads the cluster.",
+            self.proposer.is_leader() as u64,
+        );
+        gauge(
+            &mut out,
+            "paxos_applied_slots",
+            "The first slot this replica has not applied.",
+            self.learner.next_slot(),
+        );
+        out
+    }
+
+    /// What the replica knows of itself. It cannot see its connections, so
+    /// `peers` is left empty.
+    pub fn status(&self) -> Status {
+        let role = if self.proposer.is_leader() {
+            "leader"
+        } else if self.proposer.is_candidate() {
+            "candidate"
+        } else {
+            "follower"
+        };
+        let applied = self.learner.next_slot();
+        Status {
+            id: self.id,
+            role,
+            leader: self.proposer.leader(),
+            ballot: self.proposer.ballot(),
+            promised: self.acceptor.promised_proposal_number(),
+            accepted: (self.acceptor.log_start(), self.acceptor.next_slot()),
+            chosen: self.learner.next_free_slot(),
+            applied,
+            snapshot: self.learner.snapshot_slot(),
+            members: self.learner.membership().members(applied).to_vec(),
+            keys: self.learner.state_machine().entries().len(),
+            peers: vec![],
+        }
+    }
+
+    /// Snapshots the store now rather than when the interval is up, and
+    /// compacts the acceptor's log behind it. Returns the first slot the
+    /// snapshot does not cover.
+    pub fn snapshot(&mut self) -> io::Result<u64> {
+        self.learner.snapshot()?;
+        self.acceptor.compact(self.learner.snapshot_slot())?;
+        Ok(self.learner.snapshot_slot())
+    }
+
+    /// See `Proposer::resign`.
+    pub fn step_down(&mut self) -> bool {
+        self.proposer.resign()
+    }
+
+    /// True when no client request is waiting for its outcome.
+    pub fn is_idle(&self) -> bool {
+        self.proposer.is_idle() && self.reads.is_empty()
+    }
+
+    /// Proposes a newline separated client request, answered with
+    /// `Action::Respond` under `id`. Any number of requests may be in
+    /// progress at once. Returns `None` if it cannot be parsed. The leader
+    /// answers a request that only reads without a log entry. A read whose
+    /// first line is `stale` is answered at once from this replica's store,
+    /// which may lag behind the cluster. A reconfiguration may only name
+    /// replicas in the address book. A replica that follows another leader,
+    /// or may not lead, answers `Outcome::NotLeader` at once.
+    pub fn propose(&mut self, id: u64, request: &str) -> Option<Vec<Action>> {
+        self.arrived.insert(id, self.proposer.now());
+        let actions = self.route(id, request);
+        if actions.is_none() {
+            self.arrived.remove(&id);
+        }
+        actions
+    }
+
+    fn route(&mut self, id: u64, request: &str) -> Option<Vec<Action>> {
+        if let Some((mode, read)) = request.split_once('\n') {
+            if mode.eq_ignore_ascii_case("stale") {
+                let command = Command::from_client_request(read)?;
+                let outcome = self.learner.read(&command)?;
+                let slot = self.learner.next_slot();
+                return Some(vec![self.respond(id, slot, outcome)]);
+            }
+        }
+        let command = Command::from_client_request(request)?;
+        if let Some(members) = command.new_members() {
+            if members.iter().any(|&member| member >= self.n) {
+                return None;
+            }
+        }
+        // One leader serves the cluster, so proposers do not duel
+        let leader = self.proposer.leader();
+        if leader != Some(self.id) && (leader.is_some() || !self.proposer.can_lead()) {
+            let slot = self.learner.next_slot();
+            return Some(vec![self.respond(id, slot, Outcome::NotLeader(leader))]);
+        }
+        let msgs = if command.is_read_only() {
+            self.proposer.read(id, command)
+        } else {
+            self.proposer.propose(id, request)?
+        };
+        Some(msgs.into_iter().map(Action::Broadcast).collect())
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions: Vec<Action> = self
+            .proposer
+            .tick(now)
+            .into_iter()
+            .map(Action::Broadcast)
+            .collect();
+        actions.extend(self.housekeeping());
+        actions
+    }
+
+    /// Handles `msg` received from replica `from`.
+    pub fn handle_msg(&mut self, from: usize, msg: &Message) -> Vec<Action> {
+        let mut actions = vec![];
+        match msg {
+            Message::Prepare { .. } => {
+                for msg in self.acceptor.handle_msg(from, msg) {
+                    actions.push(Action::Send(from, msg));
+                }
+            }
+            Message::Accept { .. } => {
+                for msg in self.acceptor.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+            }
+            Message::Promise { .. } | Message::Nack { .. } | Message::Unaccepted { .. } => {
+                for msg in self.proposer.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+            }
+            Message::Heartbeat { .. } => {
+                for msg in self.proposer.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+                // The leader may be the one lagging, so ask everyone
+                for msg in self.learner.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+            }
+            Message::Confirm { .. } => {
+                for msg in self.acceptor.handle_msg(from, msg) {
+                    actions.push(Action::Send(from, msg));
+                }
+            }
+            Message::Confirmed { .. } => {
+                for msg in self.proposer.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+            }
+            Message::CatchUp { .. } => {
+                for msg in self.learner.handle_msg(from, msg) {
+                    actions.push(Action::Send(from, msg));
+                }
+            }
+            Message::Accepted { .. } | Message::Chosen { .. } | Message::Snapshot { .. } => {
+                for msg in self.proposer.handle_msg(from, msg) {
+                    actions.push(Action::Broadcast(msg));
+                }
+                // Responses are only meaningful to the local proposer
+                for msg in self.learner.handle_msg(from, msg) {
+                    for msg in self.proposer.handle_msg(from, &msg) {
+                        actions.push(Action::Broadcast(msg));
+                    }
+                }
+                self.proposer.set_first_slot(self.learner.next_slot());
+                self.proposer.set_membership(self.learner.membership());
+            }
+            Message::Response { .. } => {}
+        }
+        actions.extend(self.housekeeping());
+        actions
+    }
+
+    /// Compacts the acceptor behind the learner's snapshot, fills any gap
+    /// that blocks the learner and answers the applied client requests and
+    /// the confirmed reads the learner has caught up with.
+    fn housekeeping(&mut self) -> Vec<Action> {
+        if self.learner.snapshot_slot() > self.acceptor.log_start() {
+            if let Err(e) = self.acceptor.compact(self.learner.snapshot_slot()) {
+                error!("failed to compact acceptor log: {}", e);
+            }
+        }
+        let mut actions: Vec<Action> = self
+            .proposer
+            .fill_gaps(&self.learner.missing_slots())
+            .into_iter()
+            .map(Action::Broadcast)
+            .collect();
+        for (id, slot, outcome) in self.proposer.take_responses() {
+            actions.push(self.respond(id, slot, outcome));
+        }
+        self.reads.extend(self.proposer.take_confirmed_reads());
+        let applied = self.learner.next_slot();
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|(_, read_index, _)| *read_index <= applied);
+        self.reads = waiting;
+        for (id, _, command) in ready {
+            let outcome = self
+                .learner
+                .read(&command)
+                .expect("only reads are confirmed");
+            actions.push(self.respond(id, applied, outcome));
+        }
+        actions
+    }
+
+    /// Answers a client request, timing it from its arrival.
+    fn respond(&mut self, id: u64, slot: u64, outcome: Outcome) -> Action {
+        let now = self.proposer.now();
+        let metrics = self.proposer.metrics_mut();
+        metrics.requests += 1;
+        if matches!(outcome, Outcome::NotLeader(_)) {
+            metrics.redirects += 1;
+        }
+        if let Some(arrived) = self.arrived.remove(&id) {
+            metrics.request_latency.observe(now - arrived);
+        }
+        Action::Respond(id, slot, outcome)
+    }
+}
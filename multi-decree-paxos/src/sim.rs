@@ -0,0 +1,695 @@
+use crate::rng::Rng;
+use crate::{Action, Command, Learner, MemStorage, Message, Outcome, Record, Replica, Role};
+use crate::{Operation, Pipeline, Storage, Timeouts};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* Simulation:
+ * Every replica, client and link lives in one thread and runs on a virtual
+ * clock that advances in STEP increments. All randomness (message delays,
+ * drops, duplicates, crashes, client requests) comes from one generator
+ * seeded with `SimConfig::seed`, so a seed always replays the same schedule.
+ *
+ * A run injects faults for `duration`, then heals the network and restarts
+ * every replica for `settle`. Throughout the run the simulation checks that
+ * no two replicas apply different commands at a slot and that only commands
+ * a client proposed are applied. At the end it checks that every response a
+ * client got matches replaying the agreed log, and that all replicas have
+ * converged on the same log and key-value store.
+ */
+const STEP: Duration = Duration::from_millis(1);
+
+/// Knobs for one simulated run.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub replicas: usize,
+    pub clients: usize,
+    /// Every random choice of the run is drawn from this seed.
+    pub seed: u64,
+    /// How long faults are injected and clients send requests.
+    pub duration: Duration,
+    /// How long the healed cluster then gets to converge.
+    pub settle: Duration,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Probability that a message between two replicas is lost.
+    pub drop_rate: f64,
+    /// Probability that a message is delivered twice.
+    pub duplicate_rate: f64,
+    /// Probability per second that a replica crashes. At most a minority is
+    /// down at any time.
+    pub crash_rate: f64,
+    /// How long a crashed replica stays down before restarting from its logs.
+    pub downtime: Duration,
+    /// How long a client waits for a response before sending the request
+    /// again.
+    pub client_timeout: Duration,
+    /// Clients read and write keys `k0` to `k<keys - 1>`, and counters `c0`
+    /// to `c<keys - 1>`.
+    pub keys: usize,
+    /// Probability that a client request replaces the members with a random
+    /// subset of at least two replicas.
+    pub reconfigure_rate: f64,
+    pub timeouts: Timeouts,
+    pub pipeline: Pipeline,
+    /// Print every fault and client event, to study a failing seed.
+    pub trace: bool,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            replicas: 3,
+            clients: 3,
+            seed: 0,
+            duration: Duration::from_secs(10),
+            settle: Duration::from_secs(10),
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            crash_rate: 0.1,
+            downtime: Duration::from_secs(1),
+            client_timeout: Duration::from_secs(3),
+            keys: 3,
+            reconfigure_rate: 0.02,
+            timeouts: Timeouts::default(),
+            pipeline: Pipeline::default(),
+            trace: false,
+        }
+    }
+}
+
+/// What a run that found no violation did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Every client request, in the order they were sent.
+    pub history: Vec<Operation>,
+    /// The length of the agreed log.
+    pub slots: u64,
+    pub messages: u64,
+    pub crashes: u64,
+}
+
+/// An invariant that failed, with the seed that reproduces it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub seed: u64,
+    pub at: Duration,
+    pub description: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "seed {} at {:?}: {}",
+            self.seed, self.at, self.description
+        )
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// A log that outlives the replica writing it, the way a file would.
+#[derive(Clone, Default)]
+struct SimStorage(Arc<Mutex<MemStorage>>);
+
+impl Storage for SimStorage {
+    fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        self.0.lock().unwrap().append(records)
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Record>> {
+        self.0.lock().unwrap().load()
+    }
+
+    fn rewrite(&mut self, records: &[Record]) -> io::Result<()> {
+        self.0.lock().unwrap().rewrite(records)
+    }
+}
+
+struct Delivery {
+    at: Duration,
+    /// Breaks ties between deliveries due at the same time.
+    seq: u64,
+    from: usize,
+    to: usize,
+    msg: Message,
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so the max-heap pops the earliest delivery first
+impl Ord for Delivery {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+struct SimReplica {
+    /// `None` while crashed.
+    replica: Option<Replica>,
+    acceptor_log: SimStorage,
+    learner_log: SimStorage,
+    restart_at: Duration,
+    /// Requests sent to this replica that it has not been handed yet, oldest
+    /// first. Each is proposed with its index in `history` as id.
+    queue: VecDeque<usize>,
+    /// The next slot whose command has not been compared with the others.
+    checked: u64,
+}
+
+struct Client {
+    process: usize,
+    /// The index of the open request in `history`.
+    open: Option<usize>,
+    /// When the open request was last sent.
+    sent_at: Duration,
+    next_request: Duration,
+    sent: u64,
+}
+
+/// A seeded, single-threaded run of a whole cluster. See `SimConfig`.
+pub struct Simulation {
+    config: SimConfig,
+    rng: Rng,
+    start: Instant,
+    now: Duration,
+    replicas: Vec<SimReplica>,
+    clients: Vec<Client>,
+    network: BinaryHeap<Delivery>,
+    next_seq: u64,
+    history: Vec<Operation>,
+    /// Every response a replica sent: the request, the slot it was applied
+    /// at and its outcome. A retried request may be answered more than once.
+    responded: Vec<(usize, u64, Outcome)>,
+    /// Every command any replica applied, by slot.
+    log: BTreeMap<u64, Command>,
+    /// Every request a client sent.
+    proposed: HashSet<String>,
+    messages: u64,
+    crashes: u64,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Simulation {
+        let mut sim = Simulation {
+            rng: Rng::new(config.seed),
+            start: Instant::now(),
+            now: Duration::ZERO,
+            replicas: Vec::new(),
+            clients: (0..config.clients)
+                .map(|process| Client {
+                    process,
+                    open: None,
+                    sent_at: Duration::ZERO,
+                    next_request: Duration::ZERO,
+                    sent: 0,
+                })
+                .collect(),
+            network: BinaryHeap::new(),
+            next_seq: 0,
+            history: Vec::new(),
+            responded: Vec::new(),
+            log: BTreeMap::new(),
+            proposed: HashSet::new(),
+            messages: 0,
+            crashes: 0,
+            config,
+        };
+        for _ in 0..sim.config.replicas {
+            sim.replicas.push(SimReplica {
+                replica: None,
+                acceptor_log: SimStorage::default(),
+                learner_log: SimStorage::default(),
+                restart_at: Duration::ZERO,
+                queue: VecDeque::new(),
+                checked: 0,
+            });
+        }
+        for id in 0..sim.config.replicas {
+            sim.restart(id);
+        }
+        sim
+    }
+
+    /// Runs the whole schedule, stopping at the first violated invariant.
+    pub fn run(mut self) -> Result<Report, Violation> {
+        let end = self.config.duration + self.config.settle;
+        while self.now < end {
+            self.now += STEP;
+            let faulty = self.now < self.config.duration;
+            self.crash_and_restart(faulty);
+            if faulty {
+                self.send_requests();
+            }
+            self.retry_requests();
+
+            while self.network.peek().is_some_and(|d| d.at <= self.now) {
+                let delivery = self.network.pop().unwrap();
+                self.messages += 1;
+                let Some(replica) = &mut self.replicas[delivery.to].replica else {
+                    continue;
+                };
+                let actions = replica.handle_msg(delivery.from, &delivery.msg);
+                self.dispatch(delivery.to, actions)?;
+            }
+            for id in 0..self.replicas.len() {
+                let now = self.start + self.now;
+                if let Some(replica) = &mut self.replicas[id].replica {
+                    let actions = replica.tick(now);
+                    self.dispatch(id, actions)?;
+                }
+                self.serve(id)?;
+            }
+        }
+        self.check_responses()?;
+        self.check_convergence()?;
+        Ok(Report {
+            history: self.history,
+            slots: self.log.keys().next_back().map_or(0, |slot| slot + 1),
+            messages: self.messages,
+            crashes: self.crashes,
+        })
+    }
+
+    fn trace(&self, event: fmt::Arguments) {
+        if self.config.trace {
+            println!("[{:>8.3}s] {}", self.now.as_secs_f64(), event);
+        }
+    }
+
+    fn violation(&self, description: String) -> Violation {
+        Violation {
+            seed: self.config.seed,
+            at: self.now,
+            description,
+        }
+    }
+
+    fn restart(&mut self, id: usize) {
+        let n = self.config.replicas;
+        let sim_replica = &mut self.replicas[id];
+        let mut replica = Replica::with_storage(
+            id,
+            n,
+            Box::new(sim_replica.acceptor_log.clone()),
+            Box::new(sim_replica.learner_log.clone()),
+        )
+        .expect("in-memory logs cannot fail");
+        replica.set_timeouts(self.config.timeouts);
+        replica.set_pipeline(self.config.pipeline);
+        replica.set_clock(self.start + self.now);
+        // Every applied slot must stay in the learner's log to be checked
+        replica.set_snapshot_interval(0);
+        sim_replica.replica = Some(replica);
+    }
+
+    fn crash_and_restart(&mut self, faulty: bool) {
+        let max_down = (self.config.replicas - 1) / 2;
+        for id in 0..self.replicas.len() {
+            if self.replicas[id].replica.is_none() {
+                if !faulty || self.now >= self.replicas[id].restart_at {
+                    self.trace(format_args!("replica {} restarts", id));
+                    self.restart(id);
+                }
+                continue;
+            }
+            let down = self.replicas.iter().filter(|r| r.replica.is_none()).count();
+            let p = self.config.crash_rate * STEP.as_secs_f64();
+            if faulty && down < max_down && self.rng.chance(p) {
+                self.trace(format_args!("replica {} crashes", id));
+                self.crashes += 1;
+                let replica = &mut self.replicas[id];
+                replica.replica = None;
+                replica.restart_at = self.now + self.config.downtime;
+                // Its clients will time out
+                replica.queue.clear();
+            }
+        }
+    }
+
+    fn send_requests(&mut self) {
+        for c in 0..self.clients.len() {
+            let client = &self.clients[c];
+            if client.open.is_some() || self.now < client.next_request {
+                continue;
+            }
+            let to = self.rng.below(self.config.replicas as u64) as usize;
+            let index = self.rng.below(self.config.keys as u64);
+            let key = format!("k{}", index);
+            let value = format!("{}-{}", client.process, client.sent);
+            let command = match self.rng.below(20) {
+                _ if self.rng.chance(self.config.reconfigure_rate) => {
+                    let replicas = self.config.replicas;
+                    let mut members: Vec<usize> =
+                        (0..replicas).filter(|_| self.rng.chance(0.5)).collect();
+                    if members.len() < 2 {
+                        members = (0..replicas).collect();
+                    }
+                    Command::Reconfigure { members }
+                }
+                0..=7 => Command::Get { key },
+                8..=12 => Command::Put { key, value },
+                13 => Command::Delete { key },
+                14 => Command::Cas {
+                    key,
+                    expected: format!("{}-{}", client.process, client.sent.saturating_sub(1)),
+                    new: value,
+                },
+                15 => Command::Append { key, value },
+                // Counters live apart, as most values are not integers
+                16 | 17 => Command::Incr {
+                    key: format!("c{}", index),
+                    by: (client.sent * self.clients.len() as u64 + c as u64) as i64 + 1,
+                },
+                18 => Command::Scan {
+                    start: "c".to_string(),
+                    end: key,
+                },
+                _ => Command::ScanPrefix {
+                    prefix: "k".to_string(),
+                },
+            };
+            let command = Command::Session {
+                client: client.process as u64,
+                seq: client.sent,
+                command: Box::new(command),
+            };
+            self.proposed.insert(command.to_client_request().unwrap());
+            if self.replicas[to].replica.is_none() {
+                // Connection refused; try again shortly
+                self.clients[c].next_request = self.now + Duration::from_millis(10);
+                continue;
+            }
+            self.trace(format_args!(
+                "process {} sends {:?} to replica {}",
+                self.clients[c].process, command, to
+            ));
+            let op = self.history.len();
+            self.history.push(Operation {
+                process: self.clients[c].process,
+                command,
+                invoked: self.now,
+                completed: None,
+            });
+            self.replicas[to].queue.push_back(op);
+            let client = &mut self.clients[c];
+            client.open = Some(op);
+            client.sent_at = self.now;
+            client.sent += 1;
+        }
+    }
+
+    /// Sends requests that went unanswered for too long again, to a replica
+    /// picked at random. The session makes sure they take effect once.
+    fn retry_requests(&mut self) {
+        for c in 0..self.clients.len() {
+            let Some(op) = self.clients[c].open else {
+                continue;
+            };
+            if self.now - self.clients[c].sent_at < self.config.client_timeout {
+                continue;
+            }
+            let to = self.rng.below(self.config.replicas as u64) as usize;
+            self.clients[c].sent_at = self.now;
+            if self.replicas[to].replica.is_none() {
+                continue;
+            }
+            self.trace(format_args!(
+                "process {} retries at replica {}",
+                self.clients[c].process, to
+            ));
+            self.replicas[to].queue.push_back(op);
+        }
+    }
+
+    /// Hands every request queued at replica `id` to it at once, so the
+    /// leader gets to batch and pipeline them.
+    fn serve(&mut self, id: usize) -> Result<(), Violation> {
+        while let Some(op) = self.replicas[id].queue.pop_front() {
+            let Some(replica) = &mut self.replicas[id].replica else {
+                return Ok(());
+            };
+            let request = self.history[op]
+                .command
+                .to_client_request()
+                .expect("clients never send no-ops");
+            let actions = replica
+                .propose(op as u64, &request)
+                .expect("generated requests are valid");
+            self.dispatch(id, actions)?;
+        }
+        Ok(())
+    }
+
+    /// Carries out what replica `from` asked for. Messages a replica sends
+    /// itself are handled at once, like `Node` does.
+    fn dispatch(&mut self, from: usize, actions: Vec<Action>) -> Result<(), Violation> {
+        let mut local = VecDeque::new();
+        let mut actions = VecDeque::from(actions);
+        loop {
+            let Some(action) = actions.pop_front() else {
+                let Some(msg) = local.pop_front() else {
+                    break;
+                };
+                self.check_replica(from)?;
+                let Some(replica) = &mut self.replicas[from].replica else {
+                    break;
+                };
+                actions.extend(replica.handle_msg(from, &msg));
+                continue;
+            };
+            match action {
+                Action::Send(to, msg) if to == from => local.push_back(msg),
+                Action::Send(to, msg) => self.send(from, to, msg),
+                Action::Broadcast(msg) => {
+                    for to in 0..self.replicas.len() {
+                        if to != from {
+                            self.send(from, to, msg.clone());
+                        }
+                    }
+                    local.push_back(msg);
+                }
+                Action::Respond(op, slot, outcome) => self.respond(op as usize, slot, outcome),
+            }
+        }
+        self.check_replica(from)
+    }
+
+    fn send(&mut self, from: usize, to: usize, msg: Message) {
+        let faulty = self.now < self.config.duration;
+        if faulty && self.rng.chance(self.config.drop_rate) {
+            return;
+        }
+        let copies = if faulty && self.rng.chance(self.config.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let spread = (self.config.max_delay - self.config.min_delay).as_micros() as u64;
+            let delay = self.config.min_delay + Duration::from_micros(self.rng.below(spread + 1));
+            self.network.push(Delivery {
+                at: self.now + delay,
+                seq: self.next_seq,
+                from,
+                to,
+                msg: msg.clone(),
+            });
+            self.next_seq += 1;
+        }
+    }
+
+    fn respond(&mut self, op: usize, slot: u64, outcome: Outcome) {
+        if let Outcome::NotLeader(leader) = outcome {
+            self.redirect(op, leader);
+            return;
+        }
+        self.responded.push((op, slot, outcome.clone()));
+        let client = self.clients.iter_mut().find(|c| c.open == Some(op));
+        // A client answered by another replica has moved on
+        if let Some(client) = client {
+            client.open = None;
+            client.next_request = self.now + Duration::from_millis(self.rng.below(50));
+            self.trace(format_args!(
+                "process {} gets {} at slot {}",
+                self.history[op].process, outcome, slot
+            ));
+            self.history[op].completed = Some((self.now, outcome));
+        }
+    }
+
+    /// Sends a request a replica turned down to the leader it named, or to a
+    /// replica picked at random.
+    fn redirect(&mut self, op: usize, leader: Option<usize>) {
+        let Some(c) = self.clients.iter().position(|c| c.open == Some(op)) else {
+            return;
+        };
+        let to = leader.unwrap_or_else(|| self.rng.below(self.config.replicas as u64) as usize);
+        self.clients[c].sent_at = self.now;
+        if self.replicas[to].replica.is_none() {
+            return;
+        }
+        self.trace(format_args!(
+            "process {} is redirected to replica {}",
+            self.clients[c].process, to
+        ));
+        self.replicas[to].queue.push_back(op);
+    }
+
+    /// Agreement and validity: compares what replica `id` applied since the
+    /// last check with what every other replica applied at those slots.
+    fn check_replica(&mut self, id: usize) -> Result<(), Violation> {
+        let Some(replica) = &self.replicas[id].replica else {
+            return Ok(());
+        };
+        let mut checked = self.replicas[id].checked;
+        for (slot, command) in replica.learner().applied(checked) {
+            let commands = match command {
+                Command::Batch(commands) => &commands[..],
+                command => std::slice::from_ref(command),
+            };
+            for command in commands {
+                if let Some(request) = command.to_client_request() {
+                    if !self.proposed.contains(&request) {
+                        return Err(self.violation(format!(
+                            "replica {} applied {:?} at slot {}, which no client sent",
+                            id, command, slot
+                        )));
+                    }
+                } else if command != &Command::Noop {
+                    return Err(self.violation(format!(
+                        "replica {} applied {:?} at slot {}, which is not a client request",
+                        id, command, slot
+                    )));
+                }
+            }
+            match self.log.get(&slot) {
+                Some(agreed) if agreed != command => {
+                    return Err(self.violation(format!(
+                        "replica {} applied {:?} at slot {}, others applied {:?}",
+                        id, command, slot, agreed
+                    )));
+                }
+                Some(_) => {}
+                None => {
+                    self.log.insert(slot, command.clone());
+                }
+            }
+            checked = slot + 1;
+        }
+        self.replicas[id].checked = checked;
+        Ok(())
+    }
+
+    /// Replays the agreed log and checks every response against it.
+    fn check_responses(&self) -> Result<(), Violation> {
+        // A read may be answered without a log entry, from the store as it
+        // stood before its slot
+        let mut reads: BTreeMap<u64, Vec<(&Command, &Outcome)>> = BTreeMap::new();
+        let mut applied = vec![];
+        for (op, slot, outcome) in &self.responded {
+            let command = &self.history[*op].command;
+            // The position of the command in a batch
+            let batched = match self.log.get(slot) {
+                Some(Command::Batch(commands)) => commands.iter().position(|c| c == command),
+                _ => None,
+            };
+            if self.log.get(slot) == Some(command) || batched.is_some() {
+                applied.push((command, *slot, batched, outcome));
+            } else if command.is_read_only() {
+                reads.entry(*slot).or_default().push((command, outcome));
+            } else {
+                return Err(self.violation(format!(
+                    "{:?} was answered from slot {}, which holds {:?}",
+                    command,
+                    slot,
+                    self.log.get(slot)
+                )));
+            }
+        }
+        let mut reference = Learner::new();
+        let mut outcomes = BTreeMap::new();
+        let mut answers = vec![];
+        let mut reads = reads.into_iter().peekable();
+        for (&slot, command) in &self.log {
+            while let Some((read_slot, ops)) = reads.next_if(|(read_slot, _)| *read_slot <= slot) {
+                for (command, outcome) in ops {
+                    answers.push((command, read_slot, outcome, reference.read(command)));
+                }
+            }
+            let chosen = Message::Chosen {
+                slot,
+                proposal_number: Default::default(),
+                command: command.clone(),
+            };
+            for msg in reference.handle_msg(0, &chosen) {
+                if let Message::Response { slot, outcome, .. } = msg {
+                    outcomes.insert(slot, outcome);
+                }
+            }
+        }
+        for (read_slot, ops) in reads {
+            for (command, outcome) in ops {
+                answers.push((command, read_slot, outcome, reference.read(command)));
+            }
+        }
+        for (command, slot, batched, outcome) in applied {
+            let expected = match (outcomes.get(&slot), batched) {
+                (Some(Outcome::Batch(outcomes)), Some(i)) => outcomes.get(i),
+                (expected, _) => expected,
+            };
+            answers.push((command, slot, outcome, expected.cloned()));
+        }
+        for (command, slot, outcome, expected) in answers {
+            if expected.as_ref() != Some(outcome) {
+                return Err(self.violation(format!(
+                    "{:?} at slot {} got {}, but the log gives {:?}",
+                    command, slot, outcome, expected
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// After healing, every replica must have applied the same log.
+    fn check_convergence(&self) -> Result<(), Violation> {
+        let learners: Vec<&Learner> = self
+            .replicas
+            .iter()
+            .filter_map(|r| r.replica.as_ref())
+            .map(|replica| replica.learner())
+            .collect();
+        for (id, learner) in learners.iter().enumerate().skip(1) {
+            if learner.next_slot() != learners[0].next_slot()
+                || learner.state_machine().snapshot() != learners[0].state_machine().snapshot()
+            {
+                return Err(self.violation(format!(
+                    "replica {} applied {} slots and replica 0 applied {} after healing",
+                    id,
+                    learner.next_slot(),
+                    learners[0].next_slot()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
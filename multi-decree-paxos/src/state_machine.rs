@@ -0,0 +1,164 @@
+use crate::message::{Decoder, Encoder};
+use crate::{Command, DecodeError, Outcome};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/* State Machines:
+ * A learner applies the chosen commands to a state machine, one slot at a
+ * time and in slot order, so every replica's state machine goes through the
+ * same states. The learner deals with NOOP, SESSION, BATCH and RECONFIGURE
+ * itself and hands every other command, with the slot it was chosen at, to
+ * the state machine. Whatever the state machine answers goes back to the
+ * client as the command's outcome.
+ *
+ * A snapshot is the whole state as bytes the state machine itself encodes;
+ * the learner stores it and sends it to peers that fall behind without
+ * looking inside. `apply` and `restore` must be deterministic, and `snapshot`
+ * must give the same bytes on every replica in the same state. So a state
+ * machine cannot read the clock; one that keeps time asks for TICKs, which
+ * the leader proposes like any other command (see locks.rs).
+ */
+/// The service a cluster replicates.
+pub trait StateMachine: Send {
+    /// Applies `command`, chosen at `slot`, and returns its outcome.
+    fn apply(&mut self, slot: u64, command: &Command) -> Outcome;
+    /// Answers a command that only reads, from the state as it stands now.
+    /// Must answer every command `Command::is_read_only` holds for, as the
+    /// leader answers those without a log entry. `None` if the command
+    /// writes.
+    fn read(&self, command: &Command) -> Option<Outcome>;
+    /// Every entry of the state as name and value, in name order, for
+    /// operators to look at.
+    fn entries(&self) -> Vec<(String, String)>;
+    fn snapshot(&self) -> Vec<u8>;
+    /// Replaces the state with a `snapshot` of it. Leaves the state as it
+    /// was if the snapshot does not decode.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DecodeError>;
+    /// How often the leader should propose a TICK carrying the time since
+    /// its last one, for state machines that keep time. `None` if they do
+    /// not.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+    /// The keys applying `command` may change, asked just before it is
+    /// applied so that watchers hear of them (see watch.rs). Defaults to
+    /// the key of a command that writes one.
+    fn changed_keys(&self, command: &Command) -> Vec<String> {
+        match command.key() {
+            Some(key) if !command.is_read_only() => vec![key.to_string()],
+            _ => vec![],
+        }
+    }
+}
+
+/// The key-value store: GET, PUT, DELETE, CAS, APPEND, INCR and SCAN.
+/// Snapshots are <count: u32> *(<key> <value>), in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KvStore {
+    entries: HashMap<String, String>,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn scan(&self, wanted: impl Fn(&str) -> bool) -> Outcome {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| wanted(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        entries.sort();
+        Outcome::Entries(entries)
+    }
+}
+
+impl StateMachine for KvStore {
+    fn apply(&mut self, _slot: u64, command: &Command) -> Outcome {
+        match command {
+            Command::Put { key, value } => {
+                self.entries.insert(key.clone(), value.clone());
+                Outcome::PutOk
+            }
+            Command::Delete { key } => match self.entries.remove(key) {
+                Some(_) => Outcome::Deleted,
+                None => Outcome::NotFound,
+            },
+            Command::Cas { key, expected, new } => match self.entries.get_mut(key) {
+                Some(value) if value == expected => {
+                    *value = new.clone();
+                    Outcome::Updated(new.clone())
+                }
+                value => Outcome::CasFailed(value.cloned()),
+            },
+            Command::Append { key, value } => {
+                let current = self.entries.entry(key.clone()).or_default();
+                current.push_str(value);
+                Outcome::Updated(current.clone())
+            }
+            Command::Incr { key, by } => {
+                let current = match self.entries.get(key) {
+                    Some(value) => value.parse::<i64>().ok(),
+                    None => Some(0),
+                };
+                match current.and_then(|current| current.checked_add(*by)) {
+                    Some(sum) => {
+                        self.entries.insert(key.clone(), sum.to_string());
+                        Outcome::Updated(sum.to_string())
+                    }
+                    None => Outcome::NotAnInteger,
+                }
+            }
+            command => self.read(command).unwrap_or(Outcome::Noop),
+        }
+    }
+
+    fn read(&self, command: &Command) -> Option<Outcome> {
+        let outcome = match command {
+            Command::Get { key } => match self.entries.get(key) {
+                Some(value) => Outcome::Value(value.clone()),
+                None => Outcome::NotFound,
+            },
+            Command::Scan { start, end } => {
+                self.scan(|key| key >= start.as_str() && (end.is_empty() || key < end.as_str()))
+            }
+            Command::ScanPrefix { prefix } => self.scan(|key| key.starts_with(prefix.as_str())),
+            _ => return None,
+        };
+        Some(outcome)
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.put_kv_store(&self.entries());
+        encoder.into_inner()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DecodeError> {
+        let mut decoder = Decoder::new(snapshot);
+        let entries = decoder.get_kv_store()?;
+        decoder.finish()?;
+        self.entries = entries.into_iter().collect();
+        Ok(())
+    }
+}
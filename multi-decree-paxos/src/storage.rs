@@ -0,0 +1,320 @@
+use crate::message::{Decoder, Encoder};
+use crate::{Ballot, Command, DecodeError, Outcome};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+/* Log Format:
+ * Every record is stored as
+ *   <payload_len: u32> <checksum: u32> <payload>
+ * where the checksum is the FNV-1a hash of the payload, and the payload uses
+ * the same field encoding as the wire protocol:
+ * PROMISED <proposal_number>
+ * ACCEPTED <slot> <proposal_number> <command>
+ * CHOSEN   <slot> <proposal_number> <command>
+ * SNAPSHOT <slot> <state: bytes> <count: u32> *(<client> <seq> <outcome> <last_slot>)
+ *          <count: u32> *(<first_slot> <members>)
+ * TRUNCATE <slot>
+ * A record cut short by a crash is dropped the next time the log is loaded.
+ * A whole record that fails its checksum or does not decode is corruption,
+ * not a torn write, and loading fails: dropping it and everything after it
+ * would forget promises and votes. An append that fails is cut off again
+ * before anything else is appended. Compaction rewrites the whole log,
+ * replacing a prefix of it with a SNAPSHOT or TRUNCATE record.
+ */
+const RECORD_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    /// The acceptor promised not to accept anything below `proposal_number`.
+    Promised { proposal_number: Ballot },
+    /// The acceptor accepted `command` at `slot`.
+    Accepted {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+    },
+    /// The learner applied `command` at `slot`.
+    Chosen {
+        slot: u64,
+        proposal_number: Ballot,
+        command: Command,
+    },
+    /// A snapshot of the learner's state machine after applying every slot
+    /// below `slot`, every client's last request number with its outcome
+    /// and the slot its session was last used at, and the members from each
+    /// slot on.
+    Snapshot {
+        slot: u64,
+        state: Vec<u8>,
+        sessions: Vec<(u64, u64, Outcome, u64)>,
+        configs: Vec<(u64, Vec<usize>)>,
+    },
+    /// The acceptor discarded every slot below `slot`.
+    Truncated { slot: u64 },
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Encoder::default();
+        match self {
+            Record::Promised { proposal_number } => {
+                payload.put_u8(0);
+                payload.put_ballot(*proposal_number);
+            }
+            Record::Accepted {
+                slot,
+                proposal_number,
+                command,
+            } => {
+                payload.put_u8(1);
+                payload.put_u64(*slot);
+                payload.put_ballot(*proposal_number);
+                payload.put_command(command);
+            }
+            Record::Chosen {
+                slot,
+                proposal_number,
+                command,
+            } => {
+                payload.put_u8(2);
+                payload.put_u64(*slot);
+                payload.put_ballot(*proposal_number);
+                payload.put_command(command);
+            }
+            Record::Snapshot {
+                slot,
+                state,
+                sessions,
+                configs,
+            } => {
+                payload.put_u8(3);
+                payload.put_u64(*slot);
+                payload.put_bytes(state);
+                payload.put_sessions(sessions);
+                payload.put_configs(configs);
+            }
+            Record::Truncated { slot } => {
+                payload.put_u8(4);
+                payload.put_u64(*slot);
+            }
+        }
+        let payload = payload.into_inner();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&checksum(&payload).to_be_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// Decodes the first record in `buf` and the number of bytes it occupied.
+    fn decode(buf: &[u8]) -> Result<(Record, usize), DecodeError> {
+        if buf.len() < RECORD_HEADER_LEN {
+            return Err(DecodeError::Incomplete);
+        }
+        let payload_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let expected = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let record_len = RECORD_HEADER_LEN + payload_len;
+        if buf.len() < record_len {
+            return Err(DecodeError::Incomplete);
+        }
+        let payload = &buf[RECORD_HEADER_LEN..record_len];
+        if checksum(payload) != expected {
+            return Err(DecodeError::Malformed("checksum mismatch"));
+        }
+
+        let mut payload = Decoder::new(payload);
+        let record = match payload.get_u8()? {
+            0 => Record::Promised {
+                proposal_number: payload.get_ballot()?,
+            },
+            1 => Record::Accepted {
+                slot: payload.get_u64()?,
+                proposal_number: payload.get_ballot()?,
+                command: payload.get_command()?,
+            },
+            2 => Record::Chosen {
+                slot: payload.get_u64()?,
+                proposal_number: payload.get_ballot()?,
+                command: payload.get_command()?,
+            },
+            3 => Record::Snapshot {
+                slot: payload.get_u64()?,
+                state: payload.get_bytes()?,
+                sessions: payload.get_sessions()?,
+                configs: payload.get_configs()?,
+            },
+            4 => Record::Truncated {
+                slot: payload.get_u64()?,
+            },
+            _ => return Err(DecodeError::Malformed("unknown record")),
+        };
+        payload.finish()?;
+        Ok((record, record_len))
+    }
+}
+
+/// 32-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Where a role keeps the state it must not forget across a restart.
+pub trait Storage: Send {
+    /// Appends `records` to the log. Must not return until they are durable.
+    fn append(&mut self, records: &[Record]) -> io::Result<()>;
+    /// Returns every record appended so far, oldest first.
+    fn load(&mut self) -> io::Result<Vec<Record>>;
+    /// Atomically replaces the whole log with `records`.
+    fn rewrite(&mut self, records: &[Record]) -> io::Result<()>;
+}
+
+/// Keeps the log in memory. State is lost when the process exits.
+#[derive(Default)]
+pub struct MemStorage {
+    records: Vec<Record>,
+}
+
+impl Storage for MemStorage {
+    fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        self.records.extend_from_slice(records);
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Record>> {
+        Ok(self.records.clone())
+    }
+
+    fn rewrite(&mut self, records: &[Record]) -> io::Result<()> {
+        self.records = records.to_vec();
+        Ok(())
+    }
+}
+
+/// An append-only write-ahead log file, synced to disk on every append.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    /// Set when a failed append could not be cut off again. Nothing more is
+    /// appended after it, as `load` would drop whatever followed.
+    failed: bool,
+}
+
+impl FileStorage {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<FileStorage> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        Ok(FileStorage {
+            path,
+            file,
+            failed: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            buf.extend(record.encode());
+        }
+        if self.failed {
+            return Err(io::Error::other(format!(
+                "{}: an earlier append left a torn record",
+                self.path.display()
+            )));
+        }
+        let len = self.file.metadata()?.len();
+        let result = self
+            .file
+            .write_all(&buf)
+            .and_then(|()| self.file.sync_data());
+        if result.is_err() {
+            // A partial record would hide every record appended after it
+            let truncated = self.file.set_len(len).and_then(|()| self.file.sync_data());
+            self.failed = truncated.is_err();
+        }
+        result
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Record>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            match Record::decode(&buf[pos..]) {
+                Ok((record, len)) => {
+                    records.push(record);
+                    pos += len;
+                }
+                // A record cut short can only be the last one written
+                Err(DecodeError::Incomplete) => {
+                    warn!(
+                        path = %self.path.display(),
+                        offset = pos,
+                        bytes = buf.len() - pos,
+                        "discarding torn record"
+                    );
+                    self.file.set_len(pos as u64)?;
+                    self.file.sync_data()?;
+                    break;
+                }
+                // Anything after it may be a promise or a vote, so refuse to go on
+                Err(e) => {
+                    error!(path = %self.path.display(), offset = pos, "corrupt record: {}", e);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: corrupt record at offset {}: {}",
+                            self.path.display(),
+                            pos,
+                            e
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Writes `records` to a temporary file and renames it over the log, so a
+    /// crash leaves either the old or the new log behind.
+    fn rewrite(&mut self, records: &[Record]) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut buf = Vec::new();
+        for record in records {
+            buf.extend(record.encode());
+        }
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.failed = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+/* Watches:
+ * A client that wants to follow keys rather than poll them sends, on a
+ * framed connection (see Client Connections in node.rs),
+ *   watch key <key> [<from_slot>]      watch prefix <prefix> [<from_slot>]
+ * The node answers `watch successful! from slot:<slot>` and from then on
+ * streams a frame for every change to a watched key applied at that slot or
+ * later, in log order:
+ *   change slot:<slot> created\n<key>\n<new>
+ *   change slot:<slot> updated\n<key>\n<old>\n<new>
+ *   change slot:<slot> deleted\n<key>\n<old>
+ * Without a slot the watch starts at the first slot the node has not
+ * applied. The connection takes no more requests; the client closes it to
+ * stop watching.
+ *
+ * Each learner keeps the last `WATCH_HISTORY` changes it applied, so a
+ * client that lost its connection can watch again, on any node, from the
+ * slot of the last change it saw. A slot may hold several changes, so it
+ * skips the ones of that slot it already has. A node that no longer has the
+ * changes from the slot asked for, for instance because it was brought up
+ * to date with a snapshot, answers `watch failed! history from slot:<slot>`
+ * instead, and the client has to read the keys afresh. A client that reads
+ * more slowly than changes are applied is queued only so much of them at a
+ * time; if it falls behind the changes the node keeps, it is sent that same
+ * answer and the connection is closed.
+ *
+ * A change is a key whose value differs after a command was applied. The
+ * state machine names the keys each command may change, and the learner
+ * compares what GET answered for them before and after. Writes that leave a
+ * value as it was, such as a failed CAS, are not changes.
+ */
+/// How many of the changes it applied last a learner keeps for watches.
+pub const WATCH_HISTORY: usize = 10_000;
+
+/// A key whose value a command chosen at `slot` changed. `None` stands for
+/// no value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub slot: u64,
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// The keys a watch follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchTarget {
+    Key(String),
+    Prefix(String),
+}
+
+/// A request to stream the changes to some keys from `from` on, or from the
+/// node's next slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub target: WatchTarget,
+    pub from: Option<u64>,
+}
+
+impl Watch {
+    /// Parses a newline separated request such as `watch\nprefix\nuser/`.
+    /// `None` if it is not a watch.
+    pub fn from_client_request(msg: &str) -> Option<Watch> {
+        let msg: Vec<&str> = msg.split('\n').collect();
+        if !msg.first()?.eq_ignore_ascii_case("watch") || msg.len() > 4 {
+            return None;
+        }
+        let target = match msg.get(1)?.to_lowercase().as_str() {
+            "key" => WatchTarget::Key(msg.get(2)?.to_string()),
+            "prefix" => WatchTarget::Prefix(msg.get(2)?.to_string()),
+            _ => return None,
+        };
+        let from = match msg.get(3) {
+            Some(from) => Some(from.parse().ok()?),
+            None => None,
+        };
+        Some(Watch { target, from })
+    }
+
+    /// The inverse of `from_client_request`.
+    pub fn to_client_request(&self) -> String {
+        let mut request = match &self.target {
+            WatchTarget::Key(key) => format!("watch\nkey\n{}", key),
+            WatchTarget::Prefix(prefix) => format!("watch\nprefix\n{}", prefix),
+        };
+        if let Some(from) = self.from {
+            request += &format!("\n{}", from);
+        }
+        request
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match &self.target {
+            WatchTarget::Key(watched) => key == watched,
+            WatchTarget::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// What a node streams to a watching client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The watch covers the changes from the given slot on.
+    Started(u64),
+    /// The node only has the changes from the given slot on, later than the
+    /// watch asked for.
+    Compacted(u64),
+    Change(Change),
+}
+
+impl WatchEvent {
+    /// Parses an event as a client receives it.
+    pub fn from_response(response: &str) -> Option<WatchEvent> {
+        if let Some(slot) = response.strip_prefix("watch successful! from slot:") {
+            return Some(WatchEvent::Started(slot.parse().ok()?));
+        }
+        if let Some(slot) = response.strip_prefix("watch failed! history from slot:") {
+            return Some(WatchEvent::Compacted(slot.parse().ok()?));
+        }
+        let lines: Vec<&str> = response.split('\n').collect();
+        let (slot, kind) = lines
+            .first()?
+            .strip_prefix("change slot:")?
+            .split_once(' ')?;
+        let value = |i: usize| lines.get(i).map(|value| value.to_string());
+        let (old, new) = match (kind, lines.len()) {
+            ("created", 3) => (None, value(2)),
+            ("updated", 4) => (value(2), value(3)),
+            ("deleted", 3) => (value(2), None),
+            _ => return None,
+        };
+        Some(WatchEvent::Change(Change {
+            slot: slot.parse().ok()?,
+            key: lines[1].to_string(),
+            old,
+            new,
+        }))
+    }
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchEvent::Started(slot) => write!(f, "watch successful! from slot:{}", slot),
+            WatchEvent::Compacted(slot) => write!(f, "watch failed! history from slot:{}", slot),
+            WatchEvent::Change(change) => {
+                write!(f, "change slot:{} ", change.slot)?;
+                match (&change.old, &change.new) {
+                    (None, _) => write!(f, "created\n{}", change.key)?,
+                    (Some(_), Some(_)) => write!(f, "updated\n{}", change.key)?,
+                    (Some(_), None) => write!(f, "deleted\n{}", change.key)?,
+                }
+                for value in change.old.iter().chain(&change.new) {
+                    write!(f, "\n{}", value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The last changes a learner applied, in log order.
+#[derive(Default)]
+pub(crate) struct ChangeLog {
+    changes: VecDeque<Change>,
+    /// Every change from this slot on is kept.
+    start: u64,
+}
+
+impl ChangeLog {
+    pub(crate) fn push(&mut self, change: Change) {
+        if self.changes.len() == WATCH_HISTORY {
+            if let Some(dropped) = self.changes.pop_front() {
+                // Other changes of its slot may still be kept, but not all
+                self.start = self.start.max(dropped.slot + 1);
+            }
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Forgets every change, as the changes before `slot` are unknown.
+    pub(crate) fn reset(&mut self, slot: u64) {
+        self.changes.clear();
+        self.start = slot;
+    }
+
+    /// The changes at `from` or later, or the first slot whose changes are
+    /// kept if that is after `from`.
+    pub(crate) fn since(&self, from: u64) -> Result<impl Iterator<Item = &Change>, u64> {
+        if from < self.start {
+            return Err(self.start);
+        }
+        let first = self.changes.partition_point(|change| change.slot < from);
+        Ok(self.changes.range(first..))
+    }
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::{cluster_config, start_cluster};
+use multi_decree_paxos::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Sends one line to `addr` and returns everything written back.
+fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn admin(config: &Config, id: usize, command: &str) -> String {
+    send(
+        config.nodes[id].admin_addr.unwrap(),
+        &format!("{}\n", command),
+    )
+}
+
+/// Asks every node until `wanted` holds for one's status, and returns its
+/// id.
+fn wait_for(config: &Config, wanted: impl Fn(usize, &str) -> bool) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        for id in 0..config.nodes.len() {
+            if wanted(id, &admin(config, id, "status")) {
+                return id;
+            }
+        }
+        sleep(Duration::from_millis(20));
+    }
+    panic!("no node reached the wanted status");
+}
+
+#[test]
+fn test_admin_command_format() {
+    assert_eq!(AdminCommand::parse("status"), Some(AdminCommand::Status));
+    assert_eq!(
+        AdminCommand::parse(" snapshot "),
+        Some(AdminCommand::Snapshot)
+    );
+    assert_eq!(
+        AdminCommand::parse("step-down"),
+        Some(AdminCommand::StepDown)
+    );
+    assert_eq!(
+        AdminCommand::parse("log"),
+        Some(AdminCommand::Log { from: None })
+    );
+    assert_eq!(
+        AdminCommand::parse("log 12"),
+        Some(AdminCommand::Log { from: Some(12) })
+    );
+    assert_eq!(AdminCommand::parse("kv"), Some(AdminCommand::Kv));
+    assert_eq!(AdminCommand::parse("log twelve"), None);
+    assert_eq!(AdminCommand::parse("status now"), None);
+    assert_eq!(AdminCommand::parse(""), None);
+}
+
+#[test]
+fn test_status_log_and_snapshot() {
+    let config = cluster_config(1);
+    start_cluster(&config);
+    let client_addr = config.nodes[0].client_addr;
+    assert_eq!(send(client_addr, "put\nb\n2"), "put successful!");
+    assert_eq!(send(client_addr, "put\na\n1"), "put successful!");
+
+    let status = admin(&config, 0, "status");
+    for line in [
+        "id: 0",
+        "role: leader",
+        "leader: 0",
+        "ballot: 1.0",
+        "promised: 1.0",
+        "accepted: 0..2",
+        "chosen: ..2",
+        "applied: ..2",
+        "snapshot: ..0",
+        "members: 0",
+        "keys: 2",
+        "peers up: ",
+    ] {
+        assert!(
+            status.lines().any(|l| l == line),
+            "{:?} in\n{}",
+            line,
+            status
+        );
+    }
+    assert_eq!(admin(&config, 0, "kv"), "a: 1\nb: 2\n");
+    assert_eq!(admin(&config, 0, "log 1"), "1 chosen put a 1\n");
+    assert_eq!(
+        admin(&config, 0, "log"),
+        "0 chosen put b 2\n1 chosen put a 1\n"
+    );
+
+    assert_eq!(
+        admin(&config, 0, "snapshot"),
+        "snapshot taken! up to slot:2\n"
+    );
+    assert_eq!(admin(&config, 0, "log"), "");
+    assert_eq!(admin(&config, 0, "log 0"), "..2 snapshot\n");
+    let status = admin(&config, 0, "status");
+    assert!(status.contains("\naccepted: 2..2\n"), "{}", status);
+    assert!(status.contains("\nsnapshot: ..2\n"), "{}", status);
+    // the store survives the snapshot
+    assert_eq!(send(client_addr, "get\na"), "get successful! value:1");
+
+    assert!(admin(&config, 0, "drop everything").starts_with("unknown command!"));
+}
+
+#[test]
+fn test_step_down_hands_over_leadership() {
+    let config = cluster_config(3);
+    start_cluster(&config);
+    let leader = wait_for(&config, |_, status| {
+        status.contains("\nrole: leader\n") && status.contains("\npeers down: \n")
+    });
+    let follower = (leader + 1) % 3;
+    assert_eq!(admin(&config, follower, "step-down"), "not leader!\n");
+
+    assert_eq!(admin(&config, leader, "step-down"), "stepped down!\n");
+    let successor = wait_for(&config, |id, status| {
+        id != leader && status.contains("\nrole: leader\n")
+    });
+    // the old leader follows its successor
+    wait_for(&config, |id, status| {
+        id == leader && status.contains(&format!("\nleader: {}\n", successor))
+    });
+}
+
+#[test]
+fn test_large_log_dump_arrives_whole() {
+    let config = cluster_config(1);
+    start_cluster(&config);
+    let value = "x".repeat(100_000);
+    for key in 0..40 {
+        let request = format!("put\n{}\n{}", key, value);
+        assert_eq!(
+            send(config.nodes[0].client_addr, &request),
+            "put successful!"
+        );
+    }
+    // the dump is far more than the socket takes before the reader wakes up
+    let mut stream = TcpStream::connect(config.nodes[0].admin_addr.unwrap()).unwrap();
+    stream.write_all(b"log\n").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    sleep(Duration::from_millis(200));
+    let mut log = String::new();
+    stream.read_to_string(&mut log).unwrap();
+    let expected: String = (0..40)
+        .map(|key| format!("{} chosen put {} {}\n", key, key, value))
+        .collect();
+    assert!(log == expected, "{} of {} bytes", log.len(), expected.len());
+}
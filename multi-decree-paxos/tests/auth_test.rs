@@ -0,0 +1,161 @@
+mod common;
+
+use common::{cluster_config, start_cluster};
+use multi_decree_paxos::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::spawn;
+use std::time::Duration;
+
+const SECRET: &str = "correct horse battery staple";
+
+fn secret_config(n: usize) -> Config {
+    Config {
+        secret: Some(SECRET.to_string()),
+        ..cluster_config(n)
+    }
+}
+
+/// Sends `request` to `addr` and returns everything written back.
+fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// Reads the next tagged frame from `stream`, checks its tag and decodes it.
+fn read_sealed(stream: &mut TcpStream, buf: &mut Vec<u8>, from: usize, to: usize) -> Message {
+    let key = PeerKey::new(SECRET.as_bytes());
+    loop {
+        if let Some(len) = Message::frame_len(buf) {
+            if buf.len() >= len + TAG_LEN {
+                let sealed: Vec<u8> = buf.drain(..len + TAG_LEN).collect();
+                assert!(key.verify(from, to, &sealed[..len], &sealed[len..]));
+                return Message::decode(&sealed[..len]).unwrap().0;
+            }
+        }
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "node closed the connection");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[test]
+fn test_tags_bind_sender_receiver_and_frame() {
+    let key = PeerKey::new(SECRET.as_bytes());
+    let frame = Message::CatchUp { slot: 3 }.encode();
+    let mut sealed = frame.clone();
+    key.seal(0, 1, &mut sealed);
+    assert_eq!(sealed.len(), frame.len() + TAG_LEN);
+    let tag = &sealed[frame.len()..];
+    assert!(key.verify(0, 1, &frame, tag));
+
+    assert!(!key.verify(2, 1, &frame, tag));
+    assert!(!key.verify(0, 2, &frame, tag));
+    assert!(!key.verify(0, 1, &Message::CatchUp { slot: 4 }.encode(), tag));
+    assert!(!key.verify(0, 1, &frame, &tag[1..]));
+    assert!(!PeerKey::new(b"guess").verify(0, 1, &frame, tag));
+}
+
+#[test]
+fn test_nodes_sharing_a_secret_serve_requests() {
+    let config = secret_config(3);
+    start_cluster(&config);
+    let client_addr = config.nodes[0].client_addr;
+    assert_eq!(send(client_addr, "put\nk\nv"), "put successful!");
+    assert_eq!(send(client_addr, "get\nk"), "get successful! value:v");
+    let metrics = send(
+        config.nodes[0].metrics_addr.unwrap(),
+        "GET /metrics HTTP/1.1\r\n\r\n",
+    );
+    assert!(metrics.contains("\npaxos_unauthenticated_frames_total 0\n"));
+}
+
+#[test]
+fn test_forged_frames_are_rejected_and_counted() {
+    // node 1 is played by the test
+    let config = secret_config(2);
+    let node1 = TcpListener::bind(config.nodes[1].peer_addr).unwrap();
+    let mut node = Node::bind(&config, 0).unwrap();
+    spawn(move || node.run().unwrap());
+    let (mut from_node0, _) = node1.accept().unwrap();
+    from_node0
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = vec![];
+    let mut handshake = [0; 4];
+    from_node0.read_exact(&mut handshake).unwrap();
+    assert_eq!(u32::from_be_bytes(handshake), 0);
+
+    // a PREPARE with the right tag is promised
+    let mut to_node0 = TcpStream::connect(config.nodes[0].peer_addr).unwrap();
+    to_node0
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    to_node0.write_all(&1u32.to_be_bytes()).unwrap();
+    let proposal_number = Ballot::new(5, 1);
+    let mut frame = Message::Prepare {
+        slot: 0,
+        proposal_number,
+    }
+    .encode();
+    PeerKey::new(SECRET.as_bytes()).seal(1, 0, &mut frame);
+    to_node0.write_all(&frame).unwrap();
+    loop {
+        if let Message::Promise {
+            proposal_number: promised,
+            ..
+        } = read_sealed(&mut from_node0, &mut buf, 0, 1)
+        {
+            assert_eq!(promised, proposal_number);
+            break;
+        }
+    }
+
+    // an ACCEPTED tagged with the wrong secret costs the connection, and
+    // the PREPARE right behind it is never read
+    let mut frames = Message::Accepted {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: Command::Noop,
+    }
+    .encode();
+    PeerKey::new(b"guess").seal(1, 0, &mut frames);
+    let mut frame = Message::Prepare {
+        slot: 0,
+        proposal_number: Ballot::new(1000, 1),
+    }
+    .encode();
+    PeerKey::new(SECRET.as_bytes()).seal(1, 0, &mut frame);
+    frames.extend(frame);
+    to_node0.write_all(&frames).unwrap();
+    assert!(matches!(to_node0.read(&mut [0; 1]), Ok(0) | Err(_)));
+
+    // so a lower ballot on a new connection is still promised
+    let mut to_node0 = TcpStream::connect(config.nodes[0].peer_addr).unwrap();
+    to_node0.write_all(&1u32.to_be_bytes()).unwrap();
+    let proposal_number = Ballot::new(999, 1);
+    let mut frame = Message::Prepare {
+        slot: 0,
+        proposal_number,
+    }
+    .encode();
+    PeerKey::new(SECRET.as_bytes()).seal(1, 0, &mut frame);
+    to_node0.write_all(&frame).unwrap();
+    loop {
+        let msg = read_sealed(&mut from_node0, &mut buf, 0, 1);
+        if msg.proposal_number() == Some(proposal_number) {
+            assert!(matches!(msg, Message::Promise { .. }), "{:?}", msg);
+            break;
+        }
+    }
+    let metrics = send(
+        config.nodes[0].metrics_addr.unwrap(),
+        "GET /metrics HTTP/1.1\r\n\r\n",
+    );
+    assert!(metrics.contains("\npaxos_unauthenticated_frames_total 1\n"));
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use common::{cluster_config, local_addr, start_cluster};
+use multi_decree_paxos::*;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+/// Plays a node that takes its time to answer every framed request with
+/// `put successful!`, and returns the client ids its requests came under.
+fn slow_node() -> (SocketAddr, Arc<Mutex<HashSet<u64>>>) {
+    let listener = TcpListener::bind(local_addr()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients = Arc::new(Mutex::new(HashSet::new()));
+    let seen = clients.clone();
+    spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let clients = clients.clone();
+            spawn(move || {
+                let mut head = [0; 1];
+                stream.read_exact(&mut head).unwrap();
+                let mut len = [0; 4];
+                while stream.read_exact(&mut len).is_ok() {
+                    let mut request = vec![0; u32::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    let request = String::from_utf8(request).unwrap();
+                    if let Some(Command::Session { client, .. }) =
+                        Command::from_client_request(&request)
+                    {
+                        clients.lock().unwrap().insert(client);
+                    }
+                    sleep(Duration::from_millis(20));
+                    let response = b"put successful!";
+                    stream
+                        .write_all(&(response.len() as u32).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(response).unwrap();
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+#[tokio::test]
+async fn test_typed_requests() {
+    let client = KvClient::new(start_cluster(&cluster_config(3)));
+    assert_eq!(client.get("k").await.unwrap(), None);
+    client.put("k", "v").await.unwrap();
+    assert_eq!(client.get("k").await.unwrap(), Some("v".to_string()));
+
+    assert_eq!(
+        client.cas("k", "x", "w").await.unwrap(),
+        Err(Some("v".to_string()))
+    );
+    assert_eq!(client.cas("k", "v", "w").await.unwrap(), Ok(()));
+    assert_eq!(client.cas("absent", "v", "w").await.unwrap(), Err(None));
+
+    assert!(client.delete("k").await.unwrap());
+    assert!(!client.delete("k").await.unwrap());
+    assert_eq!(
+        client.request("incr\nn\n5").await.unwrap(),
+        Outcome::Updated("5".to_string())
+    );
+    let invalid = client.request("incr").await.unwrap_err();
+    assert_eq!(invalid.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_requests_move_on_from_a_dead_node() {
+    // node 0 never leads, and nothing listens where the client looks for it
+    let mut nodes = start_cluster(&Config {
+        members: Some(vec![1, 2, 3]),
+        ..cluster_config(4)
+    });
+    nodes[0] = local_addr();
+    let mut client = KvClient::new(nodes.clone());
+    client.set_options(ClientOptions {
+        backoff_min: Duration::from_millis(1),
+        ..ClientOptions::default()
+    });
+    client.put("k", "v").await.unwrap();
+    let leader = client.leader();
+    assert_ne!(leader, nodes[0]);
+    assert_eq!(client.get("k").await.unwrap(), Some("v".to_string()));
+    assert_eq!(client.leader(), leader);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_the_client() {
+    let client = Arc::new(KvClient::new(start_cluster(&cluster_config(3))));
+    let tasks: Vec<_> = (0..20)
+        .map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move { client.request("incr\nn").await })
+        })
+        .collect();
+    let mut values = vec![];
+    for task in tasks {
+        match task.await.unwrap().unwrap() {
+            Outcome::Updated(value) => values.push(value.parse::<i64>().unwrap()),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+    values.sort();
+    // every increment took effect exactly once
+    assert_eq!(values, (1..=20).collect::<Vec<i64>>());
+}
+
+#[tokio::test]
+async fn test_requests_follow_the_leader_hint() {
+    // node 0 may not lead, so it sends the client elsewhere
+    let nodes = start_cluster(&Config {
+        members: Some(vec![1, 2]),
+        ..cluster_config(3)
+    });
+    let mut client = KvClient::new(nodes.clone());
+    client.set_options(ClientOptions {
+        backoff_min: Duration::from_millis(1),
+        ..ClientOptions::default()
+    });
+    client.put("k", "v").await.unwrap();
+    let leader = client.leader();
+    assert_ne!(leader, nodes[0]);
+
+    // once node 0 has heard from the leader, it names it
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut stream = std::net::TcpStream::connect(nodes[0]).unwrap();
+    stream.write_all(b"get\nk").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let id = nodes.iter().position(|&node| node == leader).unwrap();
+    assert_eq!(response, format!("not leader! leader:{}", id));
+
+    let client = KvClient::new(nodes.clone());
+    assert_eq!(client.get("k").await.unwrap(), Some("v".to_string()));
+    assert_eq!(client.leader(), leader);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_wait_for_a_session() {
+    let (node, clients) = slow_node();
+    let mut client = KvClient::new(vec![node]);
+    client.set_options(ClientOptions {
+        sessions: 2,
+        ..ClientOptions::default()
+    });
+    let client = Arc::new(client);
+    let tasks: Vec<_> = (0..10)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.put("k", &i.to_string()).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    assert_eq!(clients.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_zero_sessions_means_one() {
+    let (node, clients) = slow_node();
+    let mut client = KvClient::new(vec![node]);
+    client.set_options(ClientOptions {
+        sessions: 0,
+        ..ClientOptions::default()
+    });
+    let client = Arc::new(client);
+    let tasks: Vec<_> = (0..3)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.put("k", &i.to_string()).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    assert_eq!(clients.lock().unwrap().len(), 1);
+}
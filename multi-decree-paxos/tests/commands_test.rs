@@ -0,0 +1,160 @@
+use multi_decree_paxos::*;
+
+fn request(msg: &str) -> Command {
+    Command::from_client_request(msg).unwrap()
+}
+
+/// Chooses `command` at the learner's next slot and returns its outcome.
+fn apply(learner: &mut Learner, command: Command) -> Outcome {
+    let msg = Message::Accepted {
+        slot: learner.next_slot(),
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    learner.handle_msg(0, &msg);
+    let mut responses = learner.handle_msg(1, &msg);
+    assert_eq!(responses.len(), 1);
+    match responses.pop() {
+        Some(Message::Response { outcome, .. }) => outcome,
+        other => panic!("expected a RESPONSE, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_client_requests_parse_and_print() {
+    let requests = [
+        "get\nk",
+        "put\nk\nv",
+        "delete\nk",
+        "cas\nk\nold\nnew",
+        "append\nk\n suffix",
+        "incr\nk\n-3",
+        "scan\na\nc",
+        "scan\na\n",
+        "prefix\nk",
+        "acquire\nl\nowner\n500",
+        "renew\nl\nowner\n500",
+        "release\nl\nowner",
+    ];
+    for msg in requests {
+        assert_eq!(request(msg).to_client_request().unwrap(), msg);
+    }
+    assert_eq!(
+        request("INCR\nk"),
+        Command::Incr {
+            key: "k".to_string(),
+            by: 1
+        }
+    );
+    assert_eq!(
+        request("scan\na"),
+        Command::Scan {
+            start: "a".to_string(),
+            end: "".to_string()
+        }
+    );
+    assert_eq!(Command::from_client_request("incr\nk\none"), None);
+    assert_eq!(Command::from_client_request("cas\nk\nold"), None);
+    assert_eq!(Command::from_client_request("drop\nk"), None);
+    assert_eq!(Command::Noop.to_client_request(), None);
+    assert_eq!(Command::from_client_request("acquire\nl\nowner"), None);
+    assert_eq!(Command::from_client_request("tick\n100"), None);
+    assert_eq!(Command::Tick { millis: 100 }.to_client_request(), None);
+}
+
+#[test]
+fn test_responses_parse_and_print() {
+    let outcomes = [
+        Outcome::PutOk,
+        Outcome::Value("v".to_string()),
+        Outcome::NotFound,
+        Outcome::Deleted,
+        Outcome::Updated("12".to_string()),
+        Outcome::CasFailed(Some("other".to_string())),
+        Outcome::CasFailed(None),
+        Outcome::NotAnInteger,
+        Outcome::Entries(vec![]),
+        Outcome::Entries(vec![
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "".to_string()),
+        ]),
+        Outcome::Locked(12),
+        Outcome::LockHeld("owner".to_string()),
+        Outcome::Released,
+        Outcome::NotLockOwner,
+    ];
+    for outcome in outcomes {
+        assert_eq!(Outcome::from_response(&outcome.to_string()), Some(outcome));
+    }
+    assert_eq!(
+        Outcome::from_response("scan successful! count:2\na\n1"),
+        None
+    );
+    assert_eq!(Outcome::from_response("scan successful! count:0\na"), None);
+}
+
+#[test]
+fn test_learner_applies_commands() {
+    let mut learner = Learner::new();
+    learner.set_f(1);
+    let updated = |value: &str| Outcome::Updated(value.to_string());
+
+    assert_eq!(apply(&mut learner, request("delete\nk")), Outcome::NotFound);
+    assert_eq!(apply(&mut learner, request("put\nk\nv")), Outcome::PutOk);
+    assert_eq!(apply(&mut learner, request("delete\nk")), Outcome::Deleted);
+    assert_eq!(learner.get_value("k"), None);
+
+    assert_eq!(
+        apply(&mut learner, request("cas\nk\nold\nnew")),
+        Outcome::CasFailed(None)
+    );
+    assert_eq!(apply(&mut learner, request("put\nk\nold")), Outcome::PutOk);
+    assert_eq!(
+        apply(&mut learner, request("cas\nk\nold\nnew")),
+        updated("new")
+    );
+    assert_eq!(
+        apply(&mut learner, request("cas\nk\nold\nnewer")),
+        Outcome::CasFailed(Some("new".to_string()))
+    );
+
+    assert_eq!(
+        apply(&mut learner, request("append\nk\n!")),
+        updated("new!")
+    );
+    assert_eq!(apply(&mut learner, request("append\nl\nx")), updated("x"));
+
+    assert_eq!(apply(&mut learner, request("incr\nn")), updated("1"));
+    assert_eq!(apply(&mut learner, request("incr\nn\n-5")), updated("-4"));
+    assert_eq!(
+        apply(&mut learner, request("incr\nk")),
+        Outcome::NotAnInteger
+    );
+    assert_eq!(
+        apply(&mut learner, request(&format!("incr\nn\n{}", i64::MIN))),
+        Outcome::NotAnInteger
+    );
+    assert_eq!(learner.get_value("n"), Some("-4".to_string()));
+
+    let entries = |pairs: &[(&str, &str)]| {
+        Outcome::Entries(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    };
+    assert_eq!(
+        apply(&mut learner, request("scan\nk\nn")),
+        entries(&[("k", "new!"), ("l", "x")])
+    );
+    assert_eq!(
+        apply(&mut learner, request("scan\nl")),
+        entries(&[("l", "x"), ("n", "-4")])
+    );
+    assert_eq!(
+        apply(&mut learner, request("prefix\nk")),
+        entries(&[("k", "new!")])
+    );
+    assert_eq!(apply(&mut learner, request("prefix\nz")), entries(&[]));
+}
@@ -0,0 +1,66 @@
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+use multi_decree_paxos::*;
+use portpicker::pick_unused_port;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread::spawn;
+
+pub fn put(key: &str, value: &str) -> Command {
+    Command::Put {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// A fresh log path under the system temp directory.
+pub fn log_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("paxos-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+pub fn open(path: &Path) -> Box<dyn Storage> {
+    Box::new(FileStorage::open(path).unwrap())
+}
+
+pub fn local_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], pick_unused_port().unwrap()))
+}
+
+/// A cluster of `n` in-memory nodes on free local ports. Every node serves
+/// metrics and admin commands too.
+pub fn cluster_config(n: usize) -> Config {
+    Config {
+        nodes: (0..n)
+            .map(|id| NodeConfig {
+                metrics_addr: Some(local_addr()),
+                admin_addr: Some(local_addr()),
+                ..NodeConfig::new(id, local_addr(), local_addr())
+            })
+            .collect(),
+        ..Config::default()
+    }
+}
+
+/// Runs every node of `config` on a thread of its own and returns their
+/// client addresses.
+pub fn start_cluster(config: &Config) -> Vec<SocketAddr> {
+    start_cluster_with(config, || Box::new(KvStore::default()))
+}
+
+/// Like `start_cluster`, but each node replicates the state machine `state`
+/// makes.
+pub fn start_cluster_with(
+    config: &Config,
+    state: impl Fn() -> Box<dyn StateMachine>,
+) -> Vec<SocketAddr> {
+    for node in &config.nodes {
+        let mut node = Node::bind_with(config, node.id, state()).unwrap();
+        spawn(move || node.run().unwrap());
+    }
+    config.nodes.iter().map(|node| node.client_addr).collect()
+}
@@ -0,0 +1,84 @@
+use multi_decree_paxos::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const TOML: &str = r#"
+data_dir = "/tmp/paxos"
+
+[[nodes]]
+id = 0
+peer_addr = "127.0.0.1:9000"
+client_addr = "127.0.0.1:8000"
+
+[[nodes]]
+id = 1
+peer_addr = "127.0.0.1:9001"
+client_addr = "127.0.0.1:8001"
+"#;
+
+#[test]
+fn test_toml_and_json_configs_agree() {
+    let toml = Config::from_toml(TOML).unwrap();
+    let json = Config::from_json(
+        r#"{
+            "data_dir": "/tmp/paxos",
+            "nodes": [
+                {"id": 1, "peer_addr": "127.0.0.1:9001", "client_addr": "127.0.0.1:8001"},
+                {"id": 0, "peer_addr": "127.0.0.1:9000", "client_addr": "127.0.0.1:8000"}
+            ]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(toml.node(1), json.node(1));
+    assert_eq!(toml.node(0).unwrap().client_addr.port(), 8000);
+    assert_eq!(toml.node(2), None);
+    assert_eq!(toml.node_dir(1), Some(PathBuf::from("/tmp/paxos/node-1")));
+}
+
+#[test]
+fn test_invalid_configs_are_rejected() {
+    // ids must be 0..n with no duplicates
+    assert!(Config::from_toml(&TOML.replace("id = 1", "id = 0")).is_err());
+    assert!(Config::from_toml(&TOML.replace("id = 1", "id = 2")).is_err());
+    assert!(Config::from_toml("nodes = []").is_err());
+    assert!(Config::from_toml(&TOML.replace("127.0.0.1:9001", "not an address")).is_err());
+    assert!(Config::from_toml(&TOML.replace("data_dir", "data_directory")).is_err());
+}
+
+#[test]
+fn test_timeouts_default_and_override() {
+    assert_eq!(
+        Config::from_toml(TOML).unwrap().timeouts,
+        Timeouts::default()
+    );
+
+    let config =
+        Config::from_toml(&format!("{}\n[timeouts]\nprepare_timeout_ms = 50\n", TOML)).unwrap();
+    assert_eq!(config.timeouts.prepare_timeout, Duration::from_millis(50));
+    assert_eq!(config.timeouts.accept_timeout, ACCEPT_TIMEOUT);
+    assert!(Config::from_toml(&format!("{}\n[timeouts]\nprepare = 50\n", TOML)).is_err());
+}
+
+#[test]
+fn test_pipeline_default_and_override() {
+    assert_eq!(
+        Config::from_toml(TOML).unwrap().pipeline,
+        Pipeline::default()
+    );
+
+    let config = Config::from_toml(&format!("{}\n[pipeline]\nwindow = 1\n", TOML)).unwrap();
+    assert_eq!(config.pipeline.window, 1);
+    assert_eq!(config.pipeline.max_batch, MAX_BATCH);
+    assert!(Config::from_toml(&format!("{}\n[pipeline]\nwindow = 0\n", TOML)).is_err());
+}
+
+#[test]
+fn test_initial_members() {
+    assert_eq!(Config::from_toml(TOML).unwrap().members, None);
+
+    let members = |list: &str| Config::from_toml(&format!("members = {}\n{}", list, TOML));
+    assert_eq!(members("[1]").unwrap().members, Some(vec![1]));
+    assert!(members("[]").is_err());
+    assert!(members("[0, 0]").is_err());
+    assert!(members("[0, 2]").is_err());
+}
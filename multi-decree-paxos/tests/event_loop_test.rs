@@ -0,0 +1,198 @@
+mod common;
+
+use common::{cluster_config, start_cluster};
+use multi_decree_paxos::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+/// Reads frames from `stream` until one matches `wanted`.
+fn read_until(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    wanted: impl Fn(&Message) -> bool,
+) -> Message {
+    loop {
+        while let Ok((msg, len)) = Message::decode(buf) {
+            buf.drain(..len);
+            if wanted(&msg) {
+                return msg;
+            }
+        }
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "node closed the connection");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[test]
+fn test_split_coalesced_and_large_frames() {
+    // node 0 is real, node 1 is played by the test
+    let config = cluster_config(2);
+    let fake = TcpListener::bind(config.nodes[1].peer_addr).unwrap();
+    let mut node = Node::bind(&config, 0).unwrap();
+    spawn(move || node.run().unwrap());
+
+    let (mut from_node, _) = fake.accept().unwrap();
+    from_node
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut handshake = [0; 4];
+    from_node.read_exact(&mut handshake).unwrap();
+    assert_eq!(u32::from_be_bytes(handshake), 0);
+
+    let mut to_node = TcpStream::connect(config.nodes[0].peer_addr).unwrap();
+    to_node.set_nodelay(true).unwrap();
+    to_node.write_all(&1u32.to_be_bytes()).unwrap();
+
+    // a PREPARE trickling in one byte at a time
+    let ballot = Ballot::new(10, 1);
+    let prepare = Message::Prepare {
+        slot: 0,
+        proposal_number: ballot,
+    };
+    for byte in prepare.encode() {
+        to_node.write_all(&[byte]).unwrap();
+        sleep(Duration::from_millis(2));
+    }
+    let mut buf = vec![];
+    let promise = read_until(&mut from_node, &mut buf, |msg| {
+        matches!(msg, Message::Promise { .. })
+    });
+    assert_eq!(promise.proposal_number(), Some(ballot));
+
+    // a frame far larger than one read, sent together with a small one
+    let big = Command::Put {
+        key: "big".to_string(),
+        value: "x".repeat(100_000),
+    };
+    let mut frames = vec![];
+    for (slot, command) in [(0, big.clone()), (1, Command::Noop)] {
+        frames.extend(
+            Message::Accept {
+                slot,
+                proposal_number: ballot,
+                command,
+            }
+            .encode(),
+        );
+    }
+    to_node.write_all(&frames).unwrap();
+    for (slot, command) in [(0, big), (1, Command::Noop)] {
+        let accepted = read_until(&mut from_node, &mut buf, |msg| {
+            matches!(msg, Message::Accepted { .. })
+        });
+        assert_eq!(
+            accepted,
+            Message::Accepted {
+                slot,
+                proposal_number: ballot,
+                command,
+            }
+        );
+    }
+}
+
+#[test]
+fn test_frames_followed_by_eof_are_delivered() {
+    // node 0 is real, node 1 is played by the test
+    let config = cluster_config(2);
+    let fake = TcpListener::bind(config.nodes[1].peer_addr).unwrap();
+    let mut node = Node::bind(&config, 0).unwrap();
+
+    // the handshake, a PREPARE and EOF are all waiting by the time node 0
+    // first reads the connection
+    let ballot = Ballot::new(10, 1);
+    let mut bytes = 1u32.to_be_bytes().to_vec();
+    bytes.extend(
+        Message::Prepare {
+            slot: 0,
+            proposal_number: ballot,
+        }
+        .encode(),
+    );
+    let mut to_node = TcpStream::connect(config.nodes[0].peer_addr).unwrap();
+    to_node.write_all(&bytes).unwrap();
+    drop(to_node);
+    spawn(move || node.run().unwrap());
+
+    let (mut from_node, _) = fake.accept().unwrap();
+    from_node
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    from_node.read_exact(&mut [0; 4]).unwrap();
+    let mut buf = vec![];
+    let promise = read_until(&mut from_node, &mut buf, |msg| {
+        matches!(msg, Message::Promise { .. })
+    });
+    assert_eq!(promise.proposal_number(), Some(ballot));
+}
+
+#[test]
+fn test_overlong_frames_drop_the_connection() {
+    let config = cluster_config(1);
+    let mut node = Node::bind(&config, 0).unwrap();
+    spawn(move || node.run().unwrap());
+    let closed = |stream: &mut TcpStream| {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        matches!(stream.read(&mut [0; 1]), Ok(0) | Err(_))
+    };
+
+    // a peer announcing a frame longer than MAX_FRAME_LEN
+    let mut peer = TcpStream::connect(config.nodes[0].peer_addr).unwrap();
+    peer.write_all(&1u32.to_be_bytes()).unwrap();
+    let mut header = vec![PROTOCOL_VERSION, 1];
+    header.extend((MAX_FRAME_LEN as u32).to_be_bytes());
+    peer.write_all(&header).unwrap();
+    assert!(closed(&mut peer));
+
+    // a framed client doing the same
+    let mut client = TcpStream::connect(config.nodes[0].client_addr).unwrap();
+    client.write_all(&[0]).unwrap();
+    client
+        .write_all(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes())
+        .unwrap();
+    assert!(closed(&mut client));
+}
+
+#[test]
+fn test_large_responses_are_written_whole() {
+    let config = cluster_config(1);
+    let client_addr = start_cluster(&config)[0];
+    let value = "x".repeat(4 << 20);
+    let request = |request: &str| {
+        let mut stream = TcpStream::connect(client_addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        // far more than the socket buffers while nobody reads
+        sleep(Duration::from_millis(200));
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    assert_eq!(request(&format!("put\nk\n{}", value)), "put successful!");
+    assert_eq!(
+        request("get\nk"),
+        format!("get successful! value:{}", value)
+    );
+
+    let mut stream = TcpStream::connect(client_addr).unwrap();
+    stream.write_all(&[0]).unwrap();
+    for _ in 0..2 {
+        stream.write_all(&5u32.to_be_bytes()).unwrap();
+        stream.write_all(b"get\nk").unwrap();
+        sleep(Duration::from_millis(200));
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut response = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(
+            response,
+            format!("get successful! value:{}", value).as_bytes()
+        );
+    }
+}
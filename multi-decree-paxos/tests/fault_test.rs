@@ -0,0 +1,198 @@
+mod common;
+
+use common::cluster_config;
+use multi_decree_paxos::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+/// Starts `n` in-memory nodes that reach each other through a faulty
+/// network, and tag their frames with a shared secret.
+fn start_faulty_cluster(n: usize, seed: u64) -> (Config, FaultyNetwork) {
+    let config = Config {
+        secret: Some("secret".to_string()),
+        ..cluster_config(n)
+    };
+    let network = FaultyNetwork::start(&config, seed).unwrap();
+    for id in 0..n {
+        let mut node = Node::bind(&network.config(id), id).unwrap();
+        spawn(move || node.run().unwrap());
+    }
+    (config, network)
+}
+
+/// A client that gives up on a node quickly, as the tests cut nodes off.
+fn client(config: &Config) -> KvClient {
+    let nodes = config.nodes.iter().map(|node| node.client_addr).collect();
+    let mut client = KvClient::new(nodes);
+    client.set_options(ClientOptions {
+        timeout: Duration::from_millis(500),
+        retries: 40,
+        backoff_min: Duration::from_millis(10),
+        backoff_max: Duration::from_millis(200),
+        ..ClientOptions::default()
+    });
+    client
+}
+
+fn admin(config: &Config, id: usize, command: &str) -> String {
+    let mut stream = TcpStream::connect(config.nodes[id].admin_addr.unwrap()).unwrap();
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// Waits until `wanted` holds for the admin status of some node in `ids`,
+/// and returns that node.
+fn wait_for(config: &Config, ids: &[usize], wanted: impl Fn(&str) -> bool) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while Instant::now() < deadline {
+        for &id in ids {
+            if wanted(&admin(config, id, "status")) {
+                return id;
+            }
+        }
+        sleep(Duration::from_millis(20));
+    }
+    panic!("no node in {:?} reached the wanted status", ids);
+}
+
+/// Reads frames from `stream` until `count` have arrived.
+fn read_frames(stream: &mut TcpStream, count: usize) -> Vec<Message> {
+    let mut buf = vec![];
+    let mut msgs = vec![];
+    while msgs.len() < count {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "proxy closed the connection");
+        buf.extend_from_slice(&chunk[..n]);
+        while let Ok((msg, len)) = Message::decode(&buf) {
+            buf.drain(..len);
+            msgs.push(msg);
+        }
+    }
+    msgs
+}
+
+#[test]
+fn test_links_drop_delay_and_duplicate_frames() {
+    // both nodes are played by the test
+    let config = cluster_config(2);
+    let node1 = TcpListener::bind(config.nodes[1].peer_addr).unwrap();
+    let network = FaultyNetwork::start(&config, 7).unwrap();
+    let heartbeat = |slot| Message::Heartbeat {
+        slot,
+        proposal_number: Ballot::new(1, 0),
+    };
+
+    let mut node0 = TcpStream::connect(network.config(0).nodes[1].peer_addr).unwrap();
+    node0.write_all(&0u32.to_be_bytes()).unwrap();
+    let (mut link, _) = node1.accept().unwrap();
+    link.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut handshake = [0; 4];
+    link.read_exact(&mut handshake).unwrap();
+    assert_eq!(u32::from_be_bytes(handshake), 0);
+
+    network.set_faults(
+        0,
+        1,
+        LinkFaults {
+            duplicate: 1.0,
+            ..LinkFaults::default()
+        },
+    );
+    node0.write_all(&heartbeat(1).encode()).unwrap();
+    assert_eq!(read_frames(&mut link, 2), vec![heartbeat(1), heartbeat(1)]);
+
+    // frames sent while partitioned are lost, frames sent after are not
+    network.partition(&[&[0], &[1]]);
+    assert!(network.faults(0, 1).partitioned);
+    node0.write_all(&heartbeat(2).encode()).unwrap();
+    sleep(Duration::from_millis(50));
+    network.heal();
+    let delay = Duration::from_millis(200);
+    network.set_faults(
+        0,
+        1,
+        LinkFaults {
+            delay,
+            ..LinkFaults::default()
+        },
+    );
+    let sent = Instant::now();
+    let mut frames = heartbeat(3).encode();
+    frames.extend(heartbeat(4).encode());
+    node0.write_all(&frames).unwrap();
+    assert_eq!(read_frames(&mut link, 2), vec![heartbeat(3), heartbeat(4)]);
+    assert!(sent.elapsed() >= delay);
+
+    network.set_faults(
+        0,
+        1,
+        LinkFaults {
+            drop: 1.0,
+            ..LinkFaults::default()
+        },
+    );
+    node0.write_all(&heartbeat(5).encode()).unwrap();
+    sleep(Duration::from_millis(50));
+    network.set_faults(0, 1, LinkFaults::default());
+    node0.write_all(&heartbeat(6).encode()).unwrap();
+    assert_eq!(read_frames(&mut link, 1), vec![heartbeat(6)]);
+}
+
+#[tokio::test]
+async fn test_isolated_leader_is_replaced() {
+    let (config, network) = start_faulty_cluster(3, 1);
+    let client = client(&config);
+    client.put("k", "before").await.unwrap();
+    let all = [0, 1, 2];
+    let leader = wait_for(&config, &all, |status| status.contains("\nrole: leader\n"));
+
+    // isolate the leader for 3s
+    network.isolate(leader);
+    let others: Vec<usize> = all.into_iter().filter(|&id| id != leader).collect();
+    wait_for(&config, &others, |status| {
+        status.contains("\nrole: leader\n")
+    });
+    client.put("k", "during").await.unwrap();
+    assert_eq!(client.get("k").await.unwrap(), Some("during".to_string()));
+    sleep(Duration::from_secs(3));
+    network.heal();
+
+    // the old leader follows another node and catches up
+    wait_for(&config, &[leader], |status| {
+        status.contains("\nrole: follower\n")
+            && !status.contains(&format!("\nleader: {}\n", leader))
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while admin(&config, leader, "kv") != "k: during\n" {
+        assert!(
+            Instant::now() < deadline,
+            "node {} did not catch up",
+            leader
+        );
+        sleep(Duration::from_millis(50));
+    }
+}
+
+#[tokio::test]
+async fn test_lossy_links_apply_requests_once() {
+    let (config, network) = start_faulty_cluster(3, 2);
+    network.set_all(LinkFaults {
+        drop: 0.1,
+        duplicate: 0.2,
+        delay: Duration::from_millis(2),
+        partitioned: false,
+    });
+    let client = client(&config);
+    for _ in 0..20 {
+        client.request("incr\nn").await.unwrap();
+    }
+    assert_eq!(client.get("n").await.unwrap(), Some("20".to_string()));
+}
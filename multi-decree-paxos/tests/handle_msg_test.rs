@@ -1,3 +1,6 @@
+mod common;
+
+use common::put;
 use multi_decree_paxos::*;
 
 #[test]
@@ -8,39 +11,48 @@ fn test_proposer() {
 
     // test msg from client
     let msg = "put\nhello\nworld";
-    if let Some(receive_msg) = proposer.send_prepare(msg) {
-        let msg = format!("{} {}", char::from(MsgType::PREPARE as u8), 1);
-        assert_eq!(msg, receive_msg);
-    } else {
-        assert!(false);
-    }
-
-    let msg = format!("{} {}", 1, 0);
+    assert_eq!(
+        proposer.propose(7, msg),
+        Some(vec![Message::Prepare {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0)
+        }])
+    );
+
+    let msg = Message::Promise {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        log_start: 0,
+        accepted: vec![],
+    };
 
     // test promise
     // 1st promise, not achieve quorum
-    assert!(proposer.handle_msg(&MsgType::PROMISE, &msg).is_none());
+    assert!(proposer.handle_msg(0, &msg).is_empty());
+    // a duplicate from the same acceptor does not count twice
+    assert!(proposer.handle_msg(0, &msg).is_empty());
 
     // 2nd promise, achieve quorum
-    if let Some(receive_msg) = proposer.handle_msg(&MsgType::PROMISE, &msg) {
-        let msg = format!(
-            "{} {} {} {}",
-            char::from(MsgType::ACCEPT as u8),
-            1,
-            "hello",
-            "world"
-        );
-        assert_eq!(msg, receive_msg);
-    } else {
-        assert!(false);
-    }
+    assert_eq!(
+        proposer.handle_msg(1, &msg),
+        vec![Message::Accept {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+            command: put("hello", "world"),
+        }]
+    );
+    assert!(proposer.is_leader());
+
     // test response
-    let msg = format!("{} {}", 1, 0);
-    if let Some(receive_msg) = proposer.handle_msg(&MsgType::RESPONSE, &msg) {
-        assert_eq!(receive_msg, "put successful!".to_string());
-    } else {
-        assert!(false);
-    }
+    let msg = Message::Response {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: put("hello", "world"),
+        outcome: Outcome::PutOk,
+    };
+    assert!(proposer.handle_msg(0, &msg).is_empty());
+    assert_eq!(proposer.take_responses(), vec![(7, 0, Outcome::PutOk)]);
+    assert!(proposer.is_idle());
 }
 
 #[test]
@@ -48,42 +60,60 @@ fn test_acceptor() {
     let mut acceptor = Acceptor::new();
 
     // test prepare
-    let msg = format!("{}", 1);
-    if let Some(receive_msg) = acceptor.handle_msg(&MsgType::PREPARE, &msg) {
-        let msg = format!("{} {} {}", char::from(MsgType::PROMISE as u8), 1, 0);
-        assert_eq!(msg, receive_msg);
-    } else {
-        assert!(false);
-    }
+    let msg = Message::Prepare {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+    };
+    assert_eq!(
+        acceptor.handle_msg(0, &msg),
+        vec![Message::Promise {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+            log_start: 0,
+            accepted: vec![],
+        }]
+    );
 
     // test accept
-    let msg = format!("{} {} {}", 1, "hello", "world");
-    if let Some(receive_msg) = acceptor.handle_msg(&MsgType::ACCEPT, &msg) {
-        let msg = format!(
-            "{} {} {} {}",
-            char::from(MsgType::ACCEPTED as u8),
-            1,
-            "hello",
-            "world"
-        );
-        assert_eq!(msg, receive_msg);
-    } else {
-        assert!(false);
-    }
+    let msg = Message::Accept {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: put("hello", "world"),
+    };
+    assert_eq!(
+        acceptor.handle_msg(0, &msg),
+        vec![Message::Accepted {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+            command: put("hello", "world"),
+        }]
+    );
+    assert_eq!(acceptor.next_slot(), 1);
 }
 
 #[test]
 fn test_learner() {
     let mut learner = Learner::new();
+    learner.set_f(1);
 
     // test accepted
-    let msg = format!("{} {} {}", 1, "hello", "world");
-    if let Some(receive_msg) = learner.handle_msg(&MsgType::ACCEPTED, &msg) {
-        let msg = format!("{} {} {}", char::from(MsgType::RESPONSE as u8), 1, 0);
-        assert_eq!(msg, receive_msg);
-    } else {
-        assert!(false);
-    }
+    let msg = Message::Accepted {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: put("hello", "world"),
+    };
+    // one vote is not a quorum
+    assert!(learner.handle_msg(0, &msg).is_empty());
+    assert_eq!(
+        learner.handle_msg(1, &msg),
+        vec![Message::Response {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+            command: put("hello", "world"),
+            outcome: Outcome::PutOk,
+        }]
+    );
+    assert_eq!(learner.get_value("hello"), Some("world".to_string()));
 }
 
 #[test]
@@ -91,33 +121,34 @@ fn test_paxos() {
     let mut proposer = Proposer::new();
     // set f
     proposer.set_f(1);
-    let mut acceptor = Acceptor::new();
+    let mut acceptors = [Acceptor::new(), Acceptor::new()];
     let mut learner = Learner::new();
+    learner.set_f(1);
+    // every hop goes through the wire format
+    let wire = |msg: &Message| Message::decode(&msg.encode()).unwrap().0;
+
     // test msg from client
     let msg = "get\nhello";
-    let prepare_msg = proposer.send_prepare(msg).unwrap();
-
-    let promise_msg = acceptor
-        .handle_msg(&MsgType::PREPARE, &prepare_msg[1..])
-        .unwrap();
-
-    proposer.handle_msg(&MsgType::PROMISE, &promise_msg[1..]);
+    let prepare_msg = wire(&proposer.propose(7, msg).unwrap()[0]);
 
-    let accept_msg = proposer
-        .handle_msg(&MsgType::PROMISE, &promise_msg[1..])
-        .unwrap();
-
-    let accepted_msg = acceptor
-        .handle_msg(&MsgType::ACCEPT, &accept_msg[1..])
-        .unwrap();
-
-    let response_msg = learner
-        .handle_msg(&MsgType::ACCEPTED, &accepted_msg[1..])
-        .unwrap();
+    let mut accept_msgs = vec![];
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        let promise_msg = wire(&acceptor.handle_msg(0, &prepare_msg)[0]);
+        accept_msgs.extend(proposer.handle_msg(i, &promise_msg));
+    }
+    let accept_msg = wire(&accept_msgs[0]);
 
-    let send_msg = proposer
-        .handle_msg(&MsgType::RESPONSE, &response_msg[1..])
-        .unwrap();
+    let mut response_msgs = vec![];
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        let accepted_msg = wire(&acceptor.handle_msg(0, &accept_msg)[0]);
+        proposer.handle_msg(i, &accepted_msg);
+        response_msgs.extend(learner.handle_msg(i, &accepted_msg));
+    }
+    let response_msg = wire(&response_msgs[0]);
 
-    assert_eq!(send_msg, "get failed!".to_string());
+    proposer.handle_msg(0, &response_msg);
+    let [(7, 0, outcome)] = &proposer.take_responses()[..] else {
+        panic!("proposer ignored its own response");
+    };
+    assert_eq!(outcome.to_string(), "get failed!".to_string());
 }
@@ -0,0 +1,171 @@
+mod common;
+
+use common::put;
+use multi_decree_paxos::*;
+use std::time::Instant;
+
+/// Runs phase 1 for `proposer` against `acceptors`, returning the messages
+/// the proposer sends once it has a quorum.
+fn elect(proposer: &mut Proposer, acceptors: &mut [Acceptor]) -> Vec<Message> {
+    let prepare = proposer.send_prepare();
+    let mut msgs = vec![];
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        for promise in acceptor.handle_msg(0, &prepare) {
+            msgs.extend(proposer.handle_msg(i, &promise));
+        }
+    }
+    msgs
+}
+
+#[test]
+fn test_leader_skips_phase_one() {
+    let mut acceptors = [Acceptor::new(), Acceptor::new(), Acceptor::new()];
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    assert!(elect(&mut proposer, &mut acceptors).is_empty());
+    assert!(proposer.is_leader());
+
+    // every command goes straight to ACCEPT, each in its own slot
+    for (slot, value) in ["a", "b", "c"].iter().enumerate() {
+        let msgs = proposer.propose(0, &format!("put\nk\n{}", value)).unwrap();
+        assert_eq!(
+            msgs,
+            vec![Message::Accept {
+                slot: slot as u64,
+                proposal_number: Ballot::new(1, 0),
+                command: put("k", value),
+            }]
+        );
+        for acceptor in acceptors.iter_mut() {
+            assert!(matches!(
+                acceptor.handle_msg(0, &msgs[0])[..],
+                [Message::Accepted { .. }]
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_leader_sends_heartbeats() {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    let mut acceptors = [Acceptor::new(), Acceptor::new()];
+    elect(&mut proposer, &mut acceptors);
+
+    let start = Instant::now();
+    let later = start + HEARTBEAT_INTERVAL;
+    assert_eq!(
+        proposer.tick(later),
+        vec![Message::Heartbeat {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+        }]
+    );
+    assert!(proposer.tick(later).is_empty());
+}
+
+#[test]
+fn test_follower_elects_itself_when_leader_is_silent() {
+    let start = Instant::now();
+    let mut follower = Proposer::new();
+    follower.set_f(1);
+    follower.set_id(1);
+    follower.tick(start);
+
+    // heartbeats from the leader keep the follower quiet
+    let heartbeat = Message::Heartbeat {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+    };
+    let mut now = start;
+    for _ in 0..20 {
+        now += HEARTBEAT_INTERVAL;
+        assert!(follower.tick(now).is_empty());
+        follower.handle_msg(0, &heartbeat);
+    }
+
+    // once they stop, the follower runs for leader with a higher number
+    now += ELECTION_TIMEOUT + HEARTBEAT_INTERVAL * 2;
+    assert_eq!(
+        follower.tick(now),
+        vec![Message::Prepare {
+            slot: 0,
+            proposal_number: Ballot::new(2, 1),
+        }]
+    );
+}
+
+#[test]
+fn test_ballots_are_unique_and_ordered() {
+    let mut proposers: Vec<Proposer> = (0..3)
+        .map(|id| {
+            let mut proposer = Proposer::new();
+            proposer.set_id(id);
+            proposer
+        })
+        .collect();
+
+    // proposers that have seen the same ballot never pick the same next one
+    let ballots: Vec<Ballot> = proposers
+        .iter_mut()
+        .map(|proposer| proposer.send_prepare().proposal_number().unwrap())
+        .collect();
+    assert_eq!(
+        ballots,
+        vec![Ballot::new(1, 0), Ballot::new(1, 1), Ballot::new(1, 2)]
+    );
+    assert!(ballots[0] < ballots[1] && ballots[1] < ballots[2]);
+
+    // an acceptor that promised one of them refuses the others
+    let mut acceptor = Acceptor::new();
+    let prepare = |ballot| Message::Prepare {
+        slot: 0,
+        proposal_number: ballot,
+    };
+    assert!(matches!(
+        acceptor.handle_msg(1, &prepare(ballots[1]))[..],
+        [Message::Promise { .. }]
+    ));
+    assert!(matches!(
+        acceptor.handle_msg(0, &prepare(ballots[0]))[..],
+        [Message::Nack { .. }]
+    ));
+
+    // well past the old u8 limit
+    let mut proposer = Proposer::new();
+    proposer.set_id(2);
+    for round in 1..=300u64 {
+        assert_eq!(
+            proposer.send_prepare().proposal_number(),
+            Some(Ballot::new(round, 2))
+        );
+    }
+}
+
+#[test]
+fn test_preempted_leader_steps_down() {
+    let mut acceptors = [Acceptor::new(), Acceptor::new(), Acceptor::new()];
+    let mut old = Proposer::new();
+    old.set_f(1);
+    elect(&mut old, &mut acceptors);
+
+    let mut new = Proposer::new();
+    new.set_f(1);
+    new.set_id(1);
+    new.handle_msg(
+        0,
+        &Message::Heartbeat {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+        },
+    );
+    elect(&mut new, &mut acceptors);
+    assert!(new.is_leader());
+
+    // the old leader's ACCEPT is refused and it stops leading
+    let accept = old.propose(0, "put\nk\nstale").unwrap().remove(0);
+    let refusal = acceptors[0].handle_msg(0, &accept).remove(0);
+    assert!(matches!(refusal, Message::Unaccepted { .. }));
+    old.handle_msg(0, &refusal);
+    assert!(!old.is_leader());
+}
@@ -0,0 +1,170 @@
+use multi_decree_paxos::*;
+use std::time::Duration;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn put(process: usize, key: &str, value: &str, invoked: u64, completed: Option<u64>) -> Operation {
+    Operation {
+        process,
+        command: Command::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+        },
+        invoked: ms(invoked),
+        completed: completed.map(|at| (ms(at), Outcome::PutOk)),
+    }
+}
+
+fn get(process: usize, key: &str, read: Option<&str>, invoked: u64, completed: u64) -> Operation {
+    let outcome = match read {
+        Some(value) => Outcome::Value(value.to_string()),
+        None => Outcome::NotFound,
+    };
+    Operation {
+        process,
+        command: Command::Get {
+            key: key.to_string(),
+        },
+        invoked: ms(invoked),
+        completed: Some((ms(completed), outcome)),
+    }
+}
+
+#[test]
+fn test_concurrent_operations_may_take_effect_in_either_order() {
+    // the get overlaps both puts, so it may see either value or none
+    for read in [None, Some("1"), Some("2")] {
+        let history = vec![
+            put(0, "a", "1", 0, Some(10)),
+            put(1, "a", "2", 5, Some(20)),
+            get(2, "a", read, 1, 30),
+        ];
+        assert_eq!(check_linearizable(&history), Ok(()));
+    }
+    // once both puts are done only the later one can be read
+    let history = vec![
+        put(0, "a", "1", 0, Some(10)),
+        put(1, "a", "2", 5, Some(20)),
+        get(2, "a", Some("2"), 25, 30),
+        get(2, "b", None, 31, 32),
+    ];
+    assert_eq!(check_linearizable(&history), Ok(()));
+}
+
+#[test]
+fn test_unanswered_put_may_or_may_not_take_effect() {
+    for read in [None, Some("1")] {
+        let history = vec![put(0, "a", "1", 0, None), get(1, "a", read, 50, 60)];
+        assert_eq!(check_linearizable(&history), Ok(()));
+    }
+    // but not before it was sent
+    let history = vec![get(1, "a", Some("1"), 0, 10), put(0, "a", "1", 20, None)];
+    assert!(check_linearizable(&history).is_err());
+}
+
+#[test]
+fn test_expired_put_may_or_may_not_take_effect() {
+    let mut expired = put(0, "a", "1", 0, Some(10));
+    expired.completed = Some((ms(10), Outcome::Expired));
+    for read in [None, Some("1")] {
+        let history = vec![expired.clone(), get(1, "a", read, 50, 60)];
+        assert_eq!(check_linearizable(&history), Ok(()));
+    }
+    // but not before it was sent
+    expired.invoked = ms(20);
+    expired.completed = Some((ms(30), Outcome::Expired));
+    let history = vec![get(1, "a", Some("1"), 0, 10), expired];
+    assert!(check_linearizable(&history).is_err());
+}
+
+#[test]
+fn test_stale_read_reports_minimal_sub_history() {
+    let history = vec![
+        put(0, "a", "1", 0, Some(10)),
+        put(1, "b", "x", 2, Some(12)),
+        get(2, "a", Some("1"), 11, 13),
+        put(0, "a", "2", 20, Some(30)),
+        get(1, "b", Some("x"), 21, 25),
+        put(2, "a", "3", 22, None),
+        get(1, "a", Some("1"), 40, 50),
+    ];
+    let error = check_linearizable(&history).unwrap_err();
+    assert_eq!(error.key, "a");
+    assert_eq!(
+        error.history,
+        vec![history[0].clone(), history[3].clone(), history[6].clone()]
+    );
+}
+
+#[test]
+fn test_minimal_sub_history_drops_writes_nobody_reads() {
+    // reading "0" is wrong on its own; the later reads first keep their
+    // writes around and must not end up in the report
+    let history = vec![
+        get(0, "a", Some("0"), 0, 5),
+        put(1, "a", "1", 10, Some(20)),
+        get(0, "a", Some("1"), 30, 40),
+        put(1, "a", "2", 50, Some(60)),
+        get(0, "a", Some("2"), 70, 80),
+    ];
+    let error = check_linearizable(&history).unwrap_err();
+    assert_eq!(error.history, vec![history[0].clone()]);
+}
+
+#[test]
+fn test_only_one_delete_finds_the_value() {
+    let delete = |process, invoked, completed, outcome| Operation {
+        process,
+        command: Command::Delete {
+            key: "a".to_string(),
+        },
+        invoked: ms(invoked),
+        completed: Some((ms(completed), outcome)),
+    };
+    let history = vec![
+        put(0, "a", "1", 0, Some(10)),
+        delete(1, 20, 40, Outcome::Deleted),
+        delete(2, 25, 35, Outcome::NotFound),
+    ];
+    assert_eq!(check_linearizable(&history), Ok(()));
+    let history = vec![
+        put(0, "a", "1", 0, Some(10)),
+        delete(1, 20, 40, Outcome::Deleted),
+        delete(2, 25, 35, Outcome::Deleted),
+        get(0, "a", None, 50, 60),
+    ];
+    let error = check_linearizable(&history).unwrap_err();
+    assert_eq!(error.history, history[..3].to_vec());
+}
+
+#[test]
+fn test_lock_commands_are_left_out() {
+    let lock = |process, command, invoked, completed, outcome| Operation {
+        process,
+        command,
+        invoked: ms(invoked),
+        completed: Some((ms(completed), outcome)),
+    };
+    let acquire = Command::Acquire {
+        name: "a".to_string(),
+        owner: "p".to_string(),
+        ttl_ms: 100,
+    };
+    let release = Command::Release {
+        name: "a".to_string(),
+        owner: "p".to_string(),
+    };
+    let history = vec![
+        lock(0, acquire, 0, 10, Outcome::Locked(1)),
+        put(1, "a", "1", 5, Some(15)),
+        lock(0, release, 20, 30, Outcome::Released),
+        get(1, "a", Some("1"), 25, 35),
+        lock(2, Command::Tick { millis: 100 }, 40, 50, Outcome::Noop),
+    ];
+    assert_eq!(check_linearizable(&history), Ok(()));
+    let mut stale = history.clone();
+    stale.push(get(1, "a", None, 60, 70));
+    assert_eq!(check_linearizable(&stale).unwrap_err().key, "a");
+}
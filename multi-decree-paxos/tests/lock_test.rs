@@ -0,0 +1,182 @@
+mod common;
+
+use common::{cluster_config, start_cluster_with};
+use multi_decree_paxos::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+fn acquire(name: &str, owner: &str, ttl_ms: u64) -> Command {
+    Command::Acquire {
+        name: name.to_string(),
+        owner: owner.to_string(),
+        ttl_ms,
+    }
+}
+
+/// Delivers every message the actions send, and those sent in turn, and
+/// returns the outcomes of client requests.
+fn deliver(replicas: &mut [Replica], from: usize, actions: Vec<Action>) -> Vec<Outcome> {
+    let mut network: VecDeque<(usize, Action)> =
+        actions.into_iter().map(|action| (from, action)).collect();
+    let mut outcomes = vec![];
+    while let Some((from, action)) = network.pop_front() {
+        let (to, msg) = match action {
+            Action::Send(to, msg) => (vec![to], msg),
+            Action::Broadcast(msg) => ((0..replicas.len()).collect(), msg),
+            Action::Respond(_, _, outcome) => {
+                outcomes.push(outcome);
+                continue;
+            }
+        };
+        for to in to {
+            let actions = replicas[to].handle_msg(from, &msg);
+            network.extend(actions.into_iter().map(|action| (to, action)));
+        }
+    }
+    outcomes
+}
+
+#[test]
+fn test_locks_are_held_renewed_released_and_expire() {
+    let mut locks = LockTable::default();
+    assert_eq!(locks.apply(3, &acquire("l", "a", 100)), Outcome::Locked(3));
+    assert_eq!(
+        locks.apply(4, &acquire("l", "b", 100)),
+        Outcome::LockHeld("a".to_string())
+    );
+    // the owner acquiring again extends the lock and keeps its token
+    assert_eq!(locks.apply(5, &acquire("l", "a", 100)), Outcome::Locked(3));
+    let renew = |owner: &str| Command::Renew {
+        name: "l".to_string(),
+        owner: owner.to_string(),
+        ttl_ms: 200,
+    };
+    assert_eq!(locks.apply(6, &renew("b")), Outcome::NotLockOwner);
+    assert_eq!(locks.apply(7, &renew("a")), Outcome::Locked(3));
+    assert_eq!(
+        locks.read(&Command::Get {
+            key: "l".to_string()
+        }),
+        Some(Outcome::Value("a".to_string()))
+    );
+
+    // the lock expires once 200ms of ticks have been applied
+    locks.apply(8, &Command::Tick { millis: 150 });
+    assert_eq!(locks.holder("l"), Some(("a", 3)));
+    assert_eq!(
+        locks.entries(),
+        vec![("l".to_string(), "a token:3 ttl:50ms".to_string())]
+    );
+    locks.apply(9, &Command::Tick { millis: 50 });
+    assert_eq!(locks.now(), 200);
+    assert_eq!(locks.holder("l"), None);
+    assert_eq!(locks.apply(10, &renew("a")), Outcome::NotLockOwner);
+
+    // tokens keep growing, even for locks granted at one slot
+    assert_eq!(
+        locks.apply(11, &acquire("l", "b", 100)),
+        Outcome::Locked(11)
+    );
+    let release = Command::Release {
+        name: "l".to_string(),
+        owner: "b".to_string(),
+    };
+    assert_eq!(locks.apply(12, &release), Outcome::Released);
+    assert_eq!(
+        locks.apply(12, &acquire("l", "c", 100)),
+        Outcome::Locked(12)
+    );
+    assert_eq!(
+        locks.apply(12, &acquire("m", "c", 100)),
+        Outcome::Locked(13)
+    );
+    assert_eq!(
+        locks.apply(13, &acquire("n", "c", 100)),
+        Outcome::Locked(14)
+    );
+
+    let mut restored = LockTable::default();
+    restored.restore(&locks.snapshot()).unwrap();
+    assert_eq!(restored, locks);
+    assert_eq!(
+        restored.apply(14, &acquire("o", "c", 100)),
+        Outcome::Locked(15)
+    );
+}
+
+#[test]
+fn test_leader_ticks_expire_locks_on_every_replica() {
+    let start = Instant::now();
+    let mut replicas: Vec<Replica> = (0..3)
+        .map(|id| {
+            let mut replica = Replica::with_state_machine(id, 3, Box::new(LockTable::default()));
+            replica.set_clock(start);
+            replica
+        })
+        .collect();
+    let actions = replicas[0].propose(0, "acquire\nl\na\n300").unwrap();
+    let outcomes = deliver(&mut replicas, 0, actions);
+    let [Outcome::Locked(token)] = outcomes[..] else {
+        panic!("lock not granted: {:?}", outcomes);
+    };
+    assert!(replicas[0].proposer().is_leader());
+
+    // only the leader's clock moves; the others learn the time from TICKs
+    let mut now = start;
+    for _ in 0..2 {
+        now += LOCK_TICK_INTERVAL;
+        let actions = replicas[0].tick(now);
+        deliver(&mut replicas, 0, actions);
+    }
+    for replica in &replicas {
+        assert_eq!(replica.learner().get_value("l"), Some("a".to_string()));
+    }
+    let actions = replicas[0].propose(1, "acquire\nl\nb\n300").unwrap();
+    assert_eq!(
+        deliver(&mut replicas, 0, actions),
+        vec![Outcome::LockHeld("a".to_string())]
+    );
+
+    now += LOCK_TICK_INTERVAL;
+    let actions = replicas[0].tick(now);
+    deliver(&mut replicas, 0, actions);
+    for replica in &replicas {
+        assert_eq!(replica.learner().get_value("l"), None);
+    }
+    let actions = replicas[0].propose(2, "acquire\nl\nb\n300").unwrap();
+    let outcomes = deliver(&mut replicas, 0, actions);
+    assert!(matches!(outcomes[..], [Outcome::Locked(next)] if next > token));
+}
+
+#[tokio::test]
+async fn test_lock_service_grants_fencing_tokens_over_tcp() {
+    let nodes = start_cluster_with(&cluster_config(3), || Box::new(LockTable::default()));
+    let client = KvClient::new(nodes);
+    let ttl = Duration::from_secs(10);
+
+    let first = client.acquire("l", "a", ttl).await.unwrap().unwrap();
+    assert_eq!(
+        client.acquire("l", "b", ttl).await.unwrap(),
+        Err("a".to_string())
+    );
+    assert_eq!(client.renew("l", "a", ttl).await.unwrap(), Some(first));
+    assert_eq!(client.get("l").await.unwrap(), Some("a".to_string()));
+    assert!(!client.release("l", "b").await.unwrap());
+    assert!(client.release("l", "a").await.unwrap());
+    let second = client.acquire("l", "b", ttl).await.unwrap().unwrap();
+    assert!(second > first);
+
+    // a lock nobody renews expires on the leader's ticks
+    let short = client
+        .acquire("m", "a", Duration::from_millis(300))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(short > second);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.get("m").await.unwrap().is_some() {
+        assert!(Instant::now() < deadline, "lock did not expire");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(client.renew("m", "a", ttl).await.unwrap(), None);
+}
@@ -0,0 +1,155 @@
+mod common;
+
+use common::put;
+use multi_decree_paxos::*;
+
+fn accepted(slot: u64, command: Command) -> Message {
+    Message::Accepted {
+        slot,
+        proposal_number: Ballot::new(1, 0),
+        command,
+    }
+}
+
+/// Delivers `msg` from a quorum of two acceptors.
+fn choose(learner: &mut Learner, msg: &Message) -> Vec<Message> {
+    let mut responses = learner.handle_msg(0, msg);
+    responses.extend(learner.handle_msg(1, msg));
+    responses
+}
+
+#[test]
+fn test_slots_are_applied_in_order() {
+    let mut learner = Learner::new();
+    learner.set_f(1);
+
+    // slot 1 is chosen before slot 0 and must wait for it
+    assert!(choose(&mut learner, &accepted(1, put("k", "second"))).is_empty());
+    assert_eq!(learner.missing_slots(), vec![0]);
+    assert_eq!(learner.get_value("k"), None);
+
+    let responses = choose(&mut learner, &accepted(0, put("k", "first")));
+    let slots: Vec<u64> = responses.iter().map(|msg| msg.slot()).collect();
+    assert_eq!(slots, vec![0, 1]);
+    assert_eq!(learner.get_value("k"), Some("second".to_string()));
+    assert_eq!(learner.next_slot(), 2);
+    assert!(learner.missing_slots().is_empty());
+
+    // late votes for an applied slot are ignored
+    assert!(choose(&mut learner, &accepted(0, put("k", "first"))).is_empty());
+    assert_eq!(learner.get_value("k"), Some("second".to_string()));
+}
+
+/// Makes `proposer` the leader with promises from acceptors 0 and 1.
+fn elect(proposer: &mut Proposer, accepted: Vec<PValue>) -> Vec<Message> {
+    let Message::Prepare {
+        slot,
+        proposal_number,
+    } = proposer.send_prepare()
+    else {
+        unreachable!()
+    };
+    let promise = Message::Promise {
+        slot,
+        proposal_number,
+        log_start: 0,
+        accepted,
+    };
+    let mut msgs = proposer.handle_msg(0, &promise);
+    msgs.extend(proposer.handle_msg(1, &promise));
+    msgs
+}
+
+#[test]
+fn test_gap_is_filled_with_noop() {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    let mut acceptors = [Acceptor::new(), Acceptor::new(), Acceptor::new()];
+    let mut learner = Learner::new();
+    learner.set_f(1);
+    elect(&mut proposer, vec![]);
+
+    choose(&mut learner, &accepted(1, put("k", "v")));
+    let accepts = proposer.fill_gaps(&learner.missing_slots());
+    assert_eq!(
+        accepts,
+        vec![Message::Accept {
+            slot: 0,
+            proposal_number: Ballot::new(1, 0),
+            command: Command::Noop,
+        }]
+    );
+    // the no-op is in flight, so it is not proposed twice
+    assert!(proposer.fill_gaps(&learner.missing_slots()).is_empty());
+
+    let accepted = acceptors[0].handle_msg(0, &accepts[0]).remove(0);
+    let responses = choose(&mut learner, &accepted);
+    assert_eq!(responses.len(), 2);
+    for response in &responses {
+        assert!(proposer.handle_msg(0, response).is_empty());
+    }
+    assert!(proposer.is_idle());
+    assert_eq!(learner.get_value("k"), Some("v".to_string()));
+}
+
+#[test]
+fn test_new_leader_recovers_accepted_values() {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+
+    // an earlier leader got values accepted at slots 1 and 2 before going silent
+    let accepted = vec![
+        PValue {
+            slot: 1,
+            proposal_number: Ballot::new(1, 0),
+            command: put("k", "old"),
+        },
+        PValue {
+            slot: 2,
+            proposal_number: Ballot::new(1, 0),
+            command: put("k", "v"),
+        },
+    ];
+    let msgs = elect(&mut proposer, accepted);
+    // the hole at slot 0 gets a no-op and the recovered values are re-proposed
+    let commands: Vec<(u64, Command)> = msgs
+        .into_iter()
+        .map(|msg| match msg {
+            Message::Accept { slot, command, .. } => (slot, command),
+            msg => panic!("unexpected {:?}", msg),
+        })
+        .collect();
+    assert_eq!(
+        commands,
+        vec![(0, Command::Noop), (1, put("k", "old")), (2, put("k", "v")),]
+    );
+
+    // new commands go after the recovered ones
+    let msgs = proposer.propose(0, "put\nk\nnew").unwrap();
+    assert_eq!(msgs[0].slot(), 3);
+}
+
+#[test]
+fn test_leader_retries_after_losing_slot() {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    elect(&mut proposer, vec![]);
+
+    proposer.propose(0, "put\nmine\n1").unwrap();
+    // another leader's command was chosen at slot 0
+    let response = Message::Response {
+        slot: 0,
+        proposal_number: Ballot::new(2, 0),
+        command: put("theirs", "2"),
+        outcome: Outcome::PutOk,
+    };
+    assert_eq!(
+        proposer.handle_msg(0, &response),
+        vec![Message::Accept {
+            slot: 1,
+            proposal_number: Ballot::new(1, 0),
+            command: put("mine", "1"),
+        }]
+    );
+    assert!(!proposer.is_idle());
+}
@@ -0,0 +1,161 @@
+use multi_decree_paxos::*;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+fn voters(ids: &[usize]) -> HashSet<usize> {
+    ids.iter().copied().collect()
+}
+
+/// Four replicas joined by a lossless in-memory network, of which 0, 1 and 2
+/// start as members. Messages to or from an isolated replica are dropped.
+struct Cluster {
+    replicas: Vec<Replica>,
+    isolated: Vec<bool>,
+    network: VecDeque<(usize, usize, Message)>,
+    /// Every client response, with the replica that sent it.
+    responses: Vec<(usize, u64, Outcome)>,
+}
+
+impl Cluster {
+    fn new() -> Cluster {
+        let mut replicas: Vec<Replica> = (0..4).map(|id| Replica::new(id, 4)).collect();
+        for replica in &mut replicas {
+            replica.set_initial_members(vec![0, 1, 2]);
+        }
+        Cluster {
+            replicas,
+            isolated: vec![false; 4],
+            network: VecDeque::new(),
+            responses: vec![],
+        }
+    }
+
+    fn dispatch(&mut self, from: usize, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(to, msg) => self.network.push_back((from, to, msg)),
+                Action::Broadcast(msg) => {
+                    for to in 0..self.replicas.len() {
+                        self.network.push_back((from, to, msg.clone()));
+                    }
+                }
+                Action::Respond(_, slot, outcome) => self.responses.push((from, slot, outcome)),
+            }
+        }
+    }
+
+    /// Delivers messages until the network is quiet.
+    fn run(&mut self) {
+        while let Some((from, to, msg)) = self.network.pop_front() {
+            if !self.isolated[from] && !self.isolated[to] {
+                let actions = self.replicas[to].handle_msg(from, &msg);
+                self.dispatch(to, actions);
+            }
+        }
+    }
+
+    /// Sends `request` to replica `id` and delivers messages until the
+    /// network is quiet.
+    fn request(&mut self, id: usize, request: &str) {
+        let actions = self.replicas[id].propose(0, request).unwrap();
+        self.dispatch(id, actions);
+        self.run();
+    }
+
+    fn tick(&mut self, id: usize, now: Instant) {
+        let actions = self.replicas[id].tick(now);
+        self.dispatch(id, actions);
+        self.run();
+    }
+}
+
+#[test]
+fn test_reconfigure_request_format() {
+    let command = Command::from_client_request("reconfigure\n0\n3").unwrap();
+    assert_eq!(
+        command,
+        Command::Reconfigure {
+            members: vec![0, 3]
+        }
+    );
+    assert_eq!(
+        command.to_client_request(),
+        Some("reconfigure\n0\n3".to_string())
+    );
+    assert_eq!(command.key(), None);
+    assert_eq!(Command::from_client_request("reconfigure"), None);
+    assert_eq!(Command::from_client_request("reconfigure\n1\n1"), None);
+    assert_eq!(Command::from_client_request("reconfigure\none"), None);
+    assert_eq!(
+        Outcome::Reconfigured(40).to_string(),
+        "reconfigure successful! from slot:40"
+    );
+
+    // members must be in the address book
+    let mut replica = Replica::new(0, 3);
+    assert_eq!(replica.propose(0, "reconfigure\n0\n3"), None);
+    assert!(replica.propose(0, "reconfigure\n0\n2").is_some());
+}
+
+#[test]
+fn test_membership_changes_at_effective_slot() {
+    let mut membership = Membership::new(vec![0, 1, 2]);
+    let start = membership.reconfigure(5, vec![2, 3]);
+    assert_eq!(start, 5 + RECONFIG_ALPHA);
+    assert_eq!(membership.members(start - 1), &[0, 1, 2]);
+    assert_eq!(membership.members(start), &[2, 3]);
+    assert_eq!(membership.latest(), &[2, 3]);
+    assert_eq!(membership.f(0), 1);
+    assert_eq!(membership.f(start), 1);
+
+    // only the votes of members at the slot count
+    assert!(membership.is_quorum(0, &voters(&[0, 1])));
+    assert!(!membership.is_quorum(0, &voters(&[0, 3, 4])));
+    assert!(!membership.is_quorum(start, &voters(&[0, 1, 2])));
+    assert!(membership.is_quorum(start, &voters(&[2, 3])));
+    // a leader of both configurations needs a quorum of each
+    assert!(!membership.is_quorum_from(0, &voters(&[0, 1])));
+    assert!(membership.is_quorum_from(0, &voters(&[1, 2, 3])));
+
+    membership.forget_before(start + 1);
+    assert_eq!(membership.configs(), vec![(start, vec![2, 3])]);
+}
+
+#[test]
+fn test_cluster_replaces_a_member() {
+    let mut cluster = Cluster::new();
+    cluster.request(0, "put\nk\nv");
+    assert_eq!(cluster.responses, vec![(0, 0, Outcome::PutOk)]);
+
+    let start = 1 + RECONFIG_ALPHA;
+    cluster.request(0, "reconfigure\n1\n2\n3");
+    assert_eq!(cluster.responses[1], (0, 1, Outcome::Reconfigured(start)));
+    for replica in &cluster.replicas {
+        assert_eq!(replica.learner().membership().latest(), &[1, 2, 3]);
+    }
+
+    // the removed leader serves the slots up to the switch, then steps down
+    for slot in 2..start {
+        cluster.request(0, &format!("put\nk\n{}", slot));
+        assert_eq!(cluster.responses.last(), Some(&(0, slot, Outcome::PutOk)));
+    }
+    assert!(!cluster.replicas[0].proposer().is_leader());
+    cluster.request(0, "put\nk\nnew");
+    assert_eq!(
+        cluster.responses.last(),
+        Some(&(0, start, Outcome::NotLeader(None)))
+    );
+
+    // replica 1 takes over, retrying with a higher ballot than replica 0
+    // last ran phase 1 with, and replica 3 now counts towards a quorum
+    cluster.isolated[2] = true;
+    cluster.request(1, "put\nk\nnew");
+    cluster.tick(1, Instant::now() + Duration::from_secs(1));
+    assert_eq!(cluster.responses.last(), Some(&(1, start, Outcome::PutOk)));
+
+    // and replica 0 no longer does
+    cluster.isolated[3] = true;
+    let answered = cluster.responses.len();
+    cluster.request(1, "put\nk\nlost");
+    assert_eq!(cluster.responses.len(), answered);
+}
@@ -0,0 +1,299 @@
+mod common;
+
+use common::put;
+use multi_decree_paxos::*;
+
+fn prepare(slot: u64) -> Message {
+    Message::Prepare {
+        slot,
+        proposal_number: Ballot::new(1, 0),
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let msgs = vec![
+        prepare(7),
+        Message::Promise {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            log_start: 5,
+            accepted: vec![
+                PValue {
+                    slot: 7,
+                    proposal_number: Ballot::new(2, 0),
+                    command: put("a key", "a value\nwith newline"),
+                },
+                PValue {
+                    slot: 9,
+                    proposal_number: Ballot::new(1, 0),
+                    command: Command::Noop,
+                },
+            ],
+        },
+        Message::Promise {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            log_start: 0,
+            accepted: vec![],
+        },
+        Message::Accept {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            command: Command::Get {
+                key: "".to_string(),
+            },
+        },
+        Message::Accepted {
+            slot: u64::MAX,
+            proposal_number: Ballot::new(3, 0),
+            command: Command::Noop,
+        },
+        Message::Unaccepted {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            promised_proposal_number: Ballot::new(4, 0),
+        },
+        Message::Response {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            command: Command::Get {
+                key: "k".to_string(),
+            },
+            outcome: Outcome::Value("v v".to_string()),
+        },
+        Message::Nack {
+            slot: 7,
+            proposal_number: Ballot::new(3, 0),
+            promised_proposal_number: Ballot::new(5, 0),
+        },
+        Message::Heartbeat {
+            slot: 12,
+            proposal_number: Ballot::new(u64::MAX, u32::MAX),
+        },
+        Message::CatchUp { slot: 3 },
+        Message::Chosen {
+            slot: 3,
+            proposal_number: Ballot::new(2, 1),
+            command: put("k", "v"),
+        },
+        Message::Snapshot {
+            slot: 3,
+            state: vec![0, 0, 0, 1, 0xff],
+            sessions: vec![(7, 2, Outcome::PutOk, 40), (9, 0, Outcome::Expired, 3)],
+            configs: vec![(0, vec![0, 1, 2]), (35, vec![1, 2, 3])],
+        },
+        Message::Confirm {
+            slot: 4,
+            proposal_number: Ballot::new(2, 1),
+        },
+        Message::Confirmed {
+            slot: 4,
+            proposal_number: Ballot::new(2, 1),
+        },
+    ];
+    for msg in msgs {
+        let frame = msg.encode();
+        assert_eq!(Message::decode(&frame), Ok((msg, frame.len())));
+    }
+}
+
+#[test]
+fn test_commands_and_outcomes_round_trip() {
+    let key = || "k".to_string();
+    let commands = vec![
+        Command::Delete { key: key() },
+        Command::Cas {
+            key: key(),
+            expected: "".to_string(),
+            new: "n".to_string(),
+        },
+        Command::Append {
+            key: key(),
+            value: "v".to_string(),
+        },
+        Command::Incr { key: key(), by: -1 },
+        Command::Incr {
+            key: key(),
+            by: i64::MIN,
+        },
+        Command::Scan {
+            start: "a".to_string(),
+            end: "".to_string(),
+        },
+        Command::ScanPrefix { prefix: key() },
+        Command::Session {
+            client: u64::MAX,
+            seq: 3,
+            command: Box::new(Command::Incr { key: key(), by: 2 }),
+        },
+        Command::Batch(vec![]),
+        Command::Batch(vec![Command::Delete { key: key() }, Command::Noop]),
+        Command::Reconfigure {
+            members: vec![0, 4, 2],
+        },
+        Command::Acquire {
+            name: key(),
+            owner: "o".to_string(),
+            ttl_ms: 500,
+        },
+        Command::Release {
+            name: key(),
+            owner: "o".to_string(),
+        },
+        Command::Renew {
+            name: key(),
+            owner: "o".to_string(),
+            ttl_ms: u64::MAX,
+        },
+        Command::Tick { millis: 100 },
+    ];
+    let outcomes = vec![
+        Outcome::Deleted,
+        Outcome::Updated("u".to_string()),
+        Outcome::CasFailed(None),
+        Outcome::CasFailed(Some("".to_string())),
+        Outcome::NotAnInteger,
+        Outcome::Entries(vec![]),
+        Outcome::Entries(vec![("a".to_string(), "1".to_string())]),
+        Outcome::Expired,
+        Outcome::Batch(vec![Outcome::Deleted, Outcome::Noop]),
+        Outcome::Reconfigured(35),
+        Outcome::Locked(7),
+        Outcome::LockHeld("o".to_string()),
+        Outcome::Released,
+        Outcome::NotLockOwner,
+    ];
+    for (command, outcome) in commands.into_iter().zip(outcomes.into_iter().cycle()) {
+        let msg = Message::Response {
+            slot: 1,
+            proposal_number: Ballot::new(1, 0),
+            command,
+            outcome,
+        };
+        let frame = msg.encode();
+        assert_eq!(Message::decode(&frame), Ok((msg, frame.len())));
+    }
+}
+
+#[test]
+fn test_coalesced_and_partial_frames() {
+    let first = prepare(0);
+    let second = Message::Accepted {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: put("hello", "world"),
+    };
+    let mut buf = first.encode();
+    buf.extend(second.encode());
+
+    let (msg, len) = Message::decode(&buf).unwrap();
+    assert_eq!(msg, first);
+    let (msg, rest) = Message::decode(&buf[len..]).unwrap();
+    assert_eq!(msg, second);
+    assert_eq!(len + rest, buf.len());
+
+    assert_eq!(
+        Message::decode(&buf[..buf.len() - 1][len..]),
+        Err(DecodeError::Incomplete)
+    );
+    assert_eq!(Message::decode(&buf[..3]), Err(DecodeError::Incomplete));
+}
+
+#[test]
+fn test_garbage_is_rejected() {
+    let mut frame = prepare(0).encode();
+    frame[0] = PROTOCOL_VERSION + 1;
+    assert_eq!(
+        Message::decode(&frame),
+        Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+    );
+
+    let mut frame = prepare(0).encode();
+    frame[1] = 42;
+    assert_eq!(Message::decode(&frame), Err(DecodeError::UnknownType(42)));
+
+    // a body longer than the message needs
+    let mut frame = prepare(0).encode();
+    frame[5] += 1;
+    frame.push(0);
+    assert!(matches!(
+        Message::decode(&frame),
+        Err(DecodeError::Malformed(_))
+    ));
+
+    // a header announcing more than a node takes
+    let mut frame = prepare(0).encode();
+    frame[2..6].copy_from_slice(&(MAX_FRAME_LEN as u32).to_be_bytes());
+    assert_eq!(
+        Message::decode(&frame),
+        Err(DecodeError::Malformed("frame too long"))
+    );
+
+    // invalid utf-8 in a key
+    let mut frame = Message::Accept {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: Command::Get {
+            key: "k".to_string(),
+        },
+    }
+    .encode();
+    let last = frame.len() - 1;
+    frame[last] = 0xff;
+    assert_eq!(Message::decode(&frame), Err(DecodeError::InvalidUtf8));
+}
+
+#[test]
+fn test_nested_batches_are_rejected() {
+    let accept = |command| Message::Accept {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    let nested = Command::Batch(vec![Command::Batch(vec![Command::Noop])]);
+    assert_eq!(
+        Message::decode(&accept(nested).encode()),
+        Err(DecodeError::Malformed("nested batch"))
+    );
+    let in_session = Command::Batch(vec![Command::Session {
+        client: 1,
+        seq: 0,
+        command: Box::new(Command::Batch(vec![])),
+    }]);
+    assert_eq!(
+        Message::decode(&accept(in_session).encode()),
+        Err(DecodeError::Malformed("batch in a session"))
+    );
+    let response = Message::Response {
+        slot: 0,
+        proposal_number: Ballot::new(1, 0),
+        command: Command::Noop,
+        outcome: Outcome::Batch(vec![Outcome::Batch(vec![])]),
+    };
+    assert_eq!(
+        Message::decode(&response.encode()),
+        Err(DecodeError::Malformed("nested batch"))
+    );
+
+    // frames nesting far deeper than decoding could recurse
+    let deep = |level: &[u8]| {
+        let mut frame = accept(Command::Noop).encode();
+        frame.truncate(frame.len() - 1);
+        for _ in 0..1_000_000 {
+            frame.extend(level);
+        }
+        frame.push(2);
+        let len = (frame.len() - 6) as u32;
+        frame[2..6].copy_from_slice(&len.to_be_bytes());
+        Message::decode(&frame)
+    };
+    assert_eq!(
+        deep(&[10, 0, 0, 0, 1]),
+        Err(DecodeError::Malformed("nested batch"))
+    );
+    assert_eq!(
+        deep(&[9, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Err(DecodeError::Malformed("nested session"))
+    );
+}
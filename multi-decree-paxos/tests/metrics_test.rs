@@ -0,0 +1,200 @@
+mod common;
+
+use common::{cluster_config, start_cluster};
+use multi_decree_paxos::*;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Three replicas joined by a lossless in-memory network on which every
+/// message takes `delay` of their shared clock.
+struct Cluster {
+    replicas: Vec<Replica>,
+    network: VecDeque<(usize, usize, Message)>,
+    now: Instant,
+    delay: Duration,
+}
+
+impl Cluster {
+    fn new(delay: Duration) -> Cluster {
+        let now = Instant::now();
+        let mut replicas: Vec<Replica> = (0..3).map(|id| Replica::new(id, 3)).collect();
+        for replica in &mut replicas {
+            replica.set_clock(now);
+        }
+        Cluster {
+            replicas,
+            network: VecDeque::new(),
+            now,
+            delay,
+        }
+    }
+
+    fn dispatch(&mut self, from: usize, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(to, msg) => self.network.push_back((from, to, msg)),
+                Action::Broadcast(msg) => {
+                    for to in 0..self.replicas.len() {
+                        self.network.push_back((from, to, msg.clone()));
+                    }
+                }
+                Action::Respond(..) => {}
+            }
+        }
+    }
+
+    /// Delivers messages until the network is quiet, advancing the clock
+    /// by `delay` for every message.
+    fn run(&mut self) {
+        while let Some((from, to, msg)) = self.network.pop_front() {
+            self.now += self.delay;
+            let mut actions = self.replicas[to].tick(self.now);
+            actions.extend(self.replicas[to].handle_msg(from, &msg));
+            self.dispatch(to, actions);
+        }
+    }
+
+    fn propose(&mut self, id: usize, request: &str) {
+        let actions = self.replicas[id].propose(0, request).unwrap();
+        self.dispatch(id, actions);
+    }
+}
+
+#[test]
+fn test_histograms_render_cumulative_buckets() {
+    let mut metrics = Metrics {
+        nacks: 3,
+        ..Metrics::default()
+    };
+    metrics.request_latency.observe(Duration::from_millis(2));
+    metrics.request_latency.observe(Duration::from_millis(40));
+    metrics.request_latency.observe(Duration::from_secs(10));
+    assert_eq!(metrics.request_latency.count(), 3);
+    assert_eq!(metrics.request_latency.sum(), Duration::from_millis(10_042));
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+    for line in [
+        "# TYPE paxos_nacks_total counter",
+        "paxos_nacks_total 3",
+        "# TYPE paxos_request_seconds histogram",
+        "paxos_request_seconds_bucket{le=\"0.001\"} 0",
+        "paxos_request_seconds_bucket{le=\"0.0025\"} 1",
+        "paxos_request_seconds_bucket{le=\"0.05\"} 2",
+        "paxos_request_seconds_bucket{le=\"5\"} 2",
+        "paxos_request_seconds_bucket{le=\"+Inf\"} 3",
+        "paxos_request_seconds_sum 10.042",
+        "paxos_request_seconds_count 3",
+        "paxos_election_seconds_count 0",
+    ] {
+        assert!(
+            out.lines().any(|l| l == line),
+            "missing {:?} in\n{}",
+            line,
+            out
+        );
+    }
+}
+
+#[test]
+fn test_rounds_are_counted_and_timed() {
+    let mut cluster = Cluster::new(Duration::from_millis(1));
+    cluster.propose(0, "put\nk\nv");
+    cluster.run();
+
+    let metrics = cluster.replicas[0].proposer().metrics();
+    assert_eq!(metrics.elections, 1);
+    assert_eq!(metrics.elections_won, 1);
+    assert_eq!(metrics.nacks, 0);
+    assert_eq!(metrics.requests, 1);
+    assert_eq!(metrics.election_latency.count(), 1);
+    assert_eq!(metrics.accept_latency.count(), 1);
+    assert_eq!(metrics.request_latency.count(), 1);
+    // PREPARE, PROMISE, ACCEPT, ACCEPTED and RESPONSE each took a hop
+    assert!(metrics.request_latency.sum() >= Duration::from_millis(5));
+    assert!(metrics.accept_latency.sum() < metrics.request_latency.sum());
+
+    let text = cluster.replicas[0].render_metrics();
+    assert!(text.contains("\npaxos_leader 1\n"));
+    assert!(text.contains("\npaxos_applied_slots 1\n"));
+    assert!(text.contains("\npaxos_requests_total 1\n"));
+}
+
+#[test]
+fn test_preempted_ballots_are_counted() {
+    let mut cluster = Cluster::new(Duration::ZERO);
+    // both run for leader; replica 1's higher ballot preempts replica 0's
+    // ACCEPT
+    cluster.propose(0, "put\nk\n0");
+    cluster.propose(1, "put\nk\n1");
+    cluster.run();
+
+    let metrics = cluster.replicas[0].proposer().metrics();
+    assert_eq!(metrics.elections, 1);
+    assert!(metrics.nacks >= 1);
+    assert!(!cluster.replicas[0].proposer().is_leader());
+    assert_eq!(cluster.replicas[1].proposer().metrics().elections_won, 1);
+    assert!(cluster.replicas[1].proposer().is_leader());
+}
+
+#[test]
+fn test_retries_are_counted() {
+    let mut cluster = Cluster::new(Duration::ZERO);
+    // the PREPARE is lost, so it goes out again
+    cluster.propose(0, "put\nk\nv");
+    cluster.network.clear();
+    let actions = cluster.replicas[0].tick(cluster.now + Duration::from_secs(1));
+    assert!(matches!(
+        actions[..],
+        [Action::Broadcast(Message::Prepare { .. })]
+    ));
+    assert_eq!(cluster.replicas[0].proposer().metrics().retries, 1);
+
+    // and so is the ACCEPT
+    cluster.dispatch(0, actions);
+    cluster.run();
+    assert!(cluster.replicas[0].proposer().is_leader());
+    let actions = cluster.replicas[0].propose(1, "put\nk\nw").unwrap();
+    assert!(!actions.is_empty());
+    let actions = cluster.replicas[0].tick(cluster.now + Duration::from_secs(2));
+    assert!(actions
+        .iter()
+        .any(|action| matches!(action, Action::Broadcast(Message::Accept { .. }))));
+    assert_eq!(cluster.replicas[0].proposer().metrics().retries, 2);
+}
+
+#[test]
+fn test_node_serves_metrics_over_http() {
+    let config = cluster_config(1);
+    let metrics_addr = config.nodes[0].metrics_addr.unwrap();
+    start_cluster(&config);
+
+    let mut stream = TcpStream::connect(config.nodes[0].client_addr).unwrap();
+    stream.write_all(b"put\nk\nv").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "put successful!");
+
+    let scrape = |path: &str| {
+        let mut stream = TcpStream::connect(metrics_addr).unwrap();
+        // the request head may arrive in pieces
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\n", path).as_bytes())
+            .unwrap();
+        sleep(Duration::from_millis(20));
+        stream.write_all(b"Host: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = scrape("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(body.contains("\npaxos_requests_total 1\n"), "{}", body);
+    assert!(body.contains("\npaxos_leader 1\n"), "{}", body);
+    assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
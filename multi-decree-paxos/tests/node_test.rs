@@ -0,0 +1,190 @@
+use multi_decree_paxos::{check_linearizable, Operation, Outcome};
+use portpicker::pick_unused_port;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::{scope, sleep};
+use std::time::{Duration, Instant};
+
+/// A cluster of `paxos-node` processes on localhost.
+struct Cluster {
+    config: PathBuf,
+    client_ports: Vec<u16>,
+    nodes: Vec<Option<Child>>,
+}
+
+impl Cluster {
+    fn new(dir: &Path, n: usize) -> Cluster {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let mut config = format!("data_dir = {:?}\n", dir.join("data"));
+        let mut client_ports = vec![];
+        for id in 0..n {
+            let client_port = pick_unused_port().unwrap();
+            config.push_str(&format!(
+                "[[nodes]]\nid = {}\npeer_addr = \"127.0.0.1:{}\"\nclient_addr = \"127.0.0.1:{}\"\n",
+                id,
+                pick_unused_port().unwrap(),
+                client_port
+            ));
+            client_ports.push(client_port);
+        }
+        let path = dir.join("cluster.toml");
+        std::fs::write(&path, config).unwrap();
+        let mut cluster = Cluster {
+            config: path,
+            client_ports,
+            nodes: (0..n).map(|_| None).collect(),
+        };
+        for id in 0..n {
+            cluster.start(id);
+        }
+        cluster
+    }
+
+    fn start(&mut self, id: usize) {
+        let child = Command::new(env!("CARGO_BIN_EXE_paxos-node"))
+            .arg("--id")
+            .arg(id.to_string())
+            .arg("--config")
+            .arg(&self.config)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        self.nodes[id] = Some(child);
+    }
+
+    fn kill(&mut self, id: usize) {
+        if let Some(mut child) = self.nodes[id].take() {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+    }
+
+    /// Sends a one-shot request, retrying until the node answers.
+    fn request(&self, id: usize, args: &[&str]) -> String {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            if let Some(response) = self.try_request(id, args) {
+                return response;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("node {} did not answer {:?}", id, args);
+    }
+
+    /// Sends a request once, so that it takes effect at most once. A node
+    /// that does not lead proposes nothing, so the request follows its hint
+    /// to the leader.
+    fn try_request(&self, id: usize, args: &[&str]) -> Option<String> {
+        let mut id = id;
+        for _ in 0..self.nodes.len() {
+            let response = self.send(id, args)?;
+            match Outcome::from_response(&response) {
+                Some(Outcome::NotLeader(Some(leader))) => id = leader,
+                Some(Outcome::NotLeader(None)) => return None,
+                _ => return Some(response),
+            }
+        }
+        None
+    }
+
+    fn send(&self, id: usize, args: &[&str]) -> Option<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.client_ports[id])).ok()?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(args.join("\n").as_bytes()).ok()?;
+        stream.shutdown(Shutdown::Write).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        Some(response).filter(|response| !response.is_empty())
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for id in 0..self.nodes.len() {
+            self.kill(id);
+        }
+    }
+}
+
+#[test]
+fn test_node_survives_being_killed_and_restarted() {
+    let dir = std::env::temp_dir().join(format!("paxos-node-test-{}", std::process::id()));
+    let mut cluster = Cluster::new(&dir, 3);
+
+    assert_eq!(cluster.request(0, &["put", "a", "1"]), "put successful!");
+    assert_eq!(cluster.request(1, &["get", "a"]), "get successful! value:1");
+
+    // the other two still form a quorum
+    cluster.kill(0);
+    assert_eq!(cluster.request(1, &["put", "b", "2"]), "put successful!");
+    assert_eq!(cluster.request(2, &["get", "b"]), "get successful! value:2");
+
+    // the restarted node recovers its log and catches up on what it missed
+    cluster.start(0);
+    assert_eq!(cluster.request(0, &["get", "b"]), "get successful! value:2");
+    assert_eq!(cluster.request(0, &["get", "a"]), "get successful! value:1");
+
+    drop(cluster);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_concurrent_clients_are_linearizable() {
+    let dir = std::env::temp_dir().join(format!("paxos-history-test-{}", std::process::id()));
+    let cluster = Cluster::new(&dir, 3);
+    // wait until the cluster is up, on a key the clients leave alone
+    cluster.request(0, &["put", "warm-up", "done"]);
+
+    // every client starts at its own node, which sends it on to the leader
+    let start = Instant::now();
+    let history: Vec<Operation> = scope(|s| {
+        let clients: Vec<_> = (0..3)
+            .map(|process| {
+                let cluster = &cluster;
+                s.spawn(move || {
+                    let mut history = vec![];
+                    for i in 0..10 {
+                        let key = format!("k{}", i % 2);
+                        let value = format!("{}-{}", process, i);
+                        let args = if (process + i) % 3 == 0 {
+                            vec!["get", &key]
+                        } else {
+                            vec!["put", &key, &value]
+                        };
+                        let invoked = start.elapsed();
+                        let response = cluster.try_request(process, &args);
+                        history.push(Operation {
+                            process,
+                            command: multi_decree_paxos::Command::from_client_request(
+                                &args.join("\n"),
+                            )
+                            .unwrap(),
+                            invoked,
+                            completed: response
+                                .and_then(|response| Outcome::from_response(&response))
+                                .map(|outcome| (start.elapsed(), outcome)),
+                        });
+                    }
+                    history
+                })
+            })
+            .collect();
+        clients
+            .into_iter()
+            .flat_map(|client| client.join().unwrap())
+            .collect()
+    });
+
+    assert!(history.iter().any(|op| op.completed.is_some()));
+    if let Err(error) = check_linearizable(&history) {
+        panic!("{}", error);
+    }
+
+    drop(cluster);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::put;
+use multi_decree_paxos::*;
+
+/// A proposer with the given window that leads with an empty log.
+fn leader(window: usize) -> Proposer {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    proposer.set_pipeline(Pipeline {
+        window,
+        max_batch: 2,
+    });
+    let Message::Prepare {
+        slot,
+        proposal_number,
+    } = proposer.send_prepare()
+    else {
+        unreachable!()
+    };
+    let promise = Message::Promise {
+        slot,
+        proposal_number,
+        log_start: 0,
+        accepted: vec![],
+    };
+    proposer.handle_msg(0, &promise);
+    assert!(proposer.handle_msg(1, &promise).is_empty());
+    proposer
+}
+
+fn accepts(msgs: &[Message]) -> Vec<(u64, Command)> {
+    msgs.iter()
+        .map(|msg| match msg {
+            Message::Accept { slot, command, .. } => (*slot, command.clone()),
+            msg => panic!("unexpected {:?}", msg),
+        })
+        .collect()
+}
+
+/// Tells the proposer `command` was chosen at `slot`, the way the local
+/// learner would.
+fn chosen(
+    proposer: &mut Proposer,
+    learner: &mut Learner,
+    slot: u64,
+    command: Command,
+) -> Vec<Message> {
+    let msg = Message::Chosen {
+        slot,
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    let mut msgs = vec![];
+    for response in learner.handle_msg(0, &msg) {
+        msgs.extend(proposer.handle_msg(0, &response));
+    }
+    msgs
+}
+
+#[test]
+fn test_window_limits_slots_in_flight() {
+    let mut proposer = leader(2);
+    let mut learner = Learner::new();
+    let mut msgs = vec![];
+    for (id, value) in ["a", "b", "c"].iter().enumerate() {
+        msgs.extend(
+            proposer
+                .propose(id as u64, &format!("put\n{}\n1", value))
+                .unwrap(),
+        );
+    }
+    assert_eq!(accepts(&msgs), vec![(0, put("a", "1")), (1, put("b", "1"))]);
+
+    // the window opens once a slot is chosen
+    let msgs = chosen(&mut proposer, &mut learner, 0, put("a", "1"));
+    assert_eq!(accepts(&msgs), vec![(2, put("c", "1"))]);
+    assert_eq!(proposer.take_responses(), vec![(0, 0, Outcome::PutOk)]);
+}
+
+#[test]
+fn test_queued_requests_are_batched() {
+    let mut proposer = leader(1);
+    let mut learner = Learner::new();
+    proposer.propose(0, "put\nk\nv").unwrap();
+    for id in 1..4 {
+        assert!(proposer.propose(id, "incr\nn").unwrap().is_empty());
+    }
+
+    let incr = || Command::from_client_request("incr\nn").unwrap();
+    let msgs = chosen(&mut proposer, &mut learner, 0, put("k", "v"));
+    assert_eq!(
+        accepts(&msgs),
+        vec![(1, Command::Batch(vec![incr(), incr()]))]
+    );
+
+    // each request gets its own outcome from the batch
+    let msgs = chosen(
+        &mut proposer,
+        &mut learner,
+        1,
+        Command::Batch(vec![incr(), incr()]),
+    );
+    assert_eq!(accepts(&msgs), vec![(2, incr())]);
+    let updated = |value: &str| Outcome::Updated(value.to_string());
+    assert_eq!(
+        proposer.take_responses(),
+        vec![
+            (0, 0, Outcome::PutOk),
+            (1, 1, updated("1")),
+            (2, 1, updated("2"))
+        ]
+    );
+    assert!(!proposer.is_idle());
+}
+
+#[test]
+fn test_batch_that_lost_its_slot_is_retried() {
+    let mut proposer = leader(1);
+    let mut learner = Learner::new();
+    proposer.propose(0, "put\nk\nv").unwrap();
+    proposer.propose(1, "put\na\n1").unwrap();
+    proposer.propose(2, "put\nb\n1").unwrap();
+    let msgs = chosen(&mut proposer, &mut learner, 0, put("k", "v"));
+    assert_eq!(
+        accepts(&msgs),
+        vec![(1, Command::Batch(vec![put("a", "1"), put("b", "1")]))]
+    );
+
+    // another leader's command was chosen at slot 1
+    let msgs = chosen(&mut proposer, &mut learner, 1, put("theirs", "2"));
+    assert_eq!(
+        accepts(&msgs),
+        vec![(2, Command::Batch(vec![put("a", "1"), put("b", "1")]))]
+    );
+    assert_eq!(proposer.take_responses(), vec![(0, 0, Outcome::PutOk)]);
+}
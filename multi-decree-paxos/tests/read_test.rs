@@ -0,0 +1,148 @@
+use multi_decree_paxos::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Three replicas joined by a lossless in-memory network. Messages to or from
+/// an isolated replica are dropped.
+struct Cluster {
+    replicas: Vec<Replica>,
+    isolated: Vec<bool>,
+    network: VecDeque<(usize, usize, Message)>,
+    /// Every client response, with the replica that sent it.
+    responses: Vec<(usize, u64, Outcome)>,
+}
+
+impl Cluster {
+    fn new() -> Cluster {
+        Cluster {
+            replicas: (0..3).map(|id| Replica::new(id, 3)).collect(),
+            isolated: vec![false; 3],
+            network: VecDeque::new(),
+            responses: vec![],
+        }
+    }
+
+    fn dispatch(&mut self, from: usize, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(to, msg) => self.network.push_back((from, to, msg)),
+                Action::Broadcast(msg) => {
+                    for to in 0..self.replicas.len() {
+                        self.network.push_back((from, to, msg.clone()));
+                    }
+                }
+                Action::Respond(_, slot, outcome) => self.responses.push((from, slot, outcome)),
+            }
+        }
+    }
+
+    /// Delivers messages until the network is quiet.
+    fn run(&mut self) {
+        while let Some((from, to, msg)) = self.network.pop_front() {
+            if !self.isolated[from] && !self.isolated[to] {
+                let actions = self.replicas[to].handle_msg(from, &msg);
+                self.dispatch(to, actions);
+            }
+        }
+    }
+
+    /// Sends `request` to replica `id` and returns what the messages it
+    /// causes first ask for.
+    fn propose(&mut self, id: usize, request: &str) -> Vec<Action> {
+        let actions = self.replicas[id].propose(0, request).unwrap();
+        self.dispatch(id, actions.clone());
+        actions
+    }
+
+    fn tick(&mut self, id: usize, now: Instant) {
+        let actions = self.replicas[id].tick(now);
+        self.dispatch(id, actions);
+    }
+}
+
+#[test]
+fn test_leader_reads_without_log_entry() {
+    let mut cluster = Cluster::new();
+    cluster.propose(0, "put\nk\nv");
+    cluster.run();
+    assert_eq!(cluster.responses, vec![(0, 0, Outcome::PutOk)]);
+    assert!(cluster.replicas[0].proposer().is_leader());
+
+    let actions = cluster.propose(0, "get\nk");
+    assert!(matches!(
+        actions[..],
+        [Action::Broadcast(Message::Confirm { .. })]
+    ));
+    cluster.propose(0, "scan\na");
+    cluster.run();
+    let entries = vec![("k".to_string(), "v".to_string())];
+    assert_eq!(
+        cluster.responses[1..],
+        [
+            (0, 1, Outcome::Value("v".to_string())),
+            (0, 1, Outcome::Entries(entries))
+        ]
+    );
+    for replica in &cluster.replicas {
+        assert_eq!(replica.learner().next_slot(), 1);
+        assert_eq!(replica.acceptor().next_slot(), 1);
+    }
+    assert!(cluster.replicas[0].is_idle());
+}
+
+#[test]
+fn test_deposed_leader_does_not_serve_reads() {
+    let mut cluster = Cluster::new();
+    cluster.propose(0, "put\nk\nold");
+    cluster.run();
+
+    // replica 1 takes over behind the old leader's back
+    cluster.isolated[0] = true;
+    cluster.propose(1, "put\nk\nnew");
+    cluster.run();
+    assert!(cluster.replicas[1].proposer().is_leader());
+    assert_eq!(cluster.responses.len(), 2);
+
+    // only the old leader's own acceptor still vouches for it
+    cluster.isolated[0] = false;
+    cluster.propose(0, "get\nk");
+    cluster.run();
+    assert_eq!(cluster.responses.len(), 2);
+    assert!(!cluster.replicas[0].is_idle());
+
+    // once it hears from the new leader, the read is sent there instead, and
+    // the next heartbeat makes it catch up
+    let now = Instant::now();
+    for later in [now + Duration::from_secs(1), now + Duration::from_secs(2)] {
+        cluster.tick(1, later);
+        cluster.run();
+    }
+    assert!(!cluster.replicas[0].proposer().is_leader());
+    assert_eq!(
+        cluster.responses[2..],
+        [(0, 1, Outcome::NotLeader(Some(1)))]
+    );
+    assert!(cluster.replicas[0].is_idle());
+    assert_eq!(
+        cluster.replicas[0].learner().get_value("k"),
+        Some("new".to_string())
+    );
+}
+
+#[test]
+fn test_stale_reads_are_served_locally() {
+    let mut cluster = Cluster::new();
+    cluster.isolated[2] = true;
+    cluster.propose(0, "put\nk\nv");
+    cluster.run();
+
+    assert_eq!(
+        cluster.propose(2, "stale\nget\nk"),
+        vec![Action::Respond(0, 0, Outcome::NotFound)]
+    );
+    assert_eq!(
+        cluster.propose(0, "STALE\nget\nk"),
+        vec![Action::Respond(0, 1, Outcome::Value("v".to_string()))]
+    );
+    assert_eq!(cluster.replicas[2].propose(0, "stale\nput\nk\nw"), None);
+}
@@ -0,0 +1,140 @@
+use multi_decree_paxos::*;
+
+fn append(client: u64, seq: u64, value: &str) -> Command {
+    Command::Session {
+        client,
+        seq,
+        command: Box::new(Command::Append {
+            key: "k".to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// Chooses `command` at the learner's next slot and returns its outcome.
+fn apply(learner: &mut Learner, command: Command) -> Outcome {
+    let msg = Message::Chosen {
+        slot: learner.next_slot(),
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    match learner.handle_msg(0, &msg).pop() {
+        Some(Message::Response { outcome, .. }) => outcome,
+        other => panic!("expected a RESPONSE, got {:?}", other),
+    }
+}
+
+fn updated(value: &str) -> Outcome {
+    Outcome::Updated(value.to_string())
+}
+
+#[test]
+fn test_session_requests_parse_and_print() {
+    let msg = "session\n3\n7\nappend\nk\nv";
+    let command = Command::from_client_request(msg).unwrap();
+    assert_eq!(command, append(3, 7, "v"));
+    assert_eq!(command.to_client_request().unwrap(), msg);
+    assert_eq!(command.key(), Some("k"));
+
+    let get = Command::from_client_request("session\n3\n8\nget\nk").unwrap();
+    assert!(get.is_read_only());
+    assert_eq!(Command::from_client_request("session\n3\nget\nk"), None);
+    assert_eq!(Command::from_client_request("session\n3\n-1\nget\nk"), None);
+    assert_eq!(
+        Command::from_client_request("session\n3\n9\nsession\n4\n1\nget\nk"),
+        None
+    );
+}
+
+#[test]
+fn test_retried_request_is_applied_once() {
+    let mut learner = Learner::new();
+    assert_eq!(apply(&mut learner, append(1, 0, "a")), updated("a"));
+    assert_eq!(apply(&mut learner, append(2, 0, "b")), updated("ab"));
+
+    // the retry of client 1's request landed in a later slot as well
+    assert_eq!(apply(&mut learner, append(1, 0, "a")), updated("a"));
+    assert_eq!(learner.get_value("k"), Some("ab".to_string()));
+
+    assert_eq!(apply(&mut learner, append(1, 1, "c")), updated("abc"));
+    // a copy of an older request that was still in flight is dropped
+    assert_eq!(apply(&mut learner, append(1, 0, "a")), Outcome::Expired);
+    assert_eq!(learner.get_value("k"), Some("abc".to_string()));
+
+    // requests without a session are applied every time
+    let plain = Command::Append {
+        key: "k".to_string(),
+        value: "d".to_string(),
+    };
+    apply(&mut learner, plain.clone());
+    assert_eq!(apply(&mut learner, plain), updated("abcdd"));
+}
+
+#[test]
+fn test_sessions_survive_snapshots() {
+    let mut peer = Learner::new();
+    peer.set_snapshot_interval(2);
+    apply(&mut peer, append(1, 0, "a"));
+    apply(&mut peer, append(2, 5, "b"));
+    assert_eq!(peer.snapshot_slot(), 2);
+
+    let Some(snapshot) = peer.handle_msg(1, &Message::CatchUp { slot: 0 }).pop() else {
+        panic!("expected a SNAPSHOT");
+    };
+    assert_eq!(
+        snapshot,
+        Message::Snapshot {
+            slot: 2,
+            state: {
+                let mut store = KvStore::default();
+                store.apply(
+                    0,
+                    &Command::Put {
+                        key: "k".to_string(),
+                        value: "ab".to_string(),
+                    },
+                );
+                store.snapshot()
+            },
+            sessions: vec![(1, 0, updated("a"), 0), (2, 5, updated("ab"), 1)],
+            configs: vec![(0, vec![0])],
+        }
+    );
+    let mut learner = Learner::new();
+    learner.handle_msg(0, &snapshot);
+    assert_eq!(apply(&mut learner, append(2, 5, "b")), updated("ab"));
+    assert_eq!(apply(&mut learner, append(2, 4, "b")), Outcome::Expired);
+    assert_eq!(learner.get_value("k"), Some("ab".to_string()));
+}
+
+#[test]
+fn test_idle_sessions_expire_at_the_same_slot_everywhere() {
+    let noops = |learner: &mut Learner, until| {
+        while learner.next_slot() < until {
+            apply(learner, Command::Noop);
+        }
+    };
+    let mut peer = Learner::new();
+    peer.set_session_expiry(10);
+    peer.set_snapshot_interval(8);
+    apply(&mut peer, append(1, 0, "a"));
+    noops(&mut peer, 5);
+    apply(&mut peer, append(2, 0, "b"));
+    noops(&mut peer, 8);
+
+    // a learner brought up to date by a snapshot knows when each session
+    // was last used
+    let Some(snapshot) = peer.handle_msg(1, &Message::CatchUp { slot: 0 }).pop() else {
+        panic!("expected a SNAPSHOT");
+    };
+    let mut lagging = Learner::new();
+    lagging.set_session_expiry(10);
+    lagging.handle_msg(0, &snapshot);
+    for learner in [&mut peer, &mut lagging] {
+        noops(learner, 10);
+        // client 1 was idle for 10 slots, so its session started over
+        assert_eq!(apply(learner, append(1, 0, "a")), updated("aba"));
+        assert_eq!(apply(learner, append(2, 0, "b")), updated("ab"));
+        assert_eq!(apply(learner, append(1, 0, "a")), updated("aba"));
+    }
+}
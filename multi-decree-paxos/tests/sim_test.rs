@@ -0,0 +1,65 @@
+use multi_decree_paxos::*;
+use std::time::Duration;
+
+/// Runs `seed`, panicking with the violation or a history that is not
+/// linearizable. Set `PAXOS_SIM_SEED` to rerun a single failing seed, and
+/// `PAXOS_SIM_TRACE` to print its schedule.
+fn run(seed: u64) -> Report {
+    let config = SimConfig {
+        seed,
+        trace: std::env::var_os("PAXOS_SIM_TRACE").is_some(),
+        ..SimConfig::default()
+    };
+    let report = match Simulation::new(config).run() {
+        Ok(report) => report,
+        Err(violation) => panic!("{}", violation),
+    };
+    if let Err(error) = check_linearizable(&report.history) {
+        panic!("seed {}: {}", seed, error);
+    }
+    report
+}
+
+fn seeds() -> Vec<u64> {
+    match std::env::var("PAXOS_SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("PAXOS_SIM_SEED must be a number")],
+        Err(_) => (0..20).collect(),
+    }
+}
+
+#[test]
+fn test_random_schedules_agree() {
+    for seed in seeds() {
+        let report = run(seed);
+        let completed = report
+            .history
+            .iter()
+            .filter(|op| op.completed.is_some())
+            .count();
+        assert!(completed > 0, "seed {}: no request completed", seed);
+        assert!(report.slots > 0);
+    }
+}
+
+#[test]
+fn test_same_seed_same_run() {
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7).history, run(8).history);
+}
+
+#[test]
+fn test_crashes_and_duplicates_without_drops() {
+    let config = SimConfig {
+        seed: 42,
+        drop_rate: 0.0,
+        duplicate_rate: 0.3,
+        crash_rate: 0.5,
+        duration: Duration::from_secs(5),
+        ..SimConfig::default()
+    };
+    let report = Simulation::new(config)
+        .run()
+        .unwrap_or_else(|v| panic!("{}", v));
+    assert!(report.crashes > 0);
+    check_linearizable(&report.history).unwrap_or_else(|e| panic!("{}", e));
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use common::{log_path, open, put};
+use multi_decree_paxos::*;
+
+/// A key-value store snapshot holding `entries`.
+fn kv_state(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut store = KvStore::default();
+    for (key, value) in entries {
+        store.apply(0, &put(key, value));
+    }
+    store.snapshot()
+}
+
+/// Delivers ACCEPTED from a quorum of two out of three replicas.
+fn choose(learner: &mut Learner, slot: u64, command: Command) -> Vec<Message> {
+    let accepted = Message::Accepted {
+        slot,
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    learner.handle_msg(0, &accepted);
+    learner.handle_msg(1, &accepted)
+}
+
+#[test]
+fn test_learner_snapshot_replaces_log() {
+    let path = log_path("learner.wal");
+    {
+        let mut learner = Learner::with_storage(open(&path)).unwrap();
+        learner.set_f(1);
+        learner.set_snapshot_interval(3);
+        for slot in 0..4 {
+            choose(&mut learner, slot, put("k", &slot.to_string()));
+        }
+        assert_eq!(learner.snapshot_slot(), 3);
+    }
+
+    let records = FileStorage::open(&path).unwrap().load().unwrap();
+    assert_eq!(
+        records[0],
+        Record::Snapshot {
+            slot: 3,
+            state: kv_state(&[("k", "2")]),
+            sessions: vec![],
+            configs: vec![(0, vec![0, 1, 2])],
+        }
+    );
+    assert_eq!(records.len(), 2);
+
+    let learner = Learner::with_storage(open(&path)).unwrap();
+    assert_eq!(learner.next_slot(), 4);
+    assert_eq!(learner.snapshot_slot(), 3);
+    assert_eq!(learner.get_value("k"), Some("3".to_string()));
+}
+
+#[test]
+fn test_lagging_learner_installs_snapshot() {
+    let mut peer = Learner::new();
+    peer.set_f(1);
+    peer.set_snapshot_interval(3);
+    for slot in 0..3 {
+        choose(&mut peer, slot, put(&slot.to_string(), "v"));
+    }
+
+    // the laggard noticed it is behind across two heartbeats
+    let mut learner = Learner::new();
+    learner.set_f(1);
+    let heartbeat = Message::Heartbeat {
+        slot: 3,
+        proposal_number: Ballot::new(1, 0),
+    };
+    assert_eq!(learner.handle_msg(0, &heartbeat), vec![]);
+    let catch_up = learner.handle_msg(0, &heartbeat);
+    assert_eq!(catch_up, vec![Message::CatchUp { slot: 0 }]);
+
+    // slots 0 to 2 only survive in the peer's snapshot
+    let reply = peer.handle_msg(2, &catch_up[0]);
+    assert_eq!(
+        reply,
+        vec![Message::Snapshot {
+            slot: 3,
+            state: kv_state(&[("0", "v"), ("1", "v"), ("2", "v")]),
+            sessions: vec![],
+            configs: vec![(0, vec![0, 1, 2])],
+        }]
+    );
+    for msg in &reply {
+        learner.handle_msg(0, msg);
+    }
+    assert_eq!(learner.next_slot(), 3);
+    assert_eq!(
+        learner.state_machine().entries(),
+        peer.state_machine().entries()
+    );
+
+    // later slots are sent as chosen commands
+    choose(&mut peer, 3, put("3", "v"));
+    let reply = peer.handle_msg(2, &Message::CatchUp { slot: 3 });
+    assert_eq!(
+        reply,
+        vec![Message::Chosen {
+            slot: 3,
+            proposal_number: Ballot::new(1, 0),
+            command: put("3", "v"),
+        }]
+    );
+    let responses = learner.handle_msg(0, &reply[0]);
+    assert!(matches!(
+        responses[..],
+        [Message::Response {
+            slot: 3,
+            outcome: Outcome::PutOk,
+            ..
+        }]
+    ));
+    assert_eq!(
+        learner.state_machine().entries(),
+        peer.state_machine().entries()
+    );
+}
+
+#[test]
+fn test_acceptor_compaction() {
+    let path = log_path("acceptor.wal");
+    let ballot = Ballot::new(2, 0);
+    {
+        let mut acceptor = Acceptor::with_storage(open(&path)).unwrap();
+        for slot in 0..5 {
+            acceptor.handle_msg(
+                0,
+                &Message::Accept {
+                    slot,
+                    proposal_number: ballot,
+                    command: put("k", "v"),
+                },
+            );
+        }
+        acceptor.compact(3).unwrap();
+        assert_eq!(acceptor.log_start(), 3);
+    }
+
+    let mut acceptor = Acceptor::with_storage(open(&path)).unwrap();
+    assert_eq!(acceptor.log_start(), 3);
+    assert_eq!(acceptor.promised_proposal_number(), ballot);
+    let Message::Promise {
+        log_start,
+        accepted,
+        ..
+    } = &acceptor.handle_msg(
+        1,
+        &Message::Prepare {
+            slot: 0,
+            proposal_number: Ballot::new(3, 1),
+        },
+    )[0]
+    else {
+        panic!("expected a promise");
+    };
+    assert_eq!(*log_start, 3);
+    assert_eq!(
+        accepted
+            .iter()
+            .map(|pvalue| pvalue.slot)
+            .collect::<Vec<_>>(),
+        vec![3, 4]
+    );
+}
+
+#[test]
+fn test_leader_skips_compacted_slots() {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    proposer.set_id(1);
+    let Message::Prepare {
+        slot,
+        proposal_number,
+    } = proposer.send_prepare()
+    else {
+        panic!("expected a prepare");
+    };
+    for (from, log_start) in [(0, 4), (1, 0)] {
+        let promise = Message::Promise {
+            slot,
+            proposal_number,
+            log_start,
+            accepted: vec![],
+        };
+        let msgs = proposer.handle_msg(from, &promise);
+        if from == 1 {
+            // nothing to fill in: slots below 4 are already chosen
+            assert_eq!(msgs, vec![]);
+        }
+    }
+    assert!(proposer.is_leader());
+    assert_eq!(proposer.fill_gaps(&[2, 3, 4]).len(), 1);
+}
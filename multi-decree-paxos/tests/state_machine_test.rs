@@ -0,0 +1,171 @@
+mod common;
+
+use common::put;
+use multi_decree_paxos::*;
+use std::collections::VecDeque;
+
+/// Adds up every INCR, whatever its key, and answers GET with the total.
+#[derive(Default)]
+struct Counter {
+    total: i64,
+}
+
+impl StateMachine for Counter {
+    fn apply(&mut self, _slot: u64, command: &Command) -> Outcome {
+        match command {
+            Command::Incr { by, .. } => {
+                self.total += by;
+                Outcome::Updated(self.total.to_string())
+            }
+            command => self.read(command).unwrap_or(Outcome::Noop),
+        }
+    }
+
+    fn read(&self, command: &Command) -> Option<Outcome> {
+        match command {
+            Command::Get { .. } => Some(Outcome::Value(self.total.to_string())),
+            _ => None,
+        }
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        vec![("total".to_string(), self.total.to_string())]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.total.to_be_bytes().to_vec()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), DecodeError> {
+        let total = snapshot
+            .try_into()
+            .map_err(|_| DecodeError::Malformed("counter snapshot"))?;
+        self.total = i64::from_be_bytes(total);
+        Ok(())
+    }
+}
+
+fn incr(by: i64) -> Command {
+    Command::Incr {
+        key: "n".to_string(),
+        by,
+    }
+}
+
+/// Chooses `command` at the learner's next slot.
+fn choose(learner: &mut Learner, command: Command) -> Vec<Message> {
+    let msg = Message::Chosen {
+        slot: learner.next_slot(),
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    learner.handle_msg(0, &msg)
+}
+
+#[test]
+fn test_kv_store_snapshots_restore_the_same_store() {
+    let mut store = KvStore::default();
+    store.apply(0, &put("b", "2"));
+    store.apply(1, &put("a", "1"));
+    assert_eq!(
+        store.apply(
+            2,
+            &Command::Incr {
+                key: "a".to_string(),
+                by: 4,
+            }
+        ),
+        Outcome::Updated("5".to_string())
+    );
+
+    let mut restored = KvStore::default();
+    restored.restore(&store.snapshot()).unwrap();
+    assert_eq!(restored, store);
+    assert_eq!(
+        restored.entries(),
+        vec![
+            ("a".to_string(), "5".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ]
+    );
+
+    // a snapshot that does not decode leaves the store as it was
+    let mut snapshot = store.snapshot();
+    snapshot.pop();
+    assert!(restored.restore(&snapshot).is_err());
+    assert_eq!(restored, store);
+}
+
+#[test]
+fn test_replicas_apply_commands_to_their_state_machine() {
+    let mut replicas: Vec<Replica> = (0..3)
+        .map(|id| Replica::with_state_machine(id, 3, Box::new(Counter::default())))
+        .collect();
+    let mut network = VecDeque::new();
+    let mut outcomes = vec![];
+    for (id, request) in ["incr\na\n2", "incr\nb\n3", "get\nc"].iter().enumerate() {
+        network.extend(
+            replicas[0]
+                .propose(id as u64, request)
+                .unwrap()
+                .into_iter()
+                .map(|action| (0, action)),
+        );
+        while let Some((from, action)) = network.pop_front() {
+            let (to, msg) = match action {
+                Action::Send(to, msg) => (vec![to], msg),
+                Action::Broadcast(msg) => ((0..3).collect(), msg),
+                Action::Respond(_, _, outcome) => {
+                    outcomes.push(outcome);
+                    continue;
+                }
+            };
+            for to in to {
+                let actions = replicas[to].handle_msg(from, &msg);
+                network.extend(actions.into_iter().map(|action| (to, action)));
+            }
+        }
+    }
+
+    assert_eq!(
+        outcomes,
+        vec![
+            Outcome::Updated("2".to_string()),
+            Outcome::Updated("5".to_string()),
+            Outcome::Value("5".to_string()),
+        ]
+    );
+    for replica in &replicas {
+        assert_eq!(replica.learner().get_value("any"), Some("5".to_string()));
+        let status = replica.status();
+        assert_eq!(status.keys, 1);
+    }
+}
+
+#[test]
+fn test_lagging_learner_restores_a_snapshot_of_its_state_machine() {
+    let storage = || Box::new(MemStorage::default());
+    let mut peer = Learner::with_state_machine(Box::new(Counter::default()), storage()).unwrap();
+    peer.set_snapshot_interval(2);
+    for by in [1, 2, 3] {
+        choose(&mut peer, incr(by));
+    }
+    choose(&mut peer, Command::Noop);
+    assert_eq!(peer.snapshot_slot(), 4);
+
+    let mut learner = Learner::with_state_machine(Box::new(Counter::default()), storage()).unwrap();
+    for msg in peer.handle_msg(1, &Message::CatchUp { slot: 0 }) {
+        assert!(matches!(msg, Message::Snapshot { slot: 4, .. }));
+        learner.handle_msg(0, &msg);
+    }
+    assert_eq!(learner.next_slot(), 4);
+    assert_eq!(
+        learner.state_machine().entries(),
+        vec![("total".to_string(), "6".to_string())]
+    );
+    let outcome = choose(&mut learner, incr(4));
+    assert!(matches!(
+        &outcome[..],
+        [Message::Response { outcome: Outcome::Updated(total), .. }] if total == "10"
+    ));
+}
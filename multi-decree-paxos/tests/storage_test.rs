@@ -0,0 +1,133 @@
+mod common;
+
+use common::{log_path, open, put};
+use multi_decree_paxos::*;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+#[test]
+fn test_acceptor_remembers_promises_across_restart() {
+    let path = log_path("acceptor.wal");
+    let ballot = Ballot::new(3, 1);
+    {
+        let mut acceptor = Acceptor::with_storage(open(&path)).unwrap();
+        acceptor.handle_msg(
+            1,
+            &Message::Prepare {
+                slot: 0,
+                proposal_number: ballot,
+            },
+        );
+        acceptor.handle_msg(
+            1,
+            &Message::Accept {
+                slot: 4,
+                proposal_number: ballot,
+                command: put("k", "v"),
+            },
+        );
+    }
+
+    let mut acceptor = Acceptor::with_storage(open(&path)).unwrap();
+    assert_eq!(acceptor.promised_proposal_number(), ballot);
+    assert_eq!(acceptor.next_slot(), 5);
+
+    // a lower ballot is still refused after the restart
+    let stale = Message::Prepare {
+        slot: 0,
+        proposal_number: Ballot::new(2, 0),
+    };
+    assert!(matches!(
+        acceptor.handle_msg(0, &stale)[..],
+        [Message::Nack { .. }]
+    ));
+
+    // and the accepted value is still reported to a new leader
+    let prepare = Message::Prepare {
+        slot: 0,
+        proposal_number: Ballot::new(4, 0),
+    };
+    assert_eq!(
+        acceptor.handle_msg(0, &prepare),
+        vec![Message::Promise {
+            slot: 0,
+            proposal_number: Ballot::new(4, 0),
+            log_start: 0,
+            accepted: vec![PValue {
+                slot: 4,
+                proposal_number: ballot,
+                command: put("k", "v"),
+            }],
+        }]
+    );
+}
+
+#[test]
+fn test_learner_replays_chosen_log() {
+    let path = log_path("learner.wal");
+    {
+        let mut learner = Learner::with_storage(open(&path)).unwrap();
+        learner.set_f(0);
+        for (slot, value) in ["a", "b"].iter().enumerate() {
+            learner.handle_msg(
+                0,
+                &Message::Accepted {
+                    slot: slot as u64,
+                    proposal_number: Ballot::new(1, 0),
+                    command: put("k", value),
+                },
+            );
+        }
+    }
+
+    let learner = Learner::with_storage(open(&path)).unwrap();
+    assert_eq!(learner.next_slot(), 2);
+    assert_eq!(learner.get_value("k"), Some("b".to_string()));
+}
+
+#[test]
+fn test_torn_record_is_discarded() {
+    let path = log_path("torn.wal");
+    let record = Record::Promised {
+        proposal_number: Ballot::new(1, 0),
+    };
+    open(&path).append(std::slice::from_ref(&record)).unwrap();
+
+    // a crash in the middle of the second append
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 0, 12, 1, 2]).unwrap();
+
+    let mut storage = open(&path);
+    assert_eq!(storage.load().unwrap(), vec![record.clone()]);
+
+    // the log keeps working after the torn tail is cut off
+    let next = Record::Promised {
+        proposal_number: Ballot::new(2, 0),
+    };
+    storage.append(std::slice::from_ref(&next)).unwrap();
+    assert_eq!(open(&path).load().unwrap(), vec![record, next]);
+}
+
+#[test]
+fn test_corrupt_record_fails_the_load() {
+    let path = log_path("corrupt.wal");
+    let records: Vec<Record> = (1..=3)
+        .map(|round| Record::Promised {
+            proposal_number: Ballot::new(round, 0),
+        })
+        .collect();
+    open(&path).append(&records).unwrap();
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    // a flipped bit in the second record's payload
+    let mut bytes = std::fs::read(&path).unwrap();
+    let record_len = bytes.len() / 3;
+    bytes[record_len + record_len - 1] ^= 1;
+    std::fs::write(&path, &bytes).unwrap();
+
+    let error = open(&path).load().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    // nothing was cut off
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    assert!(Acceptor::with_storage(open(&path)).is_err());
+}
@@ -0,0 +1,159 @@
+use multi_decree_paxos::*;
+use std::time::{Duration, Instant};
+
+fn timeouts() -> Timeouts {
+    Timeouts {
+        heartbeat_interval: Duration::from_millis(100),
+        election_timeout: Duration::from_millis(500),
+        prepare_timeout: Duration::from_millis(200),
+        accept_timeout: Duration::from_millis(200),
+        backoff_min: Duration::from_millis(100),
+        backoff_max: Duration::from_millis(1000),
+    }
+}
+
+/// A proposer of a three replica cluster whose clock starts at the returned
+/// instant.
+fn proposer(id: usize) -> (Proposer, Instant) {
+    let mut proposer = Proposer::new();
+    proposer.set_f(1);
+    proposer.set_id(id);
+    proposer.set_timeouts(timeouts());
+    let start = Instant::now();
+    proposer.tick(start);
+    (proposer, start)
+}
+
+/// Ticks from `from` in 10ms steps until the proposer sends something other
+/// than a HEARTBEAT.
+fn tick_until_sent(proposer: &mut Proposer, from: Instant) -> (Duration, Vec<Message>) {
+    let mut now = from;
+    loop {
+        now += Duration::from_millis(10);
+        let mut msgs = proposer.tick(now);
+        msgs.retain(|msg| !matches!(msg, Message::Heartbeat { .. }));
+        if !msgs.is_empty() {
+            return (now - from, msgs);
+        }
+        assert!(now - from < Duration::from_secs(10), "nothing was sent");
+    }
+}
+
+#[test]
+fn test_lost_promise_resends_prepare() {
+    let (mut proposer, start) = proposer(1);
+    let mut acceptors = [Acceptor::new(), Acceptor::new()];
+    let prepare = proposer.send_prepare();
+
+    // only one PROMISE gets through
+    let promise = acceptors[0].handle_msg(1, &prepare).remove(0);
+    proposer.handle_msg(0, &promise);
+    assert!(!proposer.is_leader());
+
+    // the same PREPARE goes out again, and a repeated one is promised again
+    let (waited, msgs) = tick_until_sent(&mut proposer, start);
+    assert!(waited >= Duration::from_millis(100) && waited <= Duration::from_millis(210));
+    assert_eq!(msgs, vec![prepare.clone()]);
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        let reply = acceptor.handle_msg(1, &prepare).remove(0);
+        assert!(matches!(reply, Message::Promise { .. }));
+        proposer.handle_msg(i, &reply);
+    }
+    assert!(proposer.is_leader());
+}
+
+#[test]
+fn test_lost_accepted_resends_accept() {
+    let (mut proposer, start) = proposer(0);
+    let prepare = proposer.send_prepare();
+    let mut acceptors = [Acceptor::new(), Acceptor::new()];
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        let promise = acceptor.handle_msg(0, &prepare).remove(0);
+        proposer.handle_msg(i, &promise);
+    }
+    let accept = proposer.propose(0, "put\nk\nv").unwrap().remove(0);
+
+    // the ACCEPT is resent with growing gaps until a quorum answers
+    let (first, msgs) = tick_until_sent(&mut proposer, start);
+    assert_eq!(msgs, vec![accept.clone()]);
+    let (second, msgs) = tick_until_sent(&mut proposer, start + first);
+    assert_eq!(msgs, vec![accept.clone()]);
+    assert!(second >= Duration::from_millis(200) && second <= Duration::from_millis(410));
+
+    for (i, acceptor) in acceptors.iter_mut().enumerate() {
+        let accepted = acceptor.handle_msg(0, &accept).remove(0);
+        proposer.handle_msg(i, &accepted);
+    }
+    let later = start + Duration::from_secs(5);
+    assert!(proposer
+        .tick(later)
+        .iter()
+        .all(|msg| matches!(msg, Message::Heartbeat { .. })));
+}
+
+#[test]
+fn test_unaccepted_restarts_with_higher_ballot() {
+    let (mut proposer, start) = proposer(0);
+    let prepare = proposer.send_prepare();
+    let mut acceptor = Acceptor::new();
+    for i in 0..2 {
+        let promise = acceptor.handle_msg(0, &prepare).remove(0);
+        proposer.handle_msg(i, &promise);
+    }
+    assert!(proposer.is_leader());
+
+    // another proposer got ballot 7 promised in the meantime
+    let accept = proposer.propose(0, "put\nk\nv").unwrap().remove(0);
+    acceptor.handle_msg(
+        2,
+        &Message::Prepare {
+            slot: 0,
+            proposal_number: Ballot::new(7, 2),
+        },
+    );
+    let unaccepted = acceptor.handle_msg(0, &accept).remove(0);
+    proposer.handle_msg(0, &unaccepted);
+    assert!(!proposer.is_leader());
+
+    // after a short backoff it runs again, above the ballot that beat it
+    let (waited, msgs) = tick_until_sent(&mut proposer, start);
+    assert!(waited <= Duration::from_millis(110));
+    assert_eq!(
+        msgs,
+        vec![Message::Prepare {
+            slot: 0,
+            proposal_number: Ballot::new(8, 0),
+        }]
+    );
+}
+
+#[test]
+fn test_nack_backoff_grows() {
+    let (mut proposer, start) = proposer(2);
+    let mut now = start;
+    let mut waits = vec![];
+    for _ in 0..6 {
+        let prepare = proposer.send_prepare();
+        let nack = Message::Nack {
+            slot: 0,
+            proposal_number: prepare.proposal_number().unwrap(),
+            promised_proposal_number: Ballot::new(1000, 0),
+        };
+        proposer.handle_msg(0, &nack);
+        let (waited, msgs) = tick_until_sent(&mut proposer, now);
+        assert!(matches!(msgs[..], [Message::Prepare { .. }]));
+        waits.push(waited);
+        now += waited;
+    }
+    // 100ms, 200ms, 400ms, ... capped at 1s, each jittered down to half
+    for (attempt, waited) in waits.into_iter().enumerate() {
+        let base = Duration::from_millis(100 << attempt).min(Duration::from_secs(1));
+        assert!(
+            waited >= base / 2,
+            "attempt {} waited {:?}",
+            attempt,
+            waited
+        );
+        assert!(waited <= base + Duration::from_millis(10));
+    }
+}
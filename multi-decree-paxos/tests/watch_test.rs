@@ -0,0 +1,286 @@
+mod common;
+
+use common::{cluster_config, local_addr, start_cluster};
+use multi_decree_paxos::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::spawn;
+
+fn request(msg: &str) -> Command {
+    Command::from_client_request(msg).unwrap()
+}
+
+/// Chooses `command` at the learner's next slot.
+fn choose(learner: &mut Learner, command: Command) {
+    let msg = Message::Chosen {
+        slot: learner.next_slot(),
+        proposal_number: Ballot::new(1, 0),
+        command,
+    };
+    learner.handle_msg(0, &msg);
+}
+
+fn change(slot: u64, key: &str, old: Option<&str>, new: Option<&str>) -> Change {
+    Change {
+        slot,
+        key: key.to_string(),
+        old: old.map(str::to_string),
+        new: new.map(str::to_string),
+    }
+}
+
+/// Plays a node that takes one watch connection, checks its request and
+/// streams `events` before hanging up.
+fn fake_node(expected: &'static str, events: Vec<WatchEvent>) -> SocketAddr {
+    let listener = TcpListener::bind(local_addr()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut head = [0; 5];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0);
+        let mut request = vec![0; u32::from_be_bytes(head[1..].try_into().unwrap()) as usize];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(request, expected.as_bytes());
+        for event in events {
+            let event = event.to_string();
+            stream
+                .write_all(&(event.len() as u32).to_be_bytes())
+                .unwrap();
+            stream.write_all(event.as_bytes()).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_watch_requests_and_events_parse_and_print() {
+    let watch = Watch::from_client_request("watch\nprefix\nuser/\n12").unwrap();
+    assert_eq!(
+        watch,
+        Watch {
+            target: WatchTarget::Prefix("user/".to_string()),
+            from: Some(12),
+        }
+    );
+    assert_eq!(watch.to_client_request(), "watch\nprefix\nuser/\n12");
+    assert!(watch.matches("user/1") && !watch.matches("users"));
+    let watch = Watch::from_client_request("WATCH\nkey\nk").unwrap();
+    assert_eq!(watch.target, WatchTarget::Key("k".to_string()));
+    assert!(watch.matches("k") && !watch.matches("k2"));
+    for msg in [
+        "watch\nkey",
+        "watch\nrange\na",
+        "watch\nkey\nk\nsoon",
+        "get\nk",
+    ] {
+        assert_eq!(Watch::from_client_request(msg), None);
+    }
+
+    let events = [
+        WatchEvent::Started(3),
+        WatchEvent::Compacted(40),
+        WatchEvent::Change(change(5, "k", None, Some("v"))),
+        WatchEvent::Change(change(6, "k", Some("v"), Some(""))),
+        WatchEvent::Change(change(7, "k", Some(""), None)),
+    ];
+    for event in events {
+        assert_eq!(WatchEvent::from_response(&event.to_string()), Some(event));
+    }
+    assert_eq!(
+        WatchEvent::Change(change(6, "k", Some("v"), Some("w"))).to_string(),
+        "change slot:6 updated\nk\nv\nw"
+    );
+    assert_eq!(WatchEvent::from_response("change slot:6 deleted\nk"), None);
+}
+
+#[test]
+fn test_learner_records_the_changes_it_applies() {
+    let mut learner = Learner::new();
+    choose(&mut learner, request("put\na\n1"));
+    // writes that leave the value as it was are not changes
+    choose(&mut learner, request("put\na\n1"));
+    choose(&mut learner, request("cas\na\n0\n2"));
+    choose(&mut learner, request("get\na"));
+    choose(
+        &mut learner,
+        Command::Batch(vec![request("append\na\n2"), request("put\nb\nx")]),
+    );
+    choose(&mut learner, request("delete\na"));
+
+    let changes: Vec<Change> = learner.changes(0).unwrap().cloned().collect();
+    assert_eq!(
+        changes,
+        vec![
+            change(0, "a", None, Some("1")),
+            change(4, "a", Some("1"), Some("12")),
+            change(4, "b", None, Some("x")),
+            change(5, "a", Some("12"), None),
+        ]
+    );
+    assert_eq!(learner.changes(5).unwrap().count(), 1);
+    assert_eq!(learner.changes(6).unwrap().count(), 0);
+
+    // a lagging learner does not know the changes a snapshot covers
+    let mut lagging = Learner::new();
+    for msg in learner.handle_msg(1, &Message::CatchUp { slot: 0 }) {
+        lagging.handle_msg(0, &msg);
+    }
+    assert_eq!(lagging.next_slot(), 6);
+    assert!(lagging.changes(0).is_ok());
+    learner.snapshot().unwrap();
+    let mut lagging = Learner::new();
+    for msg in learner.handle_msg(1, &Message::CatchUp { slot: 0 }) {
+        lagging.handle_msg(0, &msg);
+    }
+    assert!(matches!(lagging.changes(0), Err(6)));
+    assert_eq!(lagging.changes(6).unwrap().count(), 0);
+
+    // locks released by a TICK are changes too
+    let storage = Box::new(MemStorage::default());
+    let mut locks = Learner::with_state_machine(Box::new(LockTable::default()), storage).unwrap();
+    choose(&mut locks, request("acquire\nl\nowner\n100"));
+    choose(&mut locks, Command::Tick { millis: 100 });
+    let changes: Vec<Change> = locks.changes(0).unwrap().cloned().collect();
+    assert_eq!(
+        changes,
+        vec![
+            change(0, "l", None, Some("owner")),
+            change(1, "l", Some("owner"), None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_watchers_stream_changes_and_resume_from_a_slot() {
+    let nodes = start_cluster(&cluster_config(3));
+    let client = KvClient::new(nodes.clone());
+    client.put("other", "x").await.unwrap();
+    let watch = |from| Watch {
+        target: WatchTarget::Prefix("k".to_string()),
+        from,
+    };
+    let mut watcher = client.watch(watch(None)).await.unwrap();
+
+    client.put("k1", "a").await.unwrap();
+    client.put("other", "y").await.unwrap();
+    client.request("append\nk1\nb").await.unwrap();
+    client.delete("k1").await.unwrap();
+    let mut changes = vec![];
+    for _ in 0..3 {
+        changes.push(watcher.next().await.unwrap());
+    }
+    let values: Vec<_> = changes
+        .iter()
+        .map(|change| (change.old.as_deref(), change.new.as_deref()))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            (None, Some("a")),
+            (Some("a"), Some("ab")),
+            (Some("ab"), None)
+        ]
+    );
+    assert!(changes.windows(2).all(|pair| pair[0].slot < pair[1].slot));
+
+    // watching again from the second change, on another node, repeats the
+    // changes from there
+    let mut other = KvClient::new(nodes[1..].to_vec());
+    other.set_options(ClientOptions {
+        retries: 0,
+        ..ClientOptions::default()
+    });
+    let mut resumed = other.watch(watch(Some(changes[1].slot))).await.unwrap();
+    assert_eq!(resumed.next().await.unwrap(), changes[1]);
+    assert_eq!(resumed.next().await.unwrap(), changes[2]);
+    client.put("k2", "c").await.unwrap();
+    let next = resumed.next().await.unwrap();
+    assert_eq!(next, watcher.next().await.unwrap());
+    assert_eq!((next.key.as_str(), next.new.as_deref()), ("k2", Some("c")));
+}
+
+#[tokio::test]
+async fn test_watcher_resumes_on_the_next_node_without_repeats() {
+    let k = |slot, key: &str, new| WatchEvent::Change(change(slot, key, None, Some(new)));
+    let first = fake_node(
+        "watch\nprefix\nk",
+        vec![WatchEvent::Started(5), k(5, "k1", "a"), k(5, "k2", "b")],
+    );
+    // the second node knows more of slot 5, and sends what came before again
+    let second = fake_node(
+        "watch\nprefix\nk\n5",
+        vec![
+            WatchEvent::Started(5),
+            k(5, "k1", "a"),
+            k(5, "k2", "b"),
+            k(5, "k3", "c"),
+            k(6, "k1", "d"),
+        ],
+    );
+    let client = KvClient::new(vec![first, second]);
+    let mut watcher = client
+        .watch(Watch {
+            target: WatchTarget::Prefix("k".to_string()),
+            from: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(watcher.slot(), Some(5));
+    let mut received = vec![];
+    for _ in 0..4 {
+        let change = watcher.next().await.unwrap();
+        received.push((change.slot, change.key));
+    }
+    assert_eq!(
+        received,
+        vec![
+            (5, "k1".to_string()),
+            (5, "k2".to_string()),
+            (5, "k3".to_string()),
+            (6, "k1".to_string()),
+        ]
+    );
+    assert_eq!(watcher.slot(), Some(6));
+}
+
+#[tokio::test]
+async fn test_slow_watcher_gets_every_change() {
+    let nodes = start_cluster(&cluster_config(1));
+    let client = KvClient::new(nodes.clone());
+    // read by hand, as the client would quietly resume a broken connection
+    let mut stream = TcpStream::connect(nodes[0]).unwrap();
+    let request = Watch {
+        target: WatchTarget::Key("k".to_string()),
+        from: None,
+    }
+    .to_client_request();
+    stream.write_all(&[0]).unwrap();
+    stream
+        .write_all(&(request.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut next_event = || {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut event = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut event).unwrap();
+        WatchEvent::from_response(&String::from_utf8(event).unwrap()).unwrap()
+    };
+    assert!(matches!(next_event(), WatchEvent::Started(_)));
+
+    // far more than the node queues while the watcher does not read
+    let values: Vec<String> = (0..40).map(|i| i.to_string().repeat(100_000)).collect();
+    for value in &values {
+        client.put("k", value).await.unwrap();
+    }
+    let mut previous = None;
+    for value in &values {
+        let WatchEvent::Change(change) = next_event() else {
+            panic!("expected a change");
+        };
+        assert_eq!(change.old, previous);
+        assert_eq!(change.new.as_ref(), Some(value));
+        previous = change.new;
+    }
+}